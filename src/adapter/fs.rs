@@ -1,31 +1,52 @@
 use std::{
-    fmt::{write, Display},
-    path::{self, Path, PathBuf},
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fmt::Display,
+    fs::{self, FileType},
+    io,
+    os::unix::{ffi::OsStrExt, fs::FileTypeExt},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
 };
 
-use ignore::{gitignore::GitignoreBuilder, Walk};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    WalkBuilder,
+};
+
+use serde::Serialize;
+
+use super::git::{self, GitBlob, GitObjects};
+
+use crate::{
+    check,
+    config::{CodeSource, SymlinkPolicy, TaskCodeDefinition},
+    when,
+};
+
+// Read in every directory like .gitignore, taking precedence over it
+pub const DIFM_IGNORE_FILE: &str = ".difmignore";
 
-use crate::{remote, when};
+// Never sent, even along with the hidden files
+const ALWAYS_EXCLUDED: &[&str] = &[".git", ".difm"];
 
 pub struct FileTransferList {
     local_source_origin: PathBuf,
     remote_dest_origin: PathBuf,
-    ignore_statement: String,
+    code: TaskCodeDefinition,
     ignore_origin: PathBuf,
+    // Of the blobs of `code.rev`, shared with the entries
+    objects: Arc<GitObjects>,
 }
 
 impl FileTransferList {
-    pub fn new(
-        local_source_origin: &Path,
-        remote_dest_origin: &Path,
-        ignore_statement: &str,
-        ignore_origin: &Path,
-    ) -> Self {
+    pub fn new(code: &TaskCodeDefinition, remote_dest_origin: &Path, ignore_origin: &Path) -> Self {
         Self {
-            local_source_origin: local_source_origin.to_path_buf(),
+            local_source_origin: code.location.clone(),
             remote_dest_origin: remote_dest_origin.to_path_buf(),
-            ignore_statement: ignore_statement.to_string(),
+            code: code.clone(),
             ignore_origin: ignore_origin.to_path_buf(),
+            objects: Arc::default(),
         }
     }
 
@@ -37,47 +58,410 @@ impl FileTransferList {
         &self.remote_dest_origin
     }
 
-    pub fn traverse_dir(&self) -> impl Iterator<Item = Entry> + '_ {
-        let mut gitignore = GitignoreBuilder::new(&self.local_source_origin);
+    // The files in the directory, or the ones git sees with `code.source: git` or `code.rev`
+    pub fn traverse_dir(&self) -> io::Result<Box<dyn Iterator<Item = Entry> + '_>> {
+        Ok(match (&self.code.rev, self.code.source) {
+            (Some(rev), _) => Box::new(self.git_revision(rev)?.into_iter()),
+            (None, CodeSource::Git) => Box::new(self.git_worktree()?.into_iter()),
+            (None, CodeSource::Files) => Box::new(self.walk()),
+        })
+    }
 
-        self.ignore_statement.lines().for_each(|line| {
-            gitignore
-                .add_line(Some(self.ignore_origin.to_owned()), line)
-                .unwrap();
-        });
+    fn walk(&self) -> impl Iterator<Item = Entry> + '_ {
+        let exclude = self.matcher("exclude", self.code.exclude_patterns());
+        let include = self.matcher("include", self.code.include.iter().map(String::as_str));
+        let filters = &self.code.filters;
+        let symlinks = filters.symlinks;
+        let root = fs::canonicalize(&self.local_source_origin)
+            .unwrap_or_else(|_| self.local_source_origin.clone());
+        let link_root = root.clone();
 
-        let gitignore = gitignore.build().unwrap();
+        let walker = WalkBuilder::new(&self.local_source_origin)
+            .hidden(!filters.hidden)
+            .git_ignore(filters.gitignore)
+            .git_exclude(filters.gitignore)
+            .git_global(filters.global_gitignore)
+            .add_custom_ignore_filename(DIFM_IGNORE_FILE)
+            .follow_links(symlinks == SymlinkPolicy::Follow)
+            // The excluded directories are not entered at all
+            .filter_entry(move |entry| {
+                let is_dir = entry.file_type().is_some_and(|kind| kind.is_dir());
+                // Whatever they are, e.g. `.git` is a file in a worktree or a submodule
+                let always_excluded =
+                    ALWAYS_EXCLUDED.contains(&&*entry.file_name().to_string_lossy());
+                let is_link = entry.depth() > 0 && entry.path_is_symlink();
 
-        let walker = Walk::new(&self.local_source_origin);
+                !always_excluded
+                    && !exclude.matched(entry.path(), is_dir).is_ignore()
+                    && (!is_link || admit_symlink(entry.path(), &root, symlinks))
+            })
+            .build();
 
         walker
             .into_iter()
-            .filter_map(|path| path.ok())
+            // e.g. the loops found while following the links
+            .filter_map(|path| {
+                path.inspect_err(|err| eprintln!("[!] Skipped: {}", err))
+                    .ok()
+            })
+            // None is possible if the `path` is stdin
+            .filter(|path| path.file_type().is_some())
+            // The directories are entered anyway, as the files in them may be included
             .filter(move |path| {
-                let matched = gitignore.matched(path.path(), path.file_type().unwrap().is_dir());
-
-                when! {
-                    matched.is_whitelist() => true,
-                    matched.is_ignore() => false,
-                    matched.is_none() => true,
-                    _ => !,
-                }
+                include.is_empty()
+                    || path.file_type().unwrap().is_dir()
+                    || include
+                        .matched_path_or_any_parents(path.path(), false)
+                        .is_ignore()
             })
-            .map(move |path| {
-                // None is possible if the `path` is stdin
+            .filter_map(move |path| {
                 let file_type = path.file_type().unwrap();
-
-                Entry::new(
-                    when! {
-                        file_type.is_file() => EntryType::File,
-                        file_type.is_dir() => EntryType::Dir,
-                        _ => todo!(),
+                let kind = when! {
+                    file_type.is_file() => EntryType::File,
+                    file_type.is_dir() => EntryType::Dir,
+                    file_type.is_symlink() => EntryType::Symlink,
+                    _ => {
+                        eprintln!(
+                            "[!] Skipped {}, as it is {}",
+                            path.path().display(),
+                            special_file_kind(file_type)
+                        );
+                        return None;
                     },
+                };
+
+                let entry = Entry::new(
+                    kind,
                     &self.local_source_origin,
                     &self.remote_dest_origin,
                     path.path(),
-                )
+                );
+                match kind {
+                    EntryType::Symlink => match fs::read_link(path.path()) {
+                        Ok(target) => portable_link_target(path.path(), target, &link_root)
+                            .map(|target| entry.with_link_target(target)),
+                        Err(err) => {
+                            eprintln!("[!] Skipped {}: {}", path.path().display(), err);
+                            None
+                        }
+                    },
+                    _ => Some(entry),
+                }
+            })
+    }
+
+    fn git_worktree(&self) -> io::Result<Vec<Entry>> {
+        let exclude = self.matcher("exclude", self.code.exclude_patterns());
+        let include = self.matcher("include", self.code.include.iter().map(String::as_str));
+        let symlinks = self.code.filters.symlinks;
+        let root = fs::canonicalize(&self.local_source_origin)
+            .unwrap_or_else(|_| self.local_source_origin.clone());
+
+        Ok(
+            git::list_worktree(&self.local_source_origin, self.code.untracked)?
+                .into_iter()
+                .map(|path| self.local_source_origin.join(path))
+                .filter(|path| is_selected(&exclude, &include, &self.local_source_origin, path))
+                .filter_map(|path| {
+                    // Removed from the working tree, but not from the index yet
+                    let file_type = fs::symlink_metadata(&path).ok()?.file_type();
+                    let entry = |kind| {
+                        Entry::new(
+                            kind,
+                            &self.local_source_origin,
+                            &self.remote_dest_origin,
+                            &path,
+                        )
+                    };
+
+                    if !file_type.is_symlink() {
+                        return Some(entry(EntryType::File));
+                    }
+                    if !admit_symlink(&path, &root, symlinks) {
+                        return None;
+                    }
+                    // git only tracks the link, so a directory behind it is not followed
+                    if symlinks == SymlinkPolicy::Follow && path.is_file() {
+                        return Some(entry(EntryType::File));
+                    }
+                    let target = fs::read_link(&path).ok()?;
+                    let target = portable_link_target(&path, target, &root)?;
+                    Some(entry(EntryType::Symlink).with_link_target(target))
+                })
+                .collect(),
+        )
+    }
+
+    // Nothing is read from the working tree, so the symlinks are resolved among the files of the
+    // revision instead
+    fn git_revision(&self, rev: &str) -> io::Result<Vec<Entry>> {
+        let exclude = self.matcher("exclude", self.code.exclude_patterns());
+        let include = self.matcher("include", self.code.include.iter().map(String::as_str));
+        let symlinks = self.code.filters.symlinks;
+        let root = fs::canonicalize(&self.local_source_origin)
+            .unwrap_or_else(|_| self.local_source_origin.clone());
+
+        let tree = git::list_revision(&self.local_source_origin, rev, &self.objects)?;
+        let mut links = HashMap::new();
+        for tree_entry in tree.iter().filter(|tree_entry| tree_entry.symlink) {
+            // The blob of a symlink is its target
+            let target = tree_entry.blob.read()?;
+            links.insert(
+                tree_entry.path.clone(),
+                PathBuf::from(OsStr::from_bytes(&target)),
+            );
+        }
+        let files: HashMap<_, _> = tree
+            .iter()
+            .filter(|tree_entry| !tree_entry.symlink)
+            .map(|tree_entry| (tree_entry.path.as_path(), &tree_entry.blob))
+            .collect();
+        let dirs: HashSet<_> = tree
+            .iter()
+            .flat_map(|tree_entry| tree_entry.path.ancestors().skip(1))
+            .collect();
+
+        Ok(tree
+            .iter()
+            .map(|tree_entry| (self.local_source_origin.join(&tree_entry.path), tree_entry))
+            .filter(|(path, _)| is_selected(&exclude, &include, &self.local_source_origin, path))
+            .filter_map(|(path, tree_entry)| {
+                let entry = |kind| {
+                    Entry::new(
+                        kind,
+                        &self.local_source_origin,
+                        &self.remote_dest_origin,
+                        &path,
+                    )
+                };
+                let link = || {
+                    let target = tree_link_target(&tree_entry.path, &links[&tree_entry.path], &root);
+                    entry(EntryType::Symlink).with_link_target(target)
+                };
+
+                if !tree_entry.symlink {
+                    return Some(entry(EntryType::File).with_blob(tree_entry.blob.clone()));
+                }
+                let skipped = match (symlinks, resolve_tree_link(&tree_entry.path, &links, &root)) {
+                    (SymlinkPolicy::Skip, _) => return None,
+                    (_, Err(LinkTarget::Loop)) => "loops",
+                    (_, Err(_)) => "points outside of `code.location`",
+                    (SymlinkPolicy::Recreate, Ok(_)) => return Some(link()),
+                    (SymlinkPolicy::Follow, Ok(target)) => match files.get(target.as_path()) {
+                        Some(blob) => {
+                            return Some(entry(EntryType::File).with_blob((*blob).clone()))
+                        }
+                        None if dirs.contains(target.as_path()) => {
+                            eprintln!(
+                                "[!] Kept {} as a symlink, as a directory is not followed at a revision",
+                                path.display()
+                            );
+                            return Some(link());
+                        }
+                        None => "points to nothing",
+                    },
+                };
+
+                eprintln!("[!] Skipped {}, as the symlink {}", path.display(), skipped);
+                None
             })
+            .collect())
+    }
+
+    // `difm check` reports the invalid patterns, which are skipped here
+    fn matcher<'a>(&self, field: &str, patterns: impl Iterator<Item = &'a str>) -> Gitignore {
+        let mut builder = GitignoreBuilder::new(&self.local_source_origin);
+        for pattern in patterns {
+            let added = builder.add_line(Some(self.ignore_origin.to_owned()), pattern);
+            check!(
+                added.is_ok(),
+                "Skipped the invalid pattern in `code.{}`: {}",
+                field,
+                pattern
+            );
+        }
+
+        builder.build().unwrap_or_else(|_| Gitignore::empty())
+    }
+}
+
+// For the files listed by git, which are not walked through, so the directories are not pruned.
+// Only the part under `root` is looked at, as `code.location` may itself be e.g. in a `.difm`.
+fn is_selected(exclude: &Gitignore, include: &Gitignore, root: &Path, path: &Path) -> bool {
+    let always_excluded = path
+        .strip_prefix(root)
+        .unwrap_or(path)
+        .components()
+        .any(|component| ALWAYS_EXCLUDED.contains(&&*component.as_os_str().to_string_lossy()));
+
+    !always_excluded
+        && !exclude.matched_path_or_any_parents(path, false).is_ignore()
+        && (include.is_empty() || include.matched_path_or_any_parents(path, false).is_ignore())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum LinkTarget {
+    Inside,
+    Outside,
+    Loop,
+    Missing,
+}
+
+// Follows the chain of the links, so that a link to another link pointing outside is told too
+fn resolve_link(path: &Path, root: &Path) -> LinkTarget {
+    if let Ok(target) = fs::canonicalize(path) {
+        return match target.starts_with(root) {
+            true => LinkTarget::Inside,
+            false => LinkTarget::Outside,
+        };
+    }
+
+    let mut visited = HashSet::new();
+    let mut current = path.to_path_buf();
+    while let Ok(target) = fs::read_link(&current) {
+        if !visited.insert(current.clone()) {
+            return LinkTarget::Loop;
+        }
+        current = current.parent().unwrap_or(Path::new("")).join(target);
+    }
+
+    LinkTarget::Missing
+}
+
+// Of a link at a revision, relative to the root of the tree, as the kernel would resolve it among
+// the other entries: the links along the way are followed too, up to 40 of them.
+// Either `LinkTarget::Outside` or `LinkTarget::Loop` is returned as the error.
+fn resolve_tree_link(
+    link: &Path,
+    links: &HashMap<PathBuf, PathBuf>,
+    root: &Path,
+) -> Result<PathBuf, LinkTarget> {
+    let mut resolved = PathBuf::new();
+    let mut pending = Vec::new();
+    push_components(&mut pending, link);
+    let mut hops = 0;
+
+    while let Some(name) = pending.pop() {
+        if name == ".." {
+            if !resolved.pop() {
+                return Err(LinkTarget::Outside);
+            }
+            continue;
+        }
+
+        resolved.push(&name);
+        let Some(target) = links.get(&resolved) else {
+            continue;
+        };
+        hops += 1;
+        if hops > 40 {
+            return Err(LinkTarget::Loop);
+        }
+
+        resolved.pop();
+        let target = match target.is_absolute() {
+            true => {
+                resolved = PathBuf::new();
+                target.strip_prefix(root).map_err(|_| LinkTarget::Outside)?
+            }
+            false => target.as_path(),
+        };
+        push_components(&mut pending, target);
+    }
+
+    Ok(resolved)
+}
+
+// Pushed to be popped first to last
+fn push_components(pending: &mut Vec<OsString>, path: &Path) {
+    let components: Vec<_> = path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::ParentDir => Some(OsString::from("..")),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => None,
+        })
+        .collect();
+    pending.extend(components.into_iter().rev());
+}
+
+// As `portable_link_target`, with the paths relative to the root of the tree
+fn tree_link_target(link: &Path, target: &Path, root: &Path) -> PathBuf {
+    match target.strip_prefix(root) {
+        Ok(inside) if target.is_absolute() => relative_to(
+            &Path::new("/").join(inside),
+            &Path::new("/").join(link.parent().unwrap_or(Path::new(""))),
+        ),
+        _ => target.to_path_buf(),
+    }
+}
+
+fn admit_symlink(path: &Path, root: &Path, policy: SymlinkPolicy) -> bool {
+    let skipped = match (policy, resolve_link(path, root)) {
+        (SymlinkPolicy::Skip, _) => return false,
+        (_, LinkTarget::Inside) => return true,
+        // Sent as it is, so that it is just as broken on the host
+        (SymlinkPolicy::Recreate, LinkTarget::Missing) => return true,
+        (SymlinkPolicy::Follow, LinkTarget::Missing) => "points to nothing",
+        (_, LinkTarget::Outside) => "points outside of `code.location`",
+        (_, LinkTarget::Loop) => "loops",
+    };
+
+    eprintln!("[!] Skipped {}, as the symlink {}", path.display(), skipped);
+    false
+}
+
+// An absolute target means nothing on the host, where the code is somewhere else. The ones inside
+// `code.location` are made relative to the link instead.
+fn portable_link_target(path: &Path, target: PathBuf, root: &Path) -> Option<PathBuf> {
+    if target.is_relative() {
+        return Some(target);
+    }
+
+    let inside = match target.starts_with(root) {
+        true => Some(target),
+        false => fs::canonicalize(&target)
+            .ok()
+            .filter(|resolved| resolved.starts_with(root)),
+    };
+    let dir = path.parent().and_then(|dir| fs::canonicalize(dir).ok());
+    let (Some(inside), Some(dir)) = (inside, dir) else {
+        eprintln!(
+            "[!] Skipped {}, as the symlink has an absolute target outside of `code.location`",
+            path.display()
+        );
+        return None;
+    };
+
+    Some(relative_to(&inside, &dir))
+}
+
+// Both are absolute
+fn relative_to(path: &Path, dir: &Path) -> PathBuf {
+    let common = path
+        .components()
+        .zip(dir.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let relative: PathBuf = dir
+        .components()
+        .skip(common)
+        .map(|_| Component::ParentDir)
+        .chain(path.components().skip(common))
+        .collect();
+
+    match relative.as_os_str().is_empty() {
+        true => PathBuf::from("."),
+        false => relative,
+    }
+}
+
+fn special_file_kind(file_type: FileType) -> &'static str {
+    when! {
+        file_type.is_socket() => "a socket",
+        file_type.is_fifo() => "a FIFO",
+        file_type.is_block_device() || file_type.is_char_device() => "a device",
+        _ => "neither a file nor a directory",
     }
 }
 
@@ -87,14 +471,20 @@ pub struct Entry {
     pub local_source: PathBuf,
     pub remote_dest: PathBuf,
     pub path_name: PathBuf,
+    // What a symlink points to, as written in it
+    pub link_target: Option<PathBuf>,
+    // Where the content is read from instead of `local_source`, for `code.rev`
+    pub blob: Option<GitBlob>,
     local_origin: PathBuf,
     remote_origin: PathBuf,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum EntryType {
     File,
     Dir,
+    Symlink,
 }
 
 impl Entry {
@@ -109,11 +499,47 @@ impl Entry {
             local_source: local_origin.join(path_name),
             remote_dest: remote_origin.join(path_name),
             path_name: path_name.to_path_buf(),
+            link_target: None,
+            blob: None,
             local_origin: local_origin.to_path_buf(),
             remote_origin: remote_origin.to_path_buf(),
         }
     }
 
+    pub fn with_link_target(mut self, target: PathBuf) -> Self {
+        self.link_target = Some(target);
+        self
+    }
+
+    pub fn with_blob(mut self, blob: GitBlob) -> Self {
+        self.blob = Some(blob);
+        self
+    }
+
+    // Of the file to be sent, so 0 for the others
+    pub fn size(&self) -> u64 {
+        match (self.kind, &self.blob) {
+            (EntryType::File, Some(blob)) => blob.size,
+            (EntryType::File, None) => fs::metadata(&self.local_source)
+                .map(|metadata| metadata.len())
+                .unwrap_or(0),
+            (EntryType::Dir | EntryType::Symlink, _) => 0,
+        }
+    }
+
+    pub fn read(&self) -> io::Result<Vec<u8>> {
+        match &self.blob {
+            Some(blob) => blob.read(),
+            None => fs::read(&self.local_source),
+        }
+    }
+
+    pub fn relative_path(&self) -> &Path {
+        self.path_name
+            .strip_prefix(&self.local_origin)
+            .unwrap_or(&self.path_name)
+    }
+
     pub fn is_same(&self, path: &Path) -> bool {
         self.path_name == path
             || self.local_source == path
@@ -134,3 +560,242 @@ impl Display for Entry {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use crate::util::TempTree;
+
+    use super::*;
+
+    fn patterns(root: &Path, patterns: &[&str]) -> Gitignore {
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in patterns {
+            builder.add_line(None, pattern).unwrap();
+        }
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn selects_by_the_path_under_the_root() {
+        let root = Path::new("/home/me/.difm/checkout");
+        let none = Gitignore::empty();
+        let selected = |exclude: &Gitignore, include: &Gitignore, path: &str| {
+            is_selected(exclude, include, root, &root.join(path))
+        };
+
+        assert!(selected(&none, &none, "src/main.rs"));
+        assert!(!selected(&none, &none, ".git/config"));
+        assert!(!selected(&none, &none, "sub/.difm/runs"));
+
+        let exclude = patterns(root, &["target/", "*.log"]);
+        let include = patterns(root, &["src/", "*.log"]);
+        assert!(!selected(&exclude, &none, "target/debug/app"));
+        assert!(!selected(&exclude, &include, "src/debug.log"));
+        assert!(selected(&none, &include, "src/bin/app.rs"));
+        assert!(!selected(&none, &include, "README.md"));
+    }
+
+    #[test]
+    fn makes_a_path_relative_to_a_directory() {
+        let relative = |path, dir| relative_to(Path::new(path), Path::new(dir));
+
+        assert_eq!(
+            relative("/code/lib/a.rs", "/code/src"),
+            Path::new("../lib/a.rs")
+        );
+        assert_eq!(relative("/code/src/a.rs", "/code/src"), Path::new("a.rs"));
+        assert_eq!(relative("/code", "/code/src/bin"), Path::new("../.."));
+        assert_eq!(relative("/code", "/code"), Path::new("."));
+    }
+
+    #[test]
+    fn rewrites_an_absolute_link_target_inside_the_root() {
+        let root = TempTree::new(&[("lib/a.rs", ""), ("src/main.rs", "")]);
+        let link = root.join("src/a.rs");
+        symlink(root.join("lib/a.rs"), &link).unwrap();
+
+        assert_eq!(
+            portable_link_target(&link, root.join("lib/a.rs"), &root),
+            Some(PathBuf::from("../lib/a.rs"))
+        );
+        assert_eq!(
+            portable_link_target(&link, PathBuf::from("../lib/a.rs"), &root),
+            Some(PathBuf::from("../lib/a.rs"))
+        );
+    }
+
+    #[test]
+    fn skips_an_absolute_link_target_outside_the_root() {
+        let root = TempTree::new(&[("src/main.rs", "")]);
+        let outside = TempTree::new(&[("a.rs", "")]);
+        let link = root.join("src/a.rs");
+        symlink(outside.join("a.rs"), &link).unwrap();
+
+        assert_eq!(
+            portable_link_target(&link, outside.join("a.rs"), &root),
+            None
+        );
+    }
+
+    #[test]
+    fn resolves_a_link_among_the_entries_of_the_tree() {
+        let links: HashMap<_, _> = [
+            ("src/a.rs", "../lib/a.rs"),
+            ("lib/current", "."),
+            ("b.rs", "lib/current/a.rs"),
+            ("abs", "/code/lib"),
+            ("up", "../../etc/passwd"),
+            ("escaping", "abs/../.."),
+            ("loop", "again"),
+            ("again", "loop"),
+        ]
+        .into_iter()
+        .map(|(link, target)| (PathBuf::from(link), PathBuf::from(target)))
+        .collect();
+        let resolve = |link| resolve_tree_link(Path::new(link), &links, Path::new("/code"));
+
+        assert_eq!(resolve("src/a.rs"), Ok(PathBuf::from("lib/a.rs")));
+        assert_eq!(resolve("b.rs"), Ok(PathBuf::from("lib/a.rs")));
+        assert_eq!(resolve("abs"), Ok(PathBuf::from("lib")));
+        assert_eq!(resolve("up"), Err(LinkTarget::Outside));
+        assert_eq!(resolve("escaping"), Err(LinkTarget::Outside));
+        assert_eq!(resolve("loop"), Err(LinkTarget::Loop));
+    }
+
+    fn code(location: &Path, settings: &str) -> TaskCodeDefinition {
+        serde_yaml::from_str(&format!(
+            "location: {}\ndest: /remote\nuse: ssh\n{}",
+            location.display(),
+            settings
+        ))
+        .unwrap()
+    }
+
+    fn list(code: &TaskCodeDefinition) -> Vec<(String, EntryType, Option<PathBuf>)> {
+        let list = FileTransferList::new(code, Path::new("/remote"), Path::new("difm.yaml"));
+        let mut entries: Vec<_> = list
+            .traverse_dir()
+            .unwrap()
+            .map(|entry| {
+                (
+                    entry.relative_path().display().to_string(),
+                    entry.kind,
+                    entry.link_target,
+                )
+            })
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    #[test]
+    fn checks_the_links_of_a_revision() {
+        let root = TempTree::new(&[("lib/a.rs", "a"), ("src/main.rs", "")]);
+        symlink(root.join("lib/a.rs"), root.join("src/abs.rs")).unwrap();
+        symlink("../lib/a.rs", root.join("src/rel.rs")).unwrap();
+        symlink("lib", root.join("libs")).unwrap();
+        symlink("../outside", root.join("out")).unwrap();
+        symlink("again", root.join("loop")).unwrap();
+        symlink("loop", root.join("again")).unwrap();
+        root.commit();
+
+        let link = |path: &str, target: &str| {
+            (
+                path.to_string(),
+                EntryType::Symlink,
+                Some(PathBuf::from(target)),
+            )
+        };
+        let file = |path: &str| (path.to_string(), EntryType::File, None);
+
+        assert_eq!(
+            list(&code(&root, "rev: HEAD")),
+            [
+                file("lib/a.rs"),
+                link("libs", "lib"),
+                link("src/abs.rs", "../lib/a.rs"),
+                file("src/main.rs"),
+                link("src/rel.rs", "../lib/a.rs"),
+            ]
+        );
+        assert_eq!(
+            list(&code(&root, "rev: HEAD\nsymlinks: follow")),
+            [
+                file("lib/a.rs"),
+                link("libs", "lib"),
+                file("src/abs.rs"),
+                file("src/main.rs"),
+                file("src/rel.rs"),
+            ]
+        );
+    }
+
+    #[test]
+    fn selects_the_files_walked_through() {
+        let root = TempTree::new(&[
+            (".gitignore", "generated/\n*.log\n"),
+            (".difmignore", "!generated/\n"),
+            (".env", ""),
+            ("debug.log", ""),
+            ("generated/a.rs", ""),
+            ("notes.txt", ""),
+            ("src/main.rs", ""),
+            ("src/util.sh", ""),
+            ("tmp/a.rs", ""),
+        ]);
+        // The ignore files are only respected in a git repository
+        root.commit();
+        fs::write(root.join(".git/info/exclude"), "tmp/\n").unwrap();
+        fs::create_dir_all(root.join("sub/.difm")).unwrap();
+        fs::write(root.join("sub/.difm/run.json"), "").unwrap();
+        fs::write(root.join("sub/.git"), "gitdir: ../.git/modules/sub").unwrap();
+
+        let files = |settings| -> Vec<String> {
+            list(&code(&root, settings))
+                .into_iter()
+                .filter(|(_, kind, _)| *kind == EntryType::File)
+                .map(|(path, _, _)| path)
+                .collect()
+        };
+
+        // `.difmignore` takes precedence over `.gitignore`
+        assert_eq!(
+            files(""),
+            ["generated/a.rs", "notes.txt", "src/main.rs", "src/util.sh"]
+        );
+        assert_eq!(
+            files("gitignore: false"),
+            [
+                "debug.log",
+                "generated/a.rs",
+                "notes.txt",
+                "src/main.rs",
+                "src/util.sh",
+                "tmp/a.rs"
+            ]
+        );
+        assert_eq!(
+            files("hidden: true"),
+            [
+                ".difmignore",
+                ".env",
+                ".gitignore",
+                "generated/a.rs",
+                "notes.txt",
+                "src/main.rs",
+                "src/util.sh"
+            ]
+        );
+        // Excluded even if included
+        assert_eq!(
+            files("include: [src/, '*.rs']\nexclude: ['*.sh']"),
+            ["generated/a.rs", "src/main.rs"]
+        );
+        assert_eq!(
+            files("include: [src/]\nignore: |\n  generated/\n  main.rs"),
+            ["src/util.sh"]
+        );
+    }
+}
@@ -0,0 +1,288 @@
+use std::{
+    ffi::OsStr,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::{Arc, Mutex},
+};
+
+// The content of a file at a revision, read from the objects of `repo`
+#[derive(Clone, Debug)]
+pub struct GitBlob {
+    // The one of the submodule for the files in it
+    pub repo: PathBuf,
+    pub oid: String,
+    pub size: u64,
+    objects: Arc<GitObjects>,
+}
+
+impl GitBlob {
+    pub fn read(&self) -> io::Result<Vec<u8>> {
+        self.objects.read(self)
+    }
+}
+
+pub struct TreeEntry {
+    // Relative to the directory listed
+    pub path: PathBuf,
+    pub symlink: bool,
+    pub blob: GitBlob,
+}
+
+fn git(dir: &Path, args: &[&str]) -> io::Result<Vec<u8>> {
+    let output = Command::new("git").args(args).current_dir(dir).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "`git {}` in {} failed: {}",
+            args.join(" "),
+            dir.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(output.stdout)
+}
+
+// The branch `rev` (HEAD by default) is on, or None if it is not a branch or not in a repository
+pub fn branch(dir: &Path, rev: Option<&str>) -> Option<String> {
+    let output = git(dir, &["rev-parse", "--abbrev-ref", rev.unwrap_or("HEAD")]).ok()?;
+    let branch = String::from_utf8_lossy(&output).trim().to_string();
+
+    (!branch.is_empty() && branch != "HEAD").then_some(branch)
+}
+
+pub fn commit(dir: &Path, rev: Option<&str>) -> Option<String> {
+    let output = git(
+        dir,
+        &["rev-parse", "--verify", "--quiet", rev.unwrap_or("HEAD")],
+    )
+    .ok()?;
+
+    Some(String::from_utf8_lossy(&output).trim().to_string())
+}
+
+fn split_paths(output: &[u8]) -> impl Iterator<Item = PathBuf> + '_ {
+    output
+        .split(|byte| *byte == 0)
+        .filter(|path| !path.is_empty())
+        .map(|path| PathBuf::from(OsStr::from_bytes(path)))
+}
+
+// The tracked files, including the ones in the submodules, and the untracked ones not ignored if
+// asked. The files removed from the working tree but not from the index are listed too.
+pub fn list_worktree(dir: &Path, untracked: bool) -> io::Result<Vec<PathBuf>> {
+    let mut paths: Vec<_> = split_paths(&git(
+        dir,
+        &[
+            "ls-files",
+            "-z",
+            "--cached",
+            "--deduplicate",
+            "--recurse-submodules",
+        ],
+    )?)
+    .collect();
+    if untracked {
+        paths.extend(split_paths(&git(
+            dir,
+            &["ls-files", "-z", "--others", "--exclude-standard"],
+        )?));
+    }
+
+    Ok(paths)
+}
+
+// The submodules are listed at the commits recorded at `rev`, if they have been fetched. The blobs
+// are read through `objects`.
+pub fn list_revision(
+    dir: &Path,
+    rev: &str,
+    objects: &Arc<GitObjects>,
+) -> io::Result<Vec<TreeEntry>> {
+    let mut entries = Vec::new();
+    list_tree(dir, rev, Path::new(""), objects, &mut entries)?;
+
+    Ok(entries)
+}
+
+fn list_tree(
+    repo: &Path,
+    rev: &str,
+    prefix: &Path,
+    objects: &Arc<GitObjects>,
+    entries: &mut Vec<TreeEntry>,
+) -> io::Result<()> {
+    let output = git(repo, &["ls-tree", "-r", "-l", "-z", rev])?;
+
+    // `<mode> <type> <object> <size>\t<path>`
+    for record in output.split(|byte| *byte == 0) {
+        let Some(tab) = record.iter().position(|byte| *byte == b'\t') else {
+            continue;
+        };
+        let relative = Path::new(OsStr::from_bytes(&record[tab + 1..]));
+        let header = String::from_utf8_lossy(&record[..tab]);
+        let [mode, kind, oid, size] = header.split_whitespace().collect::<Vec<_>>()[..] else {
+            continue;
+        };
+
+        match kind {
+            "blob" => entries.push(TreeEntry {
+                path: prefix.join(relative),
+                symlink: mode == "120000",
+                blob: GitBlob {
+                    repo: repo.to_path_buf(),
+                    oid: oid.to_string(),
+                    size: size.parse().unwrap_or(0),
+                    objects: objects.clone(),
+                },
+            }),
+            "commit" => {
+                let listed = list_tree(
+                    &repo.join(relative),
+                    oid,
+                    &prefix.join(relative),
+                    objects,
+                    entries,
+                );
+                if let Err(err) = listed {
+                    eprintln!(
+                        "[!] Skipped the submodule {}: {}",
+                        prefix.join(relative).display(),
+                        err
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+// A `git cat-file --batch` kept running per repository while the files of a listing are read, as
+// a process per file is too slow. They are stopped along with the listing and its entries.
+#[derive(Debug, Default)]
+pub struct GitObjects {
+    readers: Mutex<Vec<(PathBuf, BatchReader)>>,
+}
+
+impl GitObjects {
+    fn read(&self, blob: &GitBlob) -> io::Result<Vec<u8>> {
+        let mut readers = self.readers.lock().unwrap();
+        let index = match readers.iter().position(|(repo, _)| *repo == blob.repo) {
+            Some(index) => index,
+            None => {
+                readers.push((blob.repo.clone(), BatchReader::spawn(&blob.repo)?));
+                readers.len() - 1
+            }
+        };
+
+        // Started again for the next blob, as it cannot tell where it was left
+        let content = readers[index].1.read(&blob.oid);
+        if content.is_err() {
+            readers.remove(index);
+        }
+
+        content
+    }
+}
+
+#[derive(Debug)]
+struct BatchReader {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl BatchReader {
+    fn spawn(repo: &Path) -> io::Result<Self> {
+        let mut child = Command::new("git")
+            .args(["cat-file", "--batch"])
+            .current_dir(repo)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        Ok(Self {
+            stdin: child.stdin.take().unwrap(),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+            child,
+        })
+    }
+
+    fn read(&mut self, oid: &str) -> io::Result<Vec<u8>> {
+        writeln!(self.stdin, "{}", oid)?;
+        self.stdin.flush()?;
+
+        // `<oid> <type> <size>`, or `<oid> missing`
+        let mut header = String::new();
+        self.stdout.read_line(&mut header)?;
+        let size: usize = header
+            .split_whitespace()
+            .nth(2)
+            .and_then(|size| size.parse().ok())
+            .ok_or_else(|| {
+                io::Error::other(format!("Could not read {}: {}", oid, header.trim()))
+            })?;
+
+        // Followed by a newline
+        let mut content = vec![0; size + 1];
+        self.stdout.read_exact(&mut content)?;
+        content.pop();
+
+        Ok(content)
+    }
+}
+
+impl Drop for BatchReader {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::TempTree;
+
+    use super::*;
+
+    fn repo() -> TempTree {
+        let dir = TempTree::new(&[("src/main.rs", "fn main() {}\n")]);
+        std::os::unix::fs::symlink("src/main.rs", dir.join("main.rs")).unwrap();
+        dir.commit();
+
+        dir
+    }
+
+    #[test]
+    fn reads_the_files_at_a_revision() {
+        let dir = repo();
+        std::fs::write(dir.join("src/main.rs"), "changed\n").unwrap();
+
+        let objects = Arc::default();
+        let mut entries = list_revision(&dir, "HEAD", &objects).unwrap();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, Path::new("main.rs"));
+        assert!(entries[0].symlink);
+        assert_eq!(entries[0].blob.read().unwrap(), b"src/main.rs");
+        assert_eq!(entries[1].path, Path::new("src/main.rs"));
+        assert_eq!(entries[1].blob.size, 13);
+        assert_eq!(entries[1].blob.read().unwrap(), b"fn main() {}\n");
+    }
+
+    #[test]
+    fn lists_the_worktree_once_per_file() {
+        let dir = repo();
+        std::fs::write(dir.join("untracked.rs"), "").unwrap();
+
+        let mut tracked = list_worktree(&dir, false).unwrap();
+        tracked.sort();
+        assert_eq!(tracked, [Path::new("main.rs"), Path::new("src/main.rs")]);
+        assert_eq!(list_worktree(&dir, true).unwrap().len(), 3);
+    }
+}
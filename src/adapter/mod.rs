@@ -1,2 +1,4 @@
 pub mod fs;
+pub mod git;
 pub mod ssh;
+pub mod terminal;
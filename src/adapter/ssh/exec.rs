@@ -1,112 +1,314 @@
-use std::{cell::OnceCell, io::Read, process::exit, sync::Arc, time::Duration};
+use std::io::{self, ErrorKind, Read};
 
-use regex::Regex;
-use tokio::sync::{Notify, RwLock};
-use uuid::Uuid;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::mpsc::{self, UnboundedReceiver},
+    task::JoinHandle,
+};
 
-use super::SSHSession;
+use crate::{
+    adapter::terminal::{self, TerminalSize},
+    progress::{self, Verbosity, ESEQ_RESET, ESEQ_WEAK},
+    util::shell_quote,
+};
 
-pub const TAG_REGEX_PATTERN: &str = r"\[\[ END-OF-TASK (.{36}) (\d) ]]";
-pub const TAG_REGEX: OnceCell<Regex> = OnceCell::new();
+use ssh2::Channel;
+
+use super::{io_error, retry, SSHSession, POLL_INTERVAL};
 
 pub struct ExecChannel {
-    end_notify: Arc<Notify>,
-    complete_info: Arc<RwLock<Option<ExecChannelCompleteInfo>>>,
+    output: UnboundedReceiver<ExecOutput>,
+    handle: JoinHandle<ExecChannelCompleteInfo>,
 }
 
 #[derive(Debug, Clone)]
 pub struct ExecChannelCompleteInfo {
     pub stdout: String,
     pub stderr: String,
-    pub exit_code: u8,
+    // `None` if the command could not be run to the end, e.g. the connection was lost
+    pub exit_code: Option<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ExecOutput {
+    Stdout(String),
+    Stderr(String),
+}
+
+pub const DEFAULT_TERM: &str = "xterm-256color";
+
+// With a PTY, the remote side merges stderr into stdout, just like a terminal would
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PtyRequest {
+    pub term: String,
+    pub size: TerminalSize,
+}
+
+impl PtyRequest {
+    pub fn from_local_terminal() -> Self {
+        Self {
+            term: std::env::var("TERM").unwrap_or(DEFAULT_TERM.to_string()),
+            size: terminal::size().unwrap_or_default(),
+        }
+    }
 }
 
 impl ExecChannel {
     pub async fn new(session: &SSHSession, line: &str) -> Self {
-        let id = Uuid::new_v4();
-        let tag = Self::tag(&id);
-        let mut channel = session.create_exec_channel().await;
+        Self::with_pty(session, line, None).await
+    }
+
+    pub async fn with_pty(session: &SSHSession, line: &str, pty: Option<&PtyRequest>) -> Self {
+        progress::report_at(
+            Verbosity::Debug,
+            &format!("{ESEQ_WEAK}$ {}{ESEQ_RESET}", line),
+        );
 
-        // TODO: Sanitize `line`
-        channel
-            .exec(&format!("sh -c '{line}'; echo \"{tag}\""))
-            .unwrap();
+        let (sender, output) = mpsc::unbounded_channel();
 
-        let complete_info = Arc::new(RwLock::new(None));
-        let end_notify = Arc::new(Notify::new());
+        if let Some(client) = session.daemon() {
+            let client = client.clone();
+            let line = line.to_string();
+            let pty = pty.cloned();
+            let handle =
+                tokio::spawn(async move { client.exec(&line, pty.as_ref(), sender).await });
 
-        let complete_info_for_thread = complete_info.clone();
-        let notify_for_thread = end_notify.clone();
+            return Self { output, handle };
+        }
 
-        let new_exec = Self {
-            end_notify,
-            complete_info,
+        let mut channel = match Self::start(session, line, pty).await {
+            Ok(channel) => channel,
+            Err(_) => {
+                let handle = tokio::spawn(async { ExecChannelCompleteInfo::interrupted(&[], &[]) });
+                return Self { output, handle };
+            }
         };
 
-        tokio::spawn(async move {
+        let handle = tokio::spawn(async move {
+            let mut stdout = Vec::new();
+            let mut stderr = Vec::new();
+            let mut stdout_decoder = Utf8Decoder::default();
+            let mut stderr_decoder = Utf8Decoder::default();
+            // What is left of a character cut at the end is sent as is
+            let flush = |stdout_decoder: Utf8Decoder, stderr_decoder: Utf8Decoder| {
+                if let Some(chunk) = stdout_decoder.finish() {
+                    sender.send(ExecOutput::Stdout(chunk)).ok();
+                }
+                if let Some(chunk) = stderr_decoder.finish() {
+                    sender.send(ExecOutput::Stderr(chunk)).ok();
+                }
+            };
+
             loop {
-                let mut stdout = String::new();
-                let mut stderr = String::new();
-
-                channel.stream(0).read_to_string(&mut stdout).unwrap();
-                channel.stderr().read_to_string(&mut stderr).unwrap();
-
-                if let Some(exit_code) = Self::extract_tag(&id, &stdout) {
-                    *(complete_info_for_thread.write().await) = Some(ExecChannelCompleteInfo {
-                        stdout: Self::remove_tag(&stdout),
-                        stderr,
-                        exit_code,
-                    });
-                    break;
+                // Reading a channel fails only if the connection is gone
+                let (Ok(stdout_read), Ok(stderr_read)) = (
+                    read_available(&mut channel.stream(0)),
+                    read_available(&mut channel.stderr()),
+                ) else {
+                    flush(stdout_decoder, stderr_decoder);
+                    return ExecChannelCompleteInfo::interrupted(&stdout, &stderr);
+                };
+
+                if stdout_read.is_empty() && stderr_read.is_empty() {
+                    if channel.eof() {
+                        break;
+                    }
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                    continue;
                 }
 
-                tokio::time::sleep(Duration::from_millis(100)).await;
+                // The receiver might be already dropped if nobody is interested in the output
+                let chunk = stdout_decoder.decode(&stdout_read);
+                if !chunk.is_empty() {
+                    sender.send(ExecOutput::Stdout(chunk)).ok();
+                }
+                let chunk = stderr_decoder.decode(&stderr_read);
+                if !chunk.is_empty() {
+                    sender.send(ExecOutput::Stderr(chunk)).ok();
+                }
+                stdout.extend(stdout_read);
+                stderr.extend(stderr_read);
             }
+            flush(stdout_decoder, stderr_decoder);
 
-            notify_for_thread.notify_one();
+            let exit_code = match retry(|| channel.wait_close()).await {
+                Ok(()) => exit_code(&channel),
+                Err(_) => None,
+            };
+
+            ExecChannelCompleteInfo {
+                stdout: String::from_utf8_lossy(&stdout).into_owned(),
+                stderr: String::from_utf8_lossy(&stderr).into_owned(),
+                exit_code,
+            }
         });
 
-        new_exec
+        Self { output, handle }
+    }
+
+    async fn start(
+        session: &SSHSession,
+        line: &str,
+        pty: Option<&PtyRequest>,
+    ) -> io::Result<Channel> {
+        let mut channel = session.create_exec_channel().await?;
+
+        if let Some(pty) = pty {
+            let size = (pty.size.cols, pty.size.rows, 0, 0);
+            retry(|| channel.request_pty(&pty.term, None, Some(size)))
+                .await
+                .map_err(io_error)?;
+        }
+
+        retry(|| channel.exec(&format!("sh -c {}", shell_quote(line))))
+            .await
+            .map_err(io_error)?;
+
+        Ok(channel)
     }
 
     pub async fn execute(session: &SSHSession, line: &str) -> ExecChannelCompleteInfo {
         Self::new(session, line).await.wait_done().await
     }
 
-    pub async fn wait_done(&self) -> ExecChannelCompleteInfo {
-        let notify = self.end_notify.clone();
-        notify.notified().await;
+    pub async fn next_output(&mut self) -> Option<ExecOutput> {
+        self.output.recv().await
+    }
 
-        self.complete_info.read().await.clone().unwrap()
+    pub async fn wait_done(self) -> ExecChannelCompleteInfo {
+        self.handle.await.unwrap()
     }
+}
 
-    fn tag(id: &Uuid) -> String {
-        format!(" [[ END-OF-TASK {} $? ]] ", id.hyphenated())
+// A command killed by a signal has no exit status, so it is reported as a shell would, with 128
+// plus the number of the signal
+fn exit_code(channel: &Channel) -> Option<u8> {
+    match channel
+        .exit_signal()
+        .ok()
+        .and_then(|signal| signal.exit_signal)
+    {
+        Some(signal) => Some(signal_exit_code(&signal)),
+        None => channel
+            .exit_status()
+            .ok()
+            .map(|status| status.try_into().unwrap_or(u8::MAX)),
     }
+}
 
-    fn extract_tag(task_id: &Uuid, new_output: &str) -> Option<u8> {
-        let binding = TAG_REGEX;
-        let regex = binding.get_or_init(|| Regex::new(TAG_REGEX_PATTERN).unwrap());
-        let capture = regex.captures(new_output)?;
+// The names are the ones of RFC 4254, without the `SIG` prefix. The numbers are the ones of Linux,
+// not of the local system, whose numbers may differ from the host's (e.g. USR1 on macOS).
+fn signal_exit_code(signal: &str) -> u8 {
+    let number = match signal {
+        "HUP" => 1,
+        "INT" => 2,
+        "QUIT" => 3,
+        "ILL" => 4,
+        "ABRT" => 6,
+        "FPE" => 8,
+        "KILL" => 9,
+        "USR1" => 10,
+        "SEGV" => 11,
+        "USR2" => 12,
+        "PIPE" => 13,
+        "ALRM" => 14,
+        "TERM" => 15,
+        _ => return u8::MAX,
+    };
 
-        if !capture
-            .get(1)
-            .is_some_and(|matched| matched.as_str() == task_id.hyphenated().to_string())
-        {
-            return None;
+    128 + number
+}
+
+impl ExecChannelCompleteInfo {
+    fn interrupted(stdout: &[u8], stderr: &[u8]) -> Self {
+        Self {
+            stdout: String::from_utf8_lossy(stdout).into_owned(),
+            stderr: String::from_utf8_lossy(stderr).into_owned(),
+            exit_code: None,
         }
+    }
+}
 
-        let Some(exit_code) = capture.get(2).and_then(|exit_code| exit_code.as_str().parse::<u8>().ok()) else {
-            println!("Exit code parse fail");
-            return None;
-        };
+// Keeps the bytes of a character split between two reads until the rest of it is read
+#[derive(Default)]
+struct Utf8Decoder {
+    pending: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    fn decode(&mut self, read: &[u8]) -> String {
+        self.pending.extend_from_slice(read);
+        let mut decoded = String::new();
+
+        loop {
+            match std::str::from_utf8(&self.pending) {
+                Ok(text) => {
+                    decoded.push_str(text);
+                    self.pending.clear();
+                    return decoded;
+                }
+                Err(err) => {
+                    let valid = err.valid_up_to();
+                    decoded.push_str(std::str::from_utf8(&self.pending[..valid]).unwrap());
+                    match err.error_len() {
+                        Some(len) => {
+                            decoded.push(char::REPLACEMENT_CHARACTER);
+                            self.pending.drain(..valid + len);
+                        }
+                        // The rest of the character is yet to be read
+                        None => {
+                            self.pending.drain(..valid);
+                            return decoded;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn finish(self) -> Option<String> {
+        (!self.pending.is_empty()).then(|| String::from_utf8_lossy(&self.pending).into_owned())
+    }
+}
+
+pub(super) fn read_available(stream: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut read = Vec::new();
+    let mut buffer = [0; 8192];
+
+    loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(size) => read.extend_from_slice(&buffer[..size]),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_a_character_split_between_the_reads() {
+        let mut decoder = Utf8Decoder::default();
+        let bytes = "café".as_bytes();
 
-        Some(exit_code)
+        assert_eq!(decoder.decode(&bytes[..4]), "caf");
+        assert_eq!(decoder.decode(&bytes[4..]), "é");
+        assert_eq!(decoder.decode(b"a\xffb\xc3"), "a\u{fffd}b");
+        assert_eq!(decoder.finish(), Some("\u{fffd}".to_string()));
     }
 
-    fn remove_tag(new_output: &str) -> String {
-        let binding = TAG_REGEX;
-        let regex = binding.get_or_init(|| Regex::new(TAG_REGEX_PATTERN).unwrap());
-        regex.replace(new_output, "").to_string()
+    #[test]
+    fn reports_a_signal_as_a_shell_would() {
+        assert_eq!(signal_exit_code("INT"), 130);
+        assert_eq!(signal_exit_code("KILL"), 137);
+        assert_eq!(signal_exit_code("TERM"), 143);
+        assert_eq!(signal_exit_code("USR1"), 138);
+        assert_eq!(signal_exit_code("USR2"), 140);
+        assert_eq!(signal_exit_code("UNKNOWN"), u8::MAX);
     }
 }
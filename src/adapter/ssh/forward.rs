@@ -0,0 +1,154 @@
+use std::future::Future;
+
+use futures::FutureExt;
+use ssh2::{Channel, Listener};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
+};
+
+use super::{exec::read_available, is_disconnected, retry, write_all, SSHSession, POLL_INTERVAL};
+
+// Like `ssh -L`: connections to the local listener are forwarded to `host:port` on the remote side.
+// The connections are dropped along with this future.
+pub async fn forward_local(session: SSHSession, listener: TcpListener, host: String, port: u16) {
+    let mut pumps = JoinSet::new();
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                eprintln!("[!] Could not accept the connection to forward: {}", err);
+                continue;
+            }
+        };
+
+        let generation = session.generation();
+        let channel = match session.open_direct_tcpip(&host, port).await {
+            Ok(channel) => channel,
+            // The connection is dropped, but the next one can be forwarded
+            Err(err) if is_disconnected(&err) => {
+                eprintln!("[!] Could not forward {}: {}", peer, err);
+                if session.reconnect(generation).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            Err(err) => {
+                eprintln!(
+                    "[!] Could not forward {} to {}:{}: {}",
+                    peer, host, port, err
+                );
+                continue;
+            }
+        };
+
+        spawn_reaping(&mut pumps, pump(channel, stream));
+    }
+}
+
+// Like `ssh -R`: connections to the remote listener are forwarded to `address` on the local side.
+// The connections are dropped along with this future.
+pub async fn forward_remote(mut listener: Listener, address: String) {
+    let mut pumps = JoinSet::new();
+    loop {
+        let channel = match retry(|| listener.accept()).await {
+            Ok(channel) => channel,
+            Err(err) => {
+                eprintln!("[!] Could not accept the connection to forward: {}", err);
+                break;
+            }
+        };
+
+        let stream = match TcpStream::connect(&address).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!(
+                    "[!] Could not forward the connection to {}: {}",
+                    address, err
+                );
+                continue;
+            }
+        };
+
+        spawn_reaping(&mut pumps, pump(channel, stream));
+    }
+
+    // The connections already forwarded are left to finish
+    while pumps.join_next().await.is_some() {}
+}
+
+// Also reaps the connections already closed
+fn spawn_reaping(pumps: &mut JoinSet<()>, pump: impl Future<Output = ()> + Send + 'static) {
+    while let Some(Some(_)) = pumps.join_next().now_or_never() {}
+    pumps.spawn(pump);
+}
+
+// Copies the data between the channel and the local stream until either side is closed
+pub(super) async fn pump(mut channel: Channel, stream: impl AsyncRead + AsyncWrite) {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut buffer = [0; 8192];
+    let mut local_closed = false;
+
+    while let Ok(received) = read_available(&mut channel) {
+        if !received.is_empty() && writer.write_all(&received).await.is_err() {
+            break;
+        }
+        if received.is_empty() && channel.eof() {
+            break;
+        }
+
+        tokio::select! {
+            read = reader.read(&mut buffer), if !local_closed => match read {
+                Ok(0) | Err(_) => {
+                    local_closed = true;
+                    retry(|| channel.send_eof()).await.ok();
+                }
+                Ok(size) => {
+                    if write_all(&mut channel, &buffer[..size]).await.is_err() {
+                        break;
+                    }
+                }
+            },
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+        }
+    }
+
+    writer.shutdown().await.ok();
+    retry(|| channel.close()).await.ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::daemon::client::DaemonClient;
+
+    use super::*;
+
+    // The session cannot open the channels, so each connection is dropped, but the next one is
+    // still accepted
+    #[tokio::test]
+    async fn drops_the_connections_it_cannot_forward() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let session = SSHSession::from_daemon(DaemonClient::detached("test"));
+        let forwarding = tokio::spawn(forward_local(
+            session,
+            listener,
+            "localhost".to_string(),
+            80,
+        ));
+
+        for _ in 0..2 {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            let mut buffer = [0; 16];
+            let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buffer)).await;
+            assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+        }
+
+        forwarding.abort();
+        assert!(forwarding.await.unwrap_err().is_cancelled());
+        assert!(TcpStream::connect(address).await.is_err());
+    }
+}
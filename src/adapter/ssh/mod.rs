@@ -1,116 +1,744 @@
 use std::{
-    io::Write,
-    net::{TcpStream, ToSocketAddrs},
-    path::Path,
-    sync::Arc,
+    io::{self, ErrorKind, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
-use ssh2::{Channel, MethodType, Session};
+use futures::future::BoxFuture;
+use ssh2::{Channel, CheckResult, ErrorCode, KnownHostFileKind, Listener, MethodType, Session};
 use ssh2_config::HostParams;
-use tokio::sync::Mutex;
+use tokio::{net::lookup_host, sync::Mutex};
 
-use crate::{check, progress::ProgressView, util::read_from_stdin};
+use crate::{
+    check,
+    config::AddressFamily,
+    daemon::client::DaemonClient,
+    error::DifmError,
+    progress::{self, ProgressView},
+    when,
+};
+
+use self::{
+    proxy::{bridge_command, bridge_jump, ConnectionStream, Transport},
+    transfer::OnProgress,
+};
 
 pub mod exec;
+pub mod forward;
+pub mod proxy;
+pub mod shell;
 pub mod transfer;
 
-pub struct SSHSession(Arc<Mutex<Session>>);
+pub const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Returned by libssh2 when the operation would block on a non-blocking session
+const LIBSSH2_ERROR_EAGAIN: i32 = -37;
+
+// Returned by libssh2 when the connection itself is gone, rather than the operation has failed
+const LIBSSH2_DISCONNECTION_ERRORS: [i32; 4] = [
+    -7,  // LIBSSH2_ERROR_SOCKET_SEND
+    -13, // LIBSSH2_ERROR_SOCKET_DISCONNECT
+    -30, // LIBSSH2_ERROR_SOCKET_TIMEOUT
+    -43, // LIBSSH2_ERROR_SOCKET_RECV
+];
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RETRY_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(16);
+
+// Asks the user for e.g. a password: `(hidden, prompt) -> answer`
+pub type Prompt<'a> = &'a (dyn Fn(bool, &str) -> io::Result<String> + Sync);
+
+pub struct SSHSession(Backend);
+
+#[derive(Clone)]
+enum Backend {
+    Direct(Arc<DirectSession>),
+    // The session is held by the daemon, which only executes commands and transfers files for us
+    Daemon(DaemonClient),
+}
+
+struct DirectSession {
+    session: Mutex<Session>,
+    origin: SessionOrigin,
+    // Bumped on every reconnection, so that the tasks noticing the same disconnection reconnect
+    // only once
+    generation: AtomicU64,
+    reconnecting: Mutex<()>,
+    given_up: AtomicBool,
+}
+
+// What is needed to establish the same session again, without asking the user
+struct SessionOrigin {
+    host: String,
+    port: u16,
+    params: HostParams,
+    transport: Transport,
+    credentials: Credentials,
+}
+
+struct Credentials {
+    user: String,
+    password: String,
+}
+
 impl SSHSession {
-    pub fn open(hostname: &str, params: &HostParams) -> Self {
+    pub async fn open(
+        hostname: &str,
+        params: &HostParams,
+        transport: Transport,
+        prompt: Prompt<'_>,
+    ) -> Result<Self, DifmError> {
         let host = params.host_name.as_deref().unwrap_or(hostname);
-        let host = if host.contains(':') {
+        let (host, split_port) = split_host_port(host);
+        let (host, port) = if let Some(port) = split_port {
             check!(
                 params.port.is_none(),
                 "Port {} is ignored, because hostname seems to contain port (it has ':')",
                 params.port.unwrap()
             );
-            host.to_string()
+            (host, port)
         } else {
-            let port = params.port.unwrap_or(22);
-            format!("{}:{}", host, port)
+            (host, params.port.unwrap_or(22))
+        };
+        let connection_error = |source| DifmError::Connection {
+            host: host.to_string(),
+            source,
         };
 
-        let stream = ProgressView::with("Connecting to the host..", |mut progress| {
-            let stream = try_connection(&host).expect("Could not connect to the host");
-            progress.success(Some(&format!(
-                "Connected to {}",
-                stream
-                    .peer_addr()
-                    .map(|addr| addr.to_string())
-                    .unwrap_or("[host]".to_string())
-            )));
-
-            stream
-        });
+        let session = match &transport {
+            Transport::Direct(family) => {
+                let mut progress = ProgressView::new("Connecting to the host..");
+                progress.start();
+                let stream = match try_connection(host, port, params, *family).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        progress.failure(None);
+                        return Err(connection_error(err));
+                    }
+                };
+                progress.success(Some(&format!(
+                    "Connected to {}",
+                    stream
+                        .peer_addr()
+                        .map(|addr| addr.to_string())
+                        .unwrap_or("[host]".to_string())
+                )));
 
-        let mut session = ProgressView::with("Configuring the session...", |mut progress| {
-            let mut session = Session::new().expect("Could not create session");
-            configure_session(&mut session, params);
-            session.set_tcp_stream(stream);
-            session.handshake().unwrap();
-            progress.success(None);
+                tokio::task::block_in_place(|| {
+                    ProgressView::with(
+                        "Configuring the session...",
+                        |mut progress| match handshake(ConnectionStream::Tcp(stream), params) {
+                            Ok(session) => {
+                                progress.success(None);
+                                Ok(session)
+                            }
+                            Err(err) => {
+                                progress.failure(None);
+                                Err(connection_error(io_error(err)))
+                            }
+                        },
+                    )
+                })?
+            }
+            Transport::Jump(_) => {
+                let mut progress = ProgressView::new("Connecting to the host via the jump host..");
+                progress.start();
+                match connect_session(host, port, params, &transport).await {
+                    Ok(session) => {
+                        progress.success(Some(&format!("Connected to {}:{}", host, port)));
+                        session
+                    }
+                    Err(err) => {
+                        progress.failure(None);
+                        return Err(connection_error(err));
+                    }
+                }
+            }
+            Transport::Command(command) => {
+                let mut progress = ProgressView::new("Starting the proxy command..");
+                progress.start();
+                match connect_session(host, port, params, &transport).await {
+                    Ok(session) => {
+                        progress.success(Some(command));
+                        session
+                    }
+                    Err(err) => {
+                        progress.failure(Some(command));
+                        return Err(connection_error(err));
+                    }
+                }
+            }
+        };
 
-            session
-        });
+        let credentials = tokio::task::block_in_place(|| {
+            match verify_host_key(&session, host, port) {
+                HostKeyStatus::Verified => {}
+                HostKeyStatus::Unknown(known_hosts) => eprintln!(
+                    "[!] {} is not in {}, so its host key is not verified",
+                    host,
+                    known_hosts.display()
+                ),
+                HostKeyStatus::Mismatch(known_hosts) => {
+                    return Err(DifmError::HostKey {
+                        host: host.to_string(),
+                        known_hosts: known_hosts.display().to_string(),
+                    })
+                }
+            }
+
+            let credentials =
+                authenticate(&session, params, prompt).map_err(|(user, source)| {
+                    DifmError::Auth {
+                        host: host.to_string(),
+                        user,
+                        source,
+                    }
+                })?;
 
-        authenticate(&mut session, params);
+            Ok(credentials)
+        })?;
 
-        println!("✅ Connected to the remote server");
+        // Several channels are used at once (e.g. steps running in parallel), which is only possible
+        // if none of them blocks the whole session while waiting for the data.
+        session.set_blocking(false);
 
         if let Some(banner) = session.banner() {
-            println!("----------------------------------");
-            println!("{}", banner);
-            println!("----------------------------------");
+            progress::report("----------------------------------");
+            progress::report(banner);
+            progress::report("----------------------------------");
         }
 
-        Self(Arc::new(Mutex::new(session)))
+        Ok(Self(Backend::Direct(Arc::new(DirectSession {
+            session: Mutex::new(session),
+            origin: SessionOrigin {
+                host: host.to_string(),
+                port,
+                params: params.clone(),
+                transport,
+                credentials,
+            },
+            generation: AtomicU64::new(0),
+            reconnecting: Mutex::new(()),
+            given_up: AtomicBool::new(false),
+        }))))
+    }
+
+    pub fn from_daemon(client: DaemonClient) -> Self {
+        Self(Backend::Daemon(client))
     }
 
     pub fn shared_clone(&self) -> Self {
         Self(self.0.clone())
     }
 
-    pub(self) async fn create_exec_channel(&self) -> Channel {
-        self.0.clone().lock().await.channel_session().unwrap()
+    pub(self) fn daemon(&self) -> Option<&DaemonClient> {
+        match &self.0 {
+            Backend::Direct(_) => None,
+            Backend::Daemon(client) => Some(client),
+        }
+    }
+
+    fn direct(&self) -> io::Result<&DirectSession> {
+        match &self.0 {
+            Backend::Direct(session) => Ok(session),
+            Backend::Daemon(_) => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "This is not available for the sessions held by the daemon (use --no-daemon)",
+            )),
+        }
+    }
+
+    // For what only a session of our own can do, e.g. forwarding the ports
+    pub fn ensure_direct(&self) -> io::Result<()> {
+        self.direct().map(|_| ())
     }
 
-    pub(self) async fn transfer_scp(&self, dest: &Path, content: &[u8]) {
-        let session = self.0.lock().await;
+    pub(self) async fn create_exec_channel(&self) -> io::Result<Channel> {
+        let session = self.direct()?.session.lock().await;
+        retry(|| session.channel_session()).await.map_err(io_error)
+    }
 
-        let mut scp_session = session
-            .scp_send(dest, 0o644, content.len() as u64, None)
-            .unwrap();
-        scp_session.write_all(content).unwrap();
-        scp_session.send_eof().unwrap();
-        scp_session.wait_eof().unwrap();
-        scp_session.close().unwrap();
-        scp_session.wait_close().unwrap();
+    pub async fn open_direct_tcpip(&self, host: &str, port: u16) -> io::Result<Channel> {
+        let session = self.direct()?.session.lock().await;
+        retry(|| session.channel_direct_tcpip(host, port, None))
+            .await
+            .map_err(io_error)
     }
-}
 
-fn try_connection(host: &str) -> Option<TcpStream> {
-    host.to_socket_addrs()
-        .expect("To be handled")
-        .find_map(|addr| {
-            let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(30));
-            match stream {
-                Ok(stream) => Some(stream),
-                Err(_) => None,
+    pub async fn forward_listen(&self, bind: &str, port: u16) -> io::Result<(Listener, u16)> {
+        let session = self.direct()?.session.lock().await;
+        retry(|| session.channel_forward_listen(port, Some(bind), None))
+            .await
+            .map_err(io_error)
+    }
+
+    pub(self) async fn transfer_scp(
+        &self,
+        dest: &Path,
+        content: &[u8],
+        on_progress: OnProgress<'_>,
+    ) -> io::Result<()> {
+        // The daemon only tells when the whole file is sent
+        if let Some(client) = self.daemon() {
+            client.transfer(dest, content).await?;
+            on_progress(content.len() as u64);
+            return Ok(());
+        }
+
+        let session = self.direct()?.session.lock().await;
+
+        let mut scp_session = retry(|| session.scp_send(dest, 0o644, content.len() as u64, None))
+            .await
+            .map_err(io_error)?;
+
+        // The channel does not tell why the write failed, but it is the connection in practice
+        if let Err(err) = write_all_reporting(&mut scp_session, content, on_progress).await {
+            return Err(io::Error::new(ErrorKind::ConnectionAborted, err));
+        }
+
+        retry(|| scp_session.send_eof()).await.map_err(io_error)?;
+        retry(|| scp_session.wait_eof()).await.map_err(io_error)?;
+        retry(|| scp_session.close()).await.map_err(io_error)?;
+        retry(|| scp_session.wait_close()).await.map_err(io_error)
+    }
+
+    pub(self) async fn receive_scp(&self, source: &Path) -> io::Result<Vec<u8>> {
+        if let Some(client) = self.daemon() {
+            return client.fetch(source).await;
+        }
+
+        let session = self.direct()?.session.lock().await;
+
+        let (mut scp_session, stat) = retry(|| session.scp_recv(source)).await.map_err(io_error)?;
+
+        let size = stat.size() as usize;
+        let mut content = Vec::with_capacity(size);
+        while content.len() < size {
+            let read = exec::read_available(&mut scp_session)
+                .map_err(|err| io::Error::new(ErrorKind::ConnectionAborted, err))?;
+
+            if read.is_empty() {
+                if scp_session.eof() {
+                    break;
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+            content.extend(read);
+        }
+        // The end of the file is marked by an extra byte
+        content.truncate(size);
+
+        retry(|| scp_session.send_eof()).await.map_err(io_error)?;
+        retry(|| scp_session.wait_eof()).await.map_err(io_error)?;
+        retry(|| scp_session.close()).await.map_err(io_error)?;
+        retry(|| scp_session.wait_close()).await.map_err(io_error)?;
+
+        Ok(content)
+    }
+
+    // Taken before an operation, and passed to `reconnect` if the operation has lost the connection
+    pub fn generation(&self) -> u64 {
+        match &self.0 {
+            Backend::Direct(direct) => direct.generation.load(Ordering::SeqCst),
+            Backend::Daemon(_) => 0,
+        }
+    }
+
+    // Establishes the session again with the cached credentials, unless someone else already did
+    // since `generation`. Channels opened on the lost session are not recovered. The daemon
+    // reconnects the sessions it holds by itself.
+    pub async fn reconnect(&self, generation: u64) -> io::Result<()> {
+        let Backend::Direct(direct) = &self.0 else {
+            return Ok(());
+        };
+        let _reconnecting = direct.reconnecting.lock().await;
+
+        if direct.generation.load(Ordering::SeqCst) != generation {
+            return Ok(());
+        }
+        if direct.given_up.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                ErrorKind::NotConnected,
+                "The connection has been lost",
+            ));
+        }
+
+        let mut delay = RETRY_INITIAL_DELAY;
+        for attempt in 1..=RECONNECT_ATTEMPTS {
+            eprintln!(
+                "[!] Lost the connection to {}. Reconnecting in {}s ({}/{})",
+                direct.origin.host,
+                delay.as_secs(),
+                attempt,
+                RECONNECT_ATTEMPTS
+            );
+            tokio::time::sleep(delay).await;
+
+            match direct.origin.establish().await {
+                Ok(session) => {
+                    *direct.session.lock().await = session;
+                    direct.generation.fetch_add(1, Ordering::SeqCst);
+                    progress::report("✅ Reconnected to the remote server");
+
+                    return Ok(());
+                }
+                Err(err) => eprintln!("[!] Could not reconnect: {}", err),
             }
+
+            delay = (delay * 2).min(RETRY_MAX_DELAY);
+        }
+
+        direct.given_up.store(true, Ordering::SeqCst);
+        Err(io::Error::new(
+            ErrorKind::NotConnected,
+            format!("Gave up reconnecting after {} attempts", RECONNECT_ATTEMPTS),
+        ))
+    }
+}
+
+impl SessionOrigin {
+    // Boxed, since reconnecting through a jump host reconnects the jump host first
+    fn establish(&self) -> BoxFuture<'_, io::Result<Session>> {
+        Box::pin(async move {
+            let session =
+                connect_session(&self.host, self.port, &self.params, &self.transport).await?;
+
+            tokio::task::block_in_place(|| {
+                if let HostKeyStatus::Mismatch(known_hosts) =
+                    verify_host_key(&session, &self.host, self.port)
+                {
+                    return Err(io::Error::new(
+                        ErrorKind::PermissionDenied,
+                        format!(
+                            "The host key does not match the one in {}",
+                            known_hosts.display()
+                        ),
+                    ));
+                }
+                session
+                    .userauth_password(&self.credentials.user, &self.credentials.password)
+                    .map_err(io_error)?;
+                session.set_blocking(false);
+
+                Ok(session)
+            })
         })
+    }
 }
 
-fn authenticate(session: &mut Session, params: &HostParams) {
-    let user = params
-        .user
-        .clone()
-        .unwrap_or_else(|| read_from_stdin(false, "Username :"));
+pub fn is_disconnected(err: &io::Error) -> bool {
+    err.kind() == ErrorKind::ConnectionAborted
+}
+
+// Keeps the disconnections distinguishable after being converted
+fn io_error(err: ssh2::Error) -> io::Error {
+    match err.code() {
+        ErrorCode::Session(code) if LIBSSH2_DISCONNECTION_ERRORS.contains(&code) => {
+            io::Error::new(ErrorKind::ConnectionAborted, err)
+        }
+        _ => err.into(),
+    }
+}
 
-    let password = read_from_stdin(true, &format!("[{}] Password: ", user));
+async fn write_all(writer: &mut impl Write, content: &[u8]) -> io::Result<()> {
+    write_all_reporting(writer, content, &mut |_| {}).await
+}
 
-    if let Err(err) = session.userauth_password(&user, &password) {
-        panic!("Authentication failed: {}", err);
+// Tells the bytes written so far after each write
+async fn write_all_reporting(
+    writer: &mut impl Write,
+    content: &[u8],
+    on_progress: OnProgress<'_>,
+) -> io::Result<()> {
+    let mut written = 0;
+    while written < content.len() {
+        match writer.write(&content[written..]) {
+            Ok(size) => {
+                written += size;
+                on_progress(written as u64);
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                tokio::time::sleep(POLL_INTERVAL).await
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(())
+}
+
+async fn retry<T>(mut operation: impl FnMut() -> Result<T, ssh2::Error>) -> Result<T, ssh2::Error> {
+    loop {
+        match operation() {
+            Err(err) if err.code() == ErrorCode::Session(LIBSSH2_ERROR_EAGAIN) => {
+                tokio::time::sleep(POLL_INTERVAL).await
+            }
+            result => return result,
+        }
+    }
+}
+
+// A proxied connection only tells that it has failed at the handshake, e.g. when the proxy
+// command cannot reach the host, so it is attempted with the handshake as many times as
+// `ConnectionAttempts`
+async fn connect_session(
+    host: &str,
+    port: u16,
+    params: &HostParams,
+    transport: &Transport,
+) -> io::Result<Session> {
+    let attempts = params.connection_attempts.unwrap_or(1).max(1);
+
+    let mut delay = RETRY_INITIAL_DELAY;
+    let mut attempt = 1;
+    loop {
+        let stream = match transport {
+            // Retries by itself
+            Transport::Direct(family) => {
+                let stream = try_connection(host, port, params, *family).await?;
+                return tokio::task::block_in_place(|| {
+                    handshake(ConnectionStream::Tcp(stream), params).map_err(io_error)
+                });
+            }
+            // The jump host has most likely lost its connection as well
+            Transport::Jump(jump) => {
+                let generation = jump.generation();
+                match bridge_jump(jump, host, port).await {
+                    Err(err) if is_disconnected(&err) => {
+                        jump.reconnect(generation).await?;
+                        bridge_jump(jump, host, port).await
+                    }
+                    stream => stream,
+                }
+            }
+            Transport::Command(command) => bridge_command(command),
+        };
+
+        // The handshake blocks, while a proxied connection needs other tasks to keep running
+        let result = stream.and_then(|stream| {
+            tokio::task::block_in_place(|| handshake(stream, params).map_err(io_error))
+        });
+        match result {
+            Err(_) if attempt < attempts => {
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(RETRY_MAX_DELAY);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Tries each resolved address of the family in order, as many times as `ConnectionAttempts`.
+// The error tells how each of the addresses failed in the last attempt.
+async fn try_connection(
+    host: &str,
+    port: u16,
+    params: &HostParams,
+    family: AddressFamily,
+) -> io::Result<TcpStream> {
+    let timeout = params.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+    let attempts = params.connection_attempts.unwrap_or(1).max(1);
+
+    let mut delay = RETRY_INITIAL_DELAY;
+    let mut failures = Vec::new();
+    for attempt in 1..=attempts {
+        if attempt > 1 {
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(RETRY_MAX_DELAY);
+        }
+        failures.clear();
+
+        // A hanging resolver counts against the timeout as well
+        let addresses = match tokio::time::timeout(timeout, lookup_host((host, port))).await {
+            Ok(Ok(addresses)) => addresses
+                .filter(|address| match family {
+                    AddressFamily::Any => true,
+                    AddressFamily::Inet => address.is_ipv4(),
+                    AddressFamily::Inet6 => address.is_ipv6(),
+                })
+                .collect::<Vec<_>>(),
+            Ok(Err(err)) => {
+                failures.push(format!("{}: {}", host, err));
+                continue;
+            }
+            Err(_) => {
+                failures.push(format!(
+                    "{}: Timed out resolving after {}s",
+                    host,
+                    timeout.as_secs()
+                ));
+                continue;
+            }
+        };
+        if addresses.is_empty() {
+            failures.push(format!("{} has no address of {:?}", host, family));
+        }
+
+        for address in addresses {
+            match tokio::time::timeout(timeout, tokio::net::TcpStream::connect(address)).await {
+                Ok(Ok(stream)) => {
+                    // libssh2 decides by itself whether to block
+                    let stream = stream.into_std()?;
+                    stream.set_nonblocking(false)?;
+                    return Ok(stream);
+                }
+                Ok(Err(err)) => failures.push(format!("{}: {}", address, err)),
+                Err(_) => failures.push(format!(
+                    "{}: Timed out after {}s",
+                    address,
+                    timeout.as_secs()
+                )),
+            }
+        }
+    }
+
+    Err(io::Error::new(
+        ErrorKind::NotConnected,
+        format!(
+            "Could not connect to {}:{} in {} attempt(s)\n{}",
+            host,
+            port,
+            attempts,
+            failures
+                .iter()
+                .map(|failure| format!("  - {}", failure))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ),
+    ))
+}
+
+fn handshake(stream: ConnectionStream, params: &HostParams) -> Result<Session, ssh2::Error> {
+    let mut session = Session::new()?;
+    configure_session(&mut session, params);
+    session.set_tcp_stream(stream);
+    session.handshake()?;
+
+    Ok(session)
+}
+
+// `host:port` or `[address]:port`. An IPv6 address alone has colons too, so it is left as it is.
+fn split_host_port(host: &str) -> (&str, Option<u16>) {
+    if let Some(bracketed) = host.strip_prefix('[') {
+        return match bracketed.split_once(']') {
+            Some((address, "")) => (address, None),
+            Some((address, port)) => match port.strip_prefix(':').map(str::parse) {
+                Some(Ok(port)) => (address, Some(port)),
+                _ => (host, None),
+            },
+            None => (host, None),
+        };
+    }
+
+    match host.split_once(':') {
+        Some((name, port)) if !port.contains(':') => match port.parse() {
+            Ok(port) => (name, Some(port)),
+            Err(_) => (host, None),
+        },
+        _ => (host, None),
+    }
+}
+
+fn authenticate(
+    session: &Session,
+    params: &HostParams,
+    prompt: Prompt,
+) -> Result<Credentials, (String, io::Error)> {
+    let user = match params.user.clone() {
+        Some(user) => user,
+        None => prompt(false, "Username :")
+            .map_err(|err| (String::new(), err))?
+            .trim()
+            .to_string(),
+    };
+
+    let password = match prompt(true, &format!("[{}] Password: ", user)) {
+        Ok(password) => password,
+        Err(err) => return Err((user, err)),
+    };
+
+    match session.userauth_password(&user, &password) {
+        Ok(()) => Ok(Credentials { user, password }),
+        Err(err) => Err((user, err.into())),
+    }
+}
+
+enum HostKeyStatus {
+    Verified,
+    Unknown(PathBuf),
+    Mismatch(PathBuf),
+}
+
+// Only refuses a key differing from the recorded one. Nothing is written to known_hosts, so an
+// unknown host stays unknown.
+fn verify_host_key(session: &Session, host: &str, port: u16) -> HostKeyStatus {
+    let path = PathBuf::from(std::env::var("HOME").unwrap_or_default())
+        .join(".ssh")
+        .join("known_hosts");
+
+    let Some((key, _)) = session.host_key() else {
+        return HostKeyStatus::Unknown(path);
+    };
+    let Ok(content) = std::fs::read_to_string(&path) else {
+        return HostKeyStatus::Unknown(path);
+    };
+
+    match check_host_key(session, &content, host, port, key) {
+        CheckResult::Match => HostKeyStatus::Verified,
+        CheckResult::Mismatch => HostKeyStatus::Mismatch(path),
+        CheckResult::NotFound | CheckResult::Failure => HostKeyStatus::Unknown(path),
+    }
+}
+
+// Only the entries of the type of the key offered are compared, as libssh2 would otherwise find
+// e.g. the RSA key recorded for a host offering its Ed25519 one to be a mismatch
+fn check_host_key(
+    session: &Session,
+    known_hosts: &str,
+    host: &str,
+    port: u16,
+    key: &[u8],
+) -> CheckResult {
+    let Some(key_type) = key_type(key) else {
+        return CheckResult::Failure;
+    };
+    let Ok(mut entries) = session.known_hosts() else {
+        return CheckResult::Failure;
+    };
+
+    for line in known_hosts
+        .lines()
+        .filter(|line| entry_key_type(line) == Some(key_type))
+    {
+        // The lines libssh2 cannot read, e.g. the ones with a marker, are left out as before
+        entries.read_str(line, KnownHostFileKind::OpenSSH).ok();
+    }
+
+    entries.check_port(host, port, key)
+}
+
+// The key blob starts with its type as a length-prefixed string
+fn key_type(key: &[u8]) -> Option<&str> {
+    let length = u32::from_be_bytes(key.get(..4)?.try_into().ok()?) as usize;
+    std::str::from_utf8(key.get(4..4 + length)?).ok()
+}
+
+// `[@marker] <hosts> <type> <key> [comment]`
+fn entry_key_type(line: &str) -> Option<&str> {
+    let mut fields = line.split_whitespace();
+    let first = fields.next()?;
+    when! {
+        first.starts_with('#') => None,
+        first.starts_with('@') => fields.nth(1),
+        _ => fields.next(),
     }
 }
 
@@ -119,9 +747,8 @@ fn configure_session(session: &mut Session, params: &HostParams) {
     if let Some(compress) = params.compression {
         session.set_compress(compress);
     }
-    if params.tcp_keep_alive.unwrap_or(false) && params.server_alive_interval.is_some() {
-        let interval = params.server_alive_interval.unwrap().as_secs() as u32;
-        session.set_keepalive(true, interval);
+    if let (Some(true), Some(interval)) = (params.tcp_keep_alive, params.server_alive_interval) {
+        session.set_keepalive(true, interval.as_secs() as u32);
     }
 
     macro_rules! report_if_fail {
@@ -165,3 +792,143 @@ fn configure_session(session: &mut Session, params: &HostParams) {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{daemon::client::DaemonClient, util::TempTree};
+
+    use super::*;
+
+    const ED25519_KEY: &str =
+        "AAAAC3NzaC1lZDI1NTE5AAAAIAEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEB";
+    const OTHER_ED25519_KEY: &str =
+        "AAAAC3NzaC1lZDI1NTE5AAAAIAICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgIC";
+    const RSA_KEY: &str = "AAAAB3NzaC1yc2EAAAADAQABAAAAQAMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwM=";
+
+    fn decode(key: &str) -> Vec<u8> {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut bytes = Vec::new();
+        let (mut buffer, mut bits) = (0u32, 0);
+        for char in key.bytes().take_while(|char| *char != b'=') {
+            let value = ALPHABET.iter().position(|known| *known == char).unwrap() as u32;
+            buffer = (buffer << 6) | value;
+            bits += 6;
+            if bits >= 8 {
+                bits -= 8;
+                bytes.push((buffer >> bits) as u8);
+            }
+        }
+        bytes
+    }
+
+    fn check(known_hosts: &str, key: &str) -> CheckResult {
+        let session = Session::new().unwrap();
+        check_host_key(&session, known_hosts, "example.com", 22, &decode(key))
+    }
+
+    #[test]
+    fn reads_the_key_type_from_the_blob() {
+        assert_eq!(key_type(&decode(ED25519_KEY)), Some("ssh-ed25519"));
+        assert_eq!(key_type(&decode(RSA_KEY)), Some("ssh-rsa"));
+        assert_eq!(key_type(&[0, 0, 0, 9, b'a']), None);
+    }
+
+    #[test]
+    fn reads_the_key_type_of_the_entry() {
+        assert_eq!(
+            entry_key_type("example.com ssh-rsa AAAA comment"),
+            Some("ssh-rsa")
+        );
+        assert_eq!(
+            entry_key_type("@cert-authority *.example.com ssh-ed25519 AAAA"),
+            Some("ssh-ed25519")
+        );
+        assert_eq!(entry_key_type("# example.com ssh-rsa AAAA"), None);
+        assert_eq!(entry_key_type(""), None);
+    }
+
+    #[test]
+    fn matches_the_entry_of_the_same_type() {
+        let known_hosts =
+            format!("example.com ssh-rsa {RSA_KEY}\nexample.com ssh-ed25519 {ED25519_KEY}\n");
+        assert!(matches!(
+            check(&known_hosts, ED25519_KEY),
+            CheckResult::Match
+        ));
+        assert!(matches!(check(&known_hosts, RSA_KEY), CheckResult::Match));
+    }
+
+    #[test]
+    fn refuses_a_different_key_of_the_same_type() {
+        let known_hosts = format!("example.com ssh-ed25519 {ED25519_KEY}\n");
+        assert!(matches!(
+            check(&known_hosts, OTHER_ED25519_KEY),
+            CheckResult::Mismatch
+        ));
+    }
+
+    #[test]
+    fn does_not_know_a_host_recorded_only_with_other_types() {
+        let known_hosts = format!("example.com ssh-rsa {RSA_KEY}\n");
+        assert!(matches!(
+            check(&known_hosts, ED25519_KEY),
+            CheckResult::NotFound
+        ));
+        assert!(matches!(check("", ED25519_KEY), CheckResult::NotFound));
+    }
+
+    #[tokio::test]
+    async fn leaves_the_reconnection_to_the_daemon() {
+        let session = SSHSession::from_daemon(DaemonClient::detached("test"));
+
+        assert!(session.reconnect(session.generation()).await.is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn attempts_the_proxy_command_as_many_times_as_configured() {
+        let tree = TempTree::new(&[]);
+        let spawns = tree.join("spawns");
+        let params = HostParams {
+            connection_attempts: Some(2),
+            ..Default::default()
+        };
+        let transport = Transport::Command(format!("echo >> {}", spawns.display()));
+
+        assert!(connect_session("example.com", 22, &params, &transport)
+            .await
+            .is_err());
+        assert_eq!(std::fs::read_to_string(spawns).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn fails_to_log_in_without_the_password() {
+        let closed = |_: bool, _: &str| -> io::Result<String> {
+            Err(io::Error::new(ErrorKind::UnexpectedEof, "stdin is closed"))
+        };
+        let params = HostParams {
+            user: Some("me".to_string()),
+            ..Default::default()
+        };
+
+        let Err((user, err)) = authenticate(&Session::new().unwrap(), &params, &closed) else {
+            panic!("expected the authentication to fail");
+        };
+        assert_eq!(user, "me");
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn splits_the_port_off_the_host() {
+        assert_eq!(split_host_port("example.com"), ("example.com", None));
+        assert_eq!(
+            split_host_port("example.com:2222"),
+            ("example.com", Some(2222))
+        );
+        assert_eq!(split_host_port("2001:db8::1"), ("2001:db8::1", None));
+        assert_eq!(
+            split_host_port("[2001:db8::1]:2222"),
+            ("2001:db8::1", Some(2222))
+        );
+        assert_eq!(split_host_port("[2001:db8::1]"), ("2001:db8::1", None));
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    io,
+    net::{Shutdown, TcpStream},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixStream,
+    },
+    process::{Command, Stdio},
+    thread,
+};
+
+use crate::config::AddressFamily;
+
+use super::{forward::pump, SSHSession};
+
+pub enum Transport {
+    Direct(AddressFamily),
+    Jump(SSHSession),
+    Command(String),
+}
+
+// What libssh2 talks to. Connections through a proxy are bridged through a socket pair,
+// since libssh2 only accepts a file descriptor.
+pub enum ConnectionStream {
+    Tcp(TcpStream),
+    Bridged(UnixStream),
+}
+
+impl AsRawFd for ConnectionStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            ConnectionStream::Tcp(stream) => stream.as_raw_fd(),
+            ConnectionStream::Bridged(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+pub async fn bridge_jump(jump: &SSHSession, host: &str, port: u16) -> io::Result<ConnectionStream> {
+    let channel = jump.open_direct_tcpip(host, port).await?;
+
+    let (ours, theirs) = UnixStream::pair()?;
+    ours.set_nonblocking(true)?;
+    let ours = tokio::net::UnixStream::from_std(ours)?;
+
+    tokio::spawn(pump(channel, ours));
+
+    Ok(ConnectionStream::Bridged(theirs))
+}
+
+pub fn bridge_command(command: &str) -> io::Result<ConnectionStream> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+
+    let mut child_stdin = child.stdin.take().unwrap();
+    let mut child_stdout = child.stdout.take().unwrap();
+
+    let (ours, theirs) = UnixStream::pair()?;
+    let mut ours_writer = ours.try_clone()?;
+    let mut ours_reader = ours;
+
+    thread::spawn(move || {
+        io::copy(&mut child_stdout, &mut ours_writer).ok();
+        ours_writer.shutdown(Shutdown::Write).ok();
+    });
+    thread::spawn(move || {
+        io::copy(&mut ours_reader, &mut child_stdin).ok();
+        drop(child_stdin);
+        child.wait().ok();
+    });
+
+    Ok(ConnectionStream::Bridged(theirs))
+}
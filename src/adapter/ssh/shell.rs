@@ -0,0 +1,98 @@
+use std::{
+    io::{self, stdin, stdout, Read, Write},
+    path::Path,
+};
+
+use ssh2::Channel;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
+
+use crate::{
+    adapter::terminal::{self, RawMode},
+    util::shell_quote,
+};
+
+use super::{
+    exec::{read_available, PtyRequest},
+    io_error, retry, write_all, SSHSession, POLL_INTERVAL,
+};
+
+pub struct ShellChannel {
+    channel: Channel,
+}
+
+impl ShellChannel {
+    pub async fn open(session: &SSHSession, cwd: &Path) -> io::Result<Self> {
+        let mut channel = session.create_exec_channel().await?;
+
+        let pty = PtyRequest::from_local_terminal();
+        let size = (pty.size.cols, pty.size.rows, 0, 0);
+
+        retry(|| channel.request_pty(&pty.term, None, Some(size)))
+            .await
+            .map_err(io_error)?;
+        retry(|| {
+            channel.exec(&format!(
+                "cd {} && exec \"${{SHELL:-sh}}\" -l",
+                shell_quote(&cwd.to_string_lossy())
+            ))
+        })
+        .await
+        .map_err(io_error)?;
+
+        Ok(Self { channel })
+    }
+
+    // Forwards the local terminal to the shell until it exits, and returns its exit status
+    // The terminal is restored when the raw mode is dropped, even on the errors
+    pub async fn attach(mut self) -> io::Result<i32> {
+        let raw_mode = RawMode::enable()?;
+
+        let (input_sender, mut input) = mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            while let Ok(size @ 1..) = stdin().read(&mut buffer) {
+                if input_sender.send(buffer[..size].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut resize = signal(SignalKind::window_change())?;
+
+        loop {
+            let output = read_available(&mut self.channel.stream(0))?;
+            if !output.is_empty() {
+                let mut stdout = stdout().lock();
+                stdout.write_all(&output)?;
+                stdout.flush()?;
+            }
+
+            if output.is_empty() && self.channel.eof() {
+                break;
+            }
+
+            tokio::select! {
+                Some(keys) = input.recv() => {
+                    write_all(&mut self.channel, &keys).await?;
+                }
+                Some(()) = resize.recv() => {
+                    let size = terminal::size().unwrap_or_default();
+                    retry(|| self.channel.request_pty_size(size.cols, size.rows, None, None))
+                        .await
+                        .map_err(io_error)?;
+                }
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+
+        drop(raw_mode);
+
+        retry(|| self.channel.wait_close())
+            .await
+            .map_err(io_error)?;
+        self.channel.exit_status().map_err(io_error)
+    }
+}
@@ -1,22 +1,65 @@
-use std::{fs::File, io::Read, path::Path};
+use std::{fmt::Display, fs, io, path::Path};
 
-use super::SSHSession;
+use super::{is_disconnected, SSHSession};
+
+// Called with the bytes of the file sent so far
+pub type OnProgress<'a> = &'a mut (dyn FnMut(u64) + Send);
 
 #[derive(Debug)]
-pub enum FileTransferError {}
+pub enum FileTransferError {
+    Read(io::Error),
+    Write(io::Error),
+    // Worth retrying after reconnecting
+    Disconnected(io::Error),
+}
 
-pub async fn transfer_file(
+impl Display for FileTransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileTransferError::Read(err) => write!(f, "Could not read the file: {}", err),
+            FileTransferError::Write(err) => write!(f, "Could not write the file: {}", err),
+            FileTransferError::Disconnected(err) => write!(f, "Lost the connection: {}", err),
+        }
+    }
+}
+
+pub async fn transfer_content(
     session: &SSHSession,
-    local_source: &Path,
+    content: &[u8],
     remote_dest: &Path,
+    on_progress: OnProgress<'_>,
+) -> Result<(), FileTransferError> {
+    session
+        .transfer_scp(remote_dest, content, on_progress)
+        .await
+        .map_err(|err| match is_disconnected(&err) {
+            true => FileTransferError::Disconnected(err),
+            false => FileTransferError::Write(err),
+        })
+}
+
+pub async fn receive_file(
+    session: &SSHSession,
+    remote_source: &Path,
+    local_dest: &Path,
 ) -> Result<(), FileTransferError> {
-    let mut content = Vec::new();
-    File::open(local_source)
-        .unwrap()
-        .read_to_end(&mut content)
-        .unwrap();
+    let content = receive_content(session, remote_source).await?;
 
-    session.transfer_scp(remote_dest, &content).await;
+    if let Some(parent) = local_dest.parent() {
+        fs::create_dir_all(parent).map_err(FileTransferError::Write)?;
+    }
+    fs::write(local_dest, content).map_err(FileTransferError::Write)
+}
 
-    Ok(())
+pub async fn receive_content(
+    session: &SSHSession,
+    remote_source: &Path,
+) -> Result<Vec<u8>, FileTransferError> {
+    session
+        .receive_scp(remote_source)
+        .await
+        .map_err(|err| match is_disconnected(&err) {
+            true => FileTransferError::Disconnected(err),
+            false => FileTransferError::Read(err),
+        })
 }
@@ -0,0 +1,59 @@
+use std::{io, mem::MaybeUninit};
+
+use libc::{termios, winsize, STDIN_FILENO, STDOUT_FILENO};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TerminalSize {
+    pub cols: u32,
+    pub rows: u32,
+}
+
+impl Default for TerminalSize {
+    fn default() -> Self {
+        Self { cols: 80, rows: 24 }
+    }
+}
+
+pub fn size() -> Option<TerminalSize> {
+    let mut size = MaybeUninit::<winsize>::uninit();
+
+    if unsafe { libc::ioctl(STDOUT_FILENO, libc::TIOCGWINSZ, size.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let size = unsafe { size.assume_init() };
+
+    (size.ws_col > 0 && size.ws_row > 0).then_some(TerminalSize {
+        cols: size.ws_col as u32,
+        rows: size.ws_row as u32,
+    })
+}
+
+// Puts the local terminal into the raw mode, and restores the original mode when dropped
+pub struct RawMode {
+    original: termios,
+}
+
+impl RawMode {
+    pub fn enable() -> io::Result<Self> {
+        let mut original = MaybeUninit::<termios>::uninit();
+        if unsafe { libc::tcgetattr(STDIN_FILENO, original.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let original = unsafe { original.assume_init() };
+
+        let mut raw = original;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        if unsafe { libc::tcsetattr(STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self { original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(STDIN_FILENO, libc::TCSANOW, &self.original) };
+    }
+}
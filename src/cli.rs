@@ -0,0 +1,161 @@
+use std::path::PathBuf;
+
+use clap::{ArgAction, Parser, Subcommand};
+
+use crate::{
+    daemon::DEFAULT_IDLE_TIMEOUT,
+    event::OutputFormat,
+    progress::{ColorChoice, Verbosity},
+};
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Send the code to the remote server and execute some command on it"
+)]
+pub struct Cli {
+    /// Path to the task definition (defaults to ./difm.yaml)
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Only work on this host, if the task has several
+    #[arg(long, global = true)]
+    pub host: Option<String>,
+
+    /// Connect by ourselves even if the daemon is running
+    #[arg(long, global = true)]
+    pub no_daemon: bool,
+
+    /// Compare the files with the host, then only print what would be sent, run and fetched
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    /// Send the files at this git revision instead of the working tree, e.g. HEAD~1
+    #[arg(long, value_name = "REV", global = true)]
+    pub rev: Option<String>,
+
+    /// Apply a profile in `profiles:` on top of the task (repeatable, applied in order)
+    #[arg(short, long = "profile", value_name = "NAME", global = true)]
+    pub profile: Vec<String>,
+
+    /// Override a variable in `vars:` (repeatable)
+    #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_variable, global = true)]
+    pub set: Vec<(String, String)>,
+
+    /// Print the progress for humans, or as newline-delimited JSON events for the other tools
+    #[arg(long, value_enum, default_value_t = OutputFormat::Human, global = true)]
+    pub output: OutputFormat,
+
+    /// Color the output
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto, global = true)]
+    pub color: ColorChoice,
+
+    /// Only show the failures and the summary
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Also show each file sent (-v), and each command run on the host (-vv)
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    pub verbose: u8,
+
+    /// Write a JUnit XML report of the steps after the run, e.g. for the CI
+    #[arg(long, value_name = "PATH", global = true)]
+    pub junit: Option<PathBuf>,
+
+    /// Write a Markdown summary of the run, e.g. for a comment on the pull request
+    #[arg(long, value_name = "PATH", global = true)]
+    pub markdown: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Sync the code and run the steps (default)
+    Run,
+
+    /// Open an interactive shell in the code destination on the remote host
+    Shell {
+        /// Sync the code before opening the shell
+        #[arg(long)]
+        sync: bool,
+    },
+
+    /// Forward the ports in `forward:` until interrupted
+    Tunnel,
+
+    /// Check the task definition without connecting to anywhere
+    Check {
+        /// Print the JSON Schema of the task definition instead, e.g. for the completion in editors
+        #[arg(long)]
+        schema: bool,
+    },
+
+    /// List the files selected to be sent, without connecting to anywhere
+    LsFiles,
+
+    /// List the recorded runs, newest first
+    History {
+        /// Show at most this many runs
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+    },
+
+    /// Show the output of the steps of a recorded run
+    Logs {
+        /// ID of the run, a unique prefix of it, or `last`
+        run: String,
+
+        /// Only show this step
+        step: Option<String>,
+    },
+
+    /// Manage the daemon keeping the sessions to the hosts alive between the invocations
+    Daemon {
+        #[command(subcommand)]
+        command: DaemonCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DaemonCommand {
+    /// Start the daemon in the background
+    Start {
+        /// Stop after this many seconds without any request
+        #[arg(long, default_value_t = DEFAULT_IDLE_TIMEOUT.as_secs())]
+        idle_timeout: u64,
+    },
+
+    /// Show the sessions held by the daemon
+    Status,
+
+    /// Stop the daemon, closing all the sessions
+    Stop,
+
+    /// Run the daemon in the foreground (used by `start`)
+    #[command(hide = true)]
+    Serve {
+        #[arg(long, default_value_t = DEFAULT_IDLE_TIMEOUT.as_secs())]
+        idle_timeout: u64,
+    },
+}
+
+fn parse_variable(text: &str) -> Result<(String, String), String> {
+    let (key, value) = text
+        .split_once('=')
+        .ok_or_else(|| format!("`{}` should be in the form of KEY=VALUE", text))?;
+
+    Ok((key.trim().to_string(), value.to_string()))
+}
+
+impl Cli {
+    pub fn verbosity(&self) -> Verbosity {
+        match (self.quiet, self.verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Verbose,
+            (false, _) => Verbosity::Debug,
+        }
+    }
+}
@@ -0,0 +1,291 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use glob::Pattern;
+use serde::{de::Visitor, Deserialize, Serialize};
+
+use crate::{
+    adapter::{fs::Entry, git},
+    config::{TaskDefinition, TaskHost},
+    remote::task::TaskRunStatus,
+};
+
+use self::parser::{Expr, Function, ParseError, Value};
+
+pub mod parser;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(into = "String")]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+impl Condition {
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn evaluate(&self, context: &ConditionContext) -> bool {
+        evaluate(&self.expr, context).is_truthy()
+    }
+}
+
+impl TryFrom<String> for Condition {
+    type Error = ParseError;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        let expr = parser::parse(&source)?;
+
+        Ok(Self { source, expr })
+    }
+}
+
+impl From<Condition> for String {
+    fn from(condition: Condition) -> Self {
+        condition.source
+    }
+}
+
+// Conditions are parsed inside the visitor, so that serde_yaml can attach the location of the
+// offending `if:` to the parse error.
+impl<'de> Deserialize<'de> for Condition {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ConditionVisitor;
+
+        impl<'de> Visitor<'de> for ConditionVisitor {
+            type Value = Condition;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a condition expression")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, source: &str) -> Result<Self::Value, E> {
+                Condition::try_from(source.to_string()).map_err(E::custom)
+            }
+
+            fn visit_bool<E: serde::de::Error>(self, value: bool) -> Result<Self::Value, E> {
+                self.visit_str(&value.to_string())
+            }
+        }
+
+        deserializer.deserialize_any(ConditionVisitor)
+    }
+}
+
+#[derive(Clone)]
+pub struct ConditionContext {
+    pub host: String,
+    pub alias: Option<String>,
+    // Of `code.rev`, or of the working tree of `code.location`
+    pub branch: Option<String>,
+    pub commit: Option<String>,
+    pub changed_files: Vec<PathBuf>,
+    pub matrix: Vec<(String, String)>,
+    pub vars: BTreeMap<String, String>,
+    pub steps: Vec<(String, TaskRunStatus)>,
+}
+
+impl ConditionContext {
+    pub fn new(task: &TaskDefinition, host: &TaskHost, changed_entries: &[Entry]) -> Self {
+        Self {
+            host: host.name.clone(),
+            alias: task.alias.clone(),
+            branch: git::branch(&task.code.location, task.code.rev.as_deref()),
+            commit: git::commit(&task.code.location, task.code.rev.as_deref()),
+            changed_files: changed_entries
+                .iter()
+                .map(|entry| entry.relative_path().to_path_buf())
+                .collect(),
+            matrix: Vec::new(),
+            vars: task.vars.clone(),
+            steps: Vec::new(),
+        }
+    }
+
+    fn step(&self, name: &str) -> Option<TaskRunStatus> {
+        self.steps
+            .iter()
+            .find(|(step, _)| step == name)
+            .map(|(_, status)| *status)
+    }
+}
+
+impl Value {
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Null => false,
+            Value::Bool(bool) => *bool,
+            Value::Number(number) => *number != 0.0,
+            Value::String(string) => !string.is_empty(),
+        }
+    }
+
+    fn as_string(&self) -> String {
+        match self {
+            Value::Null => String::new(),
+            Value::Bool(bool) => bool.to_string(),
+            Value::Number(number) => number.to_string(),
+            Value::String(string) => string.clone(),
+        }
+    }
+}
+
+fn evaluate(expr: &Expr, context: &ConditionContext) -> Value {
+    match expr {
+        Expr::Literal(value) => value.clone(),
+        Expr::Context(path) => resolve(path, context),
+        Expr::Call(function, args) => call(*function, args, context),
+        Expr::Not(expr) => Value::Bool(!evaluate(expr, context).is_truthy()),
+        Expr::And(lhs, rhs) => {
+            Value::Bool(evaluate(lhs, context).is_truthy() && evaluate(rhs, context).is_truthy())
+        }
+        Expr::Or(lhs, rhs) => {
+            Value::Bool(evaluate(lhs, context).is_truthy() || evaluate(rhs, context).is_truthy())
+        }
+        Expr::Eq(lhs, rhs) => Value::Bool(equals(&evaluate(lhs, context), &evaluate(rhs, context))),
+        Expr::Ne(lhs, rhs) => {
+            Value::Bool(!equals(&evaluate(lhs, context), &evaluate(rhs, context)))
+        }
+    }
+}
+
+fn equals(lhs: &Value, rhs: &Value) -> bool {
+    match (lhs, rhs) {
+        (Value::Null, Value::Null) => true,
+        (Value::Null, _) | (_, Value::Null) => false,
+        _ => lhs.as_string() == rhs.as_string(),
+    }
+}
+
+fn resolve(path: &[String], context: &ConditionContext) -> Value {
+    let path: Vec<_> = path.iter().map(String::as_str).collect();
+
+    match path.as_slice() {
+        ["host"] => Value::String(context.host.clone()),
+        ["alias"] => context
+            .alias
+            .clone()
+            .map(Value::String)
+            .unwrap_or(Value::Null),
+        ["git", "branch"] => context
+            .branch
+            .clone()
+            .map(Value::String)
+            .unwrap_or(Value::Null),
+        ["git", "commit"] => context
+            .commit
+            .clone()
+            .map(Value::String)
+            .unwrap_or(Value::Null),
+        ["env", name] => std::env::var(name)
+            .map(Value::String)
+            .unwrap_or(Value::Null),
+        ["matrix", name] => context
+            .matrix
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| Value::String(value.clone()))
+            .unwrap_or(Value::Null),
+        ["vars", name] => context
+            .vars
+            .get(*name)
+            .map(|value| Value::String(value.clone()))
+            .unwrap_or(Value::Null),
+        ["steps", name, "result"] => context
+            .step(name)
+            .map(|status| Value::String(status.to_string()))
+            .unwrap_or(Value::Null),
+        ["steps", name, "exit_code"] => match context.step(name) {
+            Some(TaskRunStatus::Success) => Value::Number(0.0),
+            Some(TaskRunStatus::Failure(code)) => Value::Number(code.get() as f64),
+            _ => Value::Null,
+        },
+        _ => Value::Null,
+    }
+}
+
+fn call(function: Function, args: &[Expr], context: &ConditionContext) -> Value {
+    let failed = || {
+        context.steps.iter().any(|(_, status)| {
+            matches!(
+                status,
+                TaskRunStatus::Failure(_) | TaskRunStatus::Interrupted
+            )
+        })
+    };
+    let string_arg = |index: usize| evaluate(&args[index], context).as_string();
+
+    Value::Bool(match function {
+        Function::Success => !failed(),
+        Function::Failure => failed(),
+        Function::Always => true,
+        Function::Changed => (0..args.len()).any(|index| {
+            let Ok(pattern) = Pattern::new(&string_arg(index)) else {
+                return false;
+            };
+            context
+                .changed_files
+                .iter()
+                .any(|path| pattern.matches_path(path))
+        }),
+        Function::Contains => string_arg(0).contains(&string_arg(1)),
+        Function::StartsWith => string_arg(0).starts_with(&string_arg(1)),
+        Function::EndsWith => string_arg(0).ends_with(&string_arg(1)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU8;
+
+    use super::*;
+
+    fn context() -> ConditionContext {
+        ConditionContext {
+            host: "box".to_string(),
+            alias: None,
+            branch: Some("main".to_string()),
+            commit: None,
+            changed_files: vec![PathBuf::from("src/main.rs"), PathBuf::from("Cargo.lock")],
+            matrix: vec![("os".to_string(), "linux".to_string())],
+            vars: BTreeMap::from([("profile".to_string(), "release".to_string())]),
+            steps: vec![
+                ("build".to_string(), TaskRunStatus::Success),
+                (
+                    "test".to_string(),
+                    TaskRunStatus::Failure(NonZeroU8::new(2).unwrap()),
+                ),
+            ],
+        }
+    }
+
+    fn holds(source: &str) -> bool {
+        Condition::try_from(source.to_string())
+            .unwrap()
+            .evaluate(&context())
+    }
+
+    #[test]
+    fn evaluates_against_the_context() {
+        assert!(holds("git.branch == 'main' && host == 'box'"));
+        assert!(holds("matrix.os == 'linux' && vars.profile != 'debug'"));
+        assert!(holds("alias == null && !git.commit"));
+        assert!(holds(
+            "steps.build.result == 'success' && steps.test.exit_code == 2"
+        ));
+        assert!(holds("failure() && !success() && always()"));
+        assert!(holds("changed('src/**/*.rs') && !changed('docs/*')"));
+        assert!(holds(
+            "starts_with(git.branch, 'ma') && ends_with(host, 'ox')"
+        ));
+        assert!(!holds("steps.missing.result == 'success'"));
+    }
+
+    #[test]
+    fn compares_the_values() {
+        assert!(holds("true || steps.test.result == 'success'"));
+        assert!(!holds("false && true"));
+        assert!(holds("0 == '0'"));
+        assert!(!holds("null == ''"));
+    }
+}
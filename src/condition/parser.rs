@@ -0,0 +1,511 @@
+use std::fmt::Display;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Literal(Value),
+    Context(Vec<String>),
+    Call(Function, Vec<Expr>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Eq(Box<Expr>, Box<Expr>),
+    Ne(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Function {
+    Success,
+    Failure,
+    Always,
+    Changed,
+    Contains,
+    StartsWith,
+    EndsWith,
+}
+
+impl Function {
+    fn lookup(name: &str) -> Option<Self> {
+        Some(match name {
+            "success" => Self::Success,
+            "failure" => Self::Failure,
+            "always" => Self::Always,
+            "changed" => Self::Changed,
+            "contains" => Self::Contains,
+            "starts_with" => Self::StartsWith,
+            "ends_with" => Self::EndsWith,
+            _ => return None,
+        })
+    }
+
+    fn accepts(&self, arg_count: usize) -> bool {
+        match self {
+            Self::Success | Self::Failure | Self::Always => arg_count == 0,
+            Self::Changed => arg_count >= 1,
+            Self::Contains | Self::StartsWith | Self::EndsWith => arg_count == 2,
+        }
+    }
+}
+
+pub const CONTEXT_ROOTS: &[&str] = &["host", "alias", "git", "env", "matrix", "vars", "steps"];
+
+// What can follow each of the roots, `*` being any name
+fn context_shapes(root: &str) -> &'static [&'static [&'static str]] {
+    match root {
+        "git" => &[&["branch"], &["commit"]],
+        "env" | "matrix" | "vars" => &[&["*"]],
+        "steps" => &[&["*", "result"], &["*", "exit_code"]],
+        _ => &[&[]],
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub message: String,
+    pub column: usize,
+    pub source: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (at column {} of `{}`)",
+            self.message, self.column, self.source
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    String(String),
+    Number(f64),
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Comma,
+    Dot,
+    Not,
+    And,
+    Or,
+    Eq,
+    Ne,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Ident(ident) => write!(f, "`{ident}`"),
+            Token::String(string) => write!(f, "'{string}'"),
+            Token::Number(number) => write!(f, "{number}"),
+            Token::LParen => write!(f, "`(`"),
+            Token::RParen => write!(f, "`)`"),
+            Token::LBracket => write!(f, "`[`"),
+            Token::RBracket => write!(f, "`]`"),
+            Token::Comma => write!(f, "`,`"),
+            Token::Dot => write!(f, "`.`"),
+            Token::Not => write!(f, "`!`"),
+            Token::And => write!(f, "`&&`"),
+            Token::Or => write!(f, "`||`"),
+            Token::Eq => write!(f, "`==`"),
+            Token::Ne => write!(f, "`!=`"),
+        }
+    }
+}
+
+pub fn parse(source: &str) -> Result<Expr, ParseError> {
+    let tokens = tokenize(source)?;
+    let mut parser = Parser {
+        source,
+        tokens,
+        cursor: 0,
+    };
+
+    let expr = parser.parse_or()?;
+    if let Some((token, column)) = parser.tokens.get(parser.cursor) {
+        return Err(parser.error(format!("Unexpected {token}"), *column));
+    }
+
+    Ok(expr)
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, ParseError> {
+    let error = |message: String, index: usize| ParseError {
+        message,
+        column: column(source, index),
+        source: source.to_string(),
+    };
+
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+
+    while let Some((index, char)) = chars.next() {
+        let token = match char {
+            c if c.is_whitespace() => continue,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '[' => Token::LBracket,
+            ']' => Token::RBracket,
+            ',' => Token::Comma,
+            '.' => Token::Dot,
+            '!' if chars.next_if(|(_, c)| *c == '=').is_some() => Token::Ne,
+            '!' => Token::Not,
+            '=' if chars.next_if(|(_, c)| *c == '=').is_some() => Token::Eq,
+            '&' if chars.next_if(|(_, c)| *c == '&').is_some() => Token::And,
+            '|' if chars.next_if(|(_, c)| *c == '|').is_some() => Token::Or,
+            '\'' | '"' => {
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, c)) if c == char => break,
+                        Some((_, c)) => string.push(c),
+                        None => return Err(error("Unterminated string".to_string(), index)),
+                    }
+                }
+                Token::String(string)
+            }
+            c if c.is_ascii_digit() => {
+                let mut number = c.to_string();
+                while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_digit() || *c == '.') {
+                    number.push(c);
+                }
+                let Ok(number) = number.parse() else {
+                    return Err(error(format!("Invalid number `{number}`"), index));
+                };
+                Token::Number(number)
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut ident = c.to_string();
+                while let Some((_, c)) =
+                    chars.next_if(|(_, c)| c.is_alphanumeric() || *c == '_' || *c == '-')
+                {
+                    ident.push(c);
+                }
+                Token::Ident(ident)
+            }
+            c => return Err(error(format!("Unexpected character `{c}`"), index)),
+        };
+
+        tokens.push((token, column(source, index)));
+    }
+
+    Ok(tokens)
+}
+
+// In characters rather than bytes, from 1
+fn column(source: &str, index: usize) -> usize {
+    source[..index].chars().count() + 1
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<(Token, usize)>,
+    cursor: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: String, column: usize) -> ParseError {
+        ParseError {
+            message,
+            column,
+            source: self.source.to_string(),
+        }
+    }
+
+    fn end_column(&self) -> usize {
+        column(self.source, self.source.len())
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.cursor).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Result<(Token, usize), ParseError> {
+        let Some(next) = self.tokens.get(self.cursor).cloned() else {
+            return Err(self.error(
+                "Unexpected end of expression".to_string(),
+                self.end_column(),
+            ));
+        };
+        self.cursor += 1;
+
+        Ok(next)
+    }
+
+    fn consume_if(&mut self, expected: &Token) -> bool {
+        if self.peek() == Some(expected) {
+            self.cursor += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), ParseError> {
+        let (token, column) = self.next()?;
+        if token != expected {
+            return Err(self.error(format!("Expected {expected}, found {token}"), column));
+        }
+
+        Ok(())
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, ParseError> {
+        let mut lhs = self.parse_and()?;
+        while self.consume_if(&Token::Or) {
+            lhs = Expr::Or(Box::new(lhs), Box::new(self.parse_and()?));
+        }
+
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, ParseError> {
+        let mut lhs = self.parse_unary()?;
+        while self.consume_if(&Token::And) {
+            lhs = Expr::And(Box::new(lhs), Box::new(self.parse_unary()?));
+        }
+
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        if self.consume_if(&Token::Not) {
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, ParseError> {
+        let lhs = self.parse_primary()?;
+
+        if self.consume_if(&Token::Eq) {
+            Ok(Expr::Eq(Box::new(lhs), Box::new(self.parse_primary()?)))
+        } else if self.consume_if(&Token::Ne) {
+            Ok(Expr::Ne(Box::new(lhs), Box::new(self.parse_primary()?)))
+        } else {
+            Ok(lhs)
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, ParseError> {
+        let (token, column) = self.next()?;
+
+        match token {
+            Token::LParen => {
+                let expr = self.parse_or()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Token::String(string) => Ok(Expr::Literal(Value::String(string))),
+            Token::Number(number) => Ok(Expr::Literal(Value::Number(number))),
+            Token::Ident(ident) => match ident.as_str() {
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "null" => Ok(Expr::Literal(Value::Null)),
+                _ if self.consume_if(&Token::LParen) => self.parse_call(ident, column),
+                _ => self.parse_context(ident, column),
+            },
+            token => Err(self.error(format!("Unexpected {token}"), column)),
+        }
+    }
+
+    fn parse_call(&mut self, name: String, column: usize) -> Result<Expr, ParseError> {
+        let Some(function) = Function::lookup(&name) else {
+            return Err(self.error(format!("Unknown function `{name}`"), column));
+        };
+
+        let mut args = Vec::new();
+        if !self.consume_if(&Token::RParen) {
+            loop {
+                args.push(self.parse_or()?);
+                if self.consume_if(&Token::Comma) {
+                    continue;
+                }
+                self.expect(Token::RParen)?;
+                break;
+            }
+        }
+
+        if !function.accepts(args.len()) {
+            return Err(self.error(
+                format!("`{name}` does not accept {} argument(s)", args.len()),
+                column,
+            ));
+        }
+
+        Ok(Expr::Call(function, args))
+    }
+
+    fn parse_context(&mut self, root: String, column: usize) -> Result<Expr, ParseError> {
+        if !CONTEXT_ROOTS.contains(&root.as_str()) {
+            return Err(self.error(
+                format!(
+                    "Unknown context `{root}` (available: {})",
+                    CONTEXT_ROOTS.join(", ")
+                ),
+                column,
+            ));
+        }
+
+        let mut path = vec![root];
+        loop {
+            if self.consume_if(&Token::Dot) {
+                match self.next()? {
+                    (Token::Ident(ident), _) => path.push(ident),
+                    (token, column) => {
+                        return Err(self.error(format!("Expected a name, found {token}"), column))
+                    }
+                }
+            } else if self.consume_if(&Token::LBracket) {
+                match self.next()? {
+                    (Token::String(string), _) => path.push(string),
+                    (token, column) => {
+                        return Err(self.error(format!("Expected a string, found {token}"), column))
+                    }
+                }
+                self.expect(Token::RBracket)?;
+            } else {
+                break;
+            }
+        }
+
+        let shapes = context_shapes(&path[0]);
+        let known = shapes.iter().any(|shape| {
+            shape.len() == path.len() - 1
+                && shape
+                    .iter()
+                    .zip(&path[1..])
+                    .all(|(expected, name)| *expected == "*" || expected == name)
+        });
+        if !known {
+            let expected: Vec<_> = shapes
+                .iter()
+                .map(|shape| {
+                    let names: Vec<_> = shape
+                        .iter()
+                        .map(|name| if *name == "*" { "<name>" } else { name })
+                        .collect();
+                    format!("`{}`", [&[path[0].as_str()][..], &names].concat().join("."))
+                })
+                .collect();
+            return Err(self.error(
+                format!(
+                    "Unknown `{}` (expected {})",
+                    path.join("."),
+                    expected.join(" or ")
+                ),
+                column,
+            ));
+        }
+
+        Ok(Expr::Context(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(path: &[&str]) -> Box<Expr> {
+        Box::new(Expr::Context(
+            path.iter().map(|name| name.to_string()).collect(),
+        ))
+    }
+
+    fn string(text: &str) -> Box<Expr> {
+        Box::new(Expr::Literal(Value::String(text.to_string())))
+    }
+
+    fn error(source: &str) -> (String, usize) {
+        let err = parse(source).unwrap_err();
+        (err.message, err.column)
+    }
+
+    #[test]
+    fn binds_and_tighter_than_or() {
+        assert_eq!(
+            parse("host == 'a' || !failure() && vars['x'] != 'y'").unwrap(),
+            Expr::Or(
+                Box::new(Expr::Eq(context(&["host"]), string("a"))),
+                Box::new(Expr::And(
+                    Box::new(Expr::Not(Box::new(Expr::Call(Function::Failure, vec![])))),
+                    Box::new(Expr::Ne(context(&["vars", "x"]), string("y"))),
+                )),
+            )
+        );
+        assert_eq!(
+            parse("(always() || success()) && git.branch == 'main'").unwrap(),
+            Expr::And(
+                Box::new(Expr::Or(
+                    Box::new(Expr::Call(Function::Always, vec![])),
+                    Box::new(Expr::Call(Function::Success, vec![])),
+                )),
+                Box::new(Expr::Eq(context(&["git", "branch"]), string("main"))),
+            )
+        );
+    }
+
+    #[test]
+    fn points_at_the_mistakes() {
+        assert_eq!(error("host == 'a"), ("Unterminated string".to_string(), 9));
+        assert_eq!(
+            error("host == "),
+            ("Unexpected end of expression".to_string(), 9)
+        );
+        assert_eq!(
+            error("(host"),
+            ("Unexpected end of expression".to_string(), 6)
+        );
+        assert_eq!(error("host host"), ("Unexpected `host`".to_string(), 6));
+        assert_eq!(
+            error("host = 'a'"),
+            ("Unexpected character `=`".to_string(), 6)
+        );
+        assert_eq!(
+            error("contains('a')"),
+            ("`contains` does not accept 1 argument(s)".to_string(), 1)
+        );
+        assert_eq!(error("fail()"), ("Unknown function `fail`".to_string(), 1));
+        assert_eq!(
+            error("'é' = 'e'"),
+            ("Unexpected character `=`".to_string(), 5)
+        );
+        assert_eq!(
+            error("'é' == "),
+            ("Unexpected end of expression".to_string(), 8)
+        );
+    }
+
+    #[test]
+    fn refuses_the_unknown_identifiers() {
+        assert_eq!(
+            error("branch == 'main'").0,
+            "Unknown context `branch` (available: host, alias, git, env, matrix, vars, steps)"
+        );
+        assert_eq!(
+            error("git.tag").0,
+            "Unknown `git.tag` (expected `git.branch` or `git.commit`)"
+        );
+        assert_eq!(
+            error("steps.build == 'success'").0,
+            "Unknown `steps.build` (expected `steps.<name>.result` or `steps.<name>.exit_code`)"
+        );
+        assert_eq!(
+            error("host.name").0,
+            "Unknown `host.name` (expected `host`)"
+        );
+        assert!(parse("steps['unit-test'].exit_code == 0 && env.CI").is_ok());
+    }
+}
@@ -0,0 +1,879 @@
+use std::{
+    collections::HashSet,
+    fmt::{Display, Write},
+    path::PathBuf,
+};
+
+use ignore::gitignore::GitignoreBuilder;
+use serde::de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde_json::Value as Schema;
+use serde_yaml::Value;
+
+use crate::{
+    condition::parser,
+    remote::task::{resolve_ancestors, TaskSetError},
+};
+
+use super::{
+    compose::{Source, KEYED_LISTS},
+    matrix::MatrixVariant,
+    schema::schema,
+    TaskDefinition,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub path: Vec<Segment>,
+    // The file the value is written in, if it is not the task definition itself
+    pub file: Option<PathBuf>,
+    // 1-based line and column in the file, if the value is written there
+    pub location: Option<(usize, usize)>,
+    pub message: String,
+    pub hint: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(path: &[Segment], message: impl Into<String>) -> Self {
+        Self {
+            path: path.to_vec(),
+            file: None,
+            location: None,
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    fn with_hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    pub fn path(&self) -> String {
+        display_path(&self.path)
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some((line, column)) = self.location {
+            write!(f, "{}:{}: ", line, column)?;
+        }
+        write!(f, "{}", self.message)?;
+        if !self.path.is_empty() {
+            write!(f, " (at `{}`)", self.path())?;
+        }
+        if let Some(hint) = &self.hint {
+            write!(f, "\n    hint: {}", hint)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn display_path(path: &[Segment]) -> String {
+    let mut result = String::new();
+    for segment in path {
+        match segment {
+            Segment::Key(key) if result.is_empty() => result.push_str(key),
+            Segment::Key(key) => write!(result, ".{}", key).unwrap(),
+            Segment::Index(index) => write!(result, "[{}]", index).unwrap(),
+        }
+    }
+
+    result
+}
+
+// Checks the shape of the definition against the JSON Schema, the step names and the `if:`
+// expressions
+pub fn check_document(document: &Value) -> Vec<Diagnostic> {
+    let schema = schema();
+    let mut diagnostics = Vec::new();
+    validate(
+        document,
+        &schema,
+        &schema,
+        &mut Vec::new(),
+        &mut diagnostics,
+    );
+
+    if let Some(Value::Sequence(runs)) = document.get("run") {
+        // `needs:`, `steps.<name>` and the logs tell the steps by their name
+        let mut names = HashSet::new();
+        for (index, run) in runs.iter().enumerate() {
+            let Some(Value::String(name)) = run.get("name") else {
+                continue;
+            };
+            if !names.insert(name) {
+                let path = [
+                    Segment::Key("run".to_string()),
+                    Segment::Index(index),
+                    Segment::Key("name".to_string()),
+                ];
+                diagnostics.push(Diagnostic::new(
+                    &path,
+                    format!("Another step is already named `{}`", name),
+                ));
+            }
+        }
+
+        for (index, run) in runs.iter().enumerate() {
+            let Some(Value::String(condition)) = run.get("if") else {
+                continue;
+            };
+            if let Err(err) = parser::parse(condition) {
+                let path = [
+                    Segment::Key("run".to_string()),
+                    Segment::Index(index),
+                    Segment::Key("if".to_string()),
+                ];
+                diagnostics.push(Diagnostic::new(&path, err.to_string()));
+            }
+        }
+    }
+
+    diagnostics
+}
+
+// Checks what the schema cannot tell, once the definition is read
+pub fn check_task(task: &TaskDefinition) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let key = |key: &str| Segment::Key(key.to_string());
+
+    if !task.code.location.is_dir() {
+        diagnostics.push(
+            Diagnostic::new(
+                &[key("code"), key("location")],
+                format!("{} is not a directory", task.code.location.display()),
+            )
+            .with_hint("`code.location` is relative to the current directory"),
+        );
+    }
+
+    let mut gitignore = GitignoreBuilder::new(&task.code.location);
+    for (line, pattern) in task.code.ignore.lines().enumerate() {
+        if let Err(err) = gitignore.add_line(None, pattern) {
+            diagnostics.push(Diagnostic::new(
+                &[key("code"), key("ignore")],
+                format!("Line {} of the patterns is invalid: {}", line + 1, err),
+            ));
+        }
+    }
+    for (field, patterns) in [
+        ("include", &task.code.include),
+        ("exclude", &task.code.exclude),
+    ] {
+        for (index, pattern) in patterns.iter().enumerate() {
+            if let Err(err) = gitignore.add_line(None, pattern) {
+                diagnostics.push(Diagnostic::new(
+                    &[key("code"), key(field), Segment::Index(index)],
+                    format!("The pattern is invalid: {}", err),
+                ));
+            }
+        }
+    }
+
+    for (index, artifact) in task.artifact.iter().enumerate() {
+        for (field, path) in [
+            ("remote_path", &artifact.remote_path),
+            ("local_path", &artifact.local_path),
+        ] {
+            if path.is_absolute() {
+                diagnostics.push(
+                    Diagnostic::new(
+                        &[key("artifact"), Segment::Index(index), key(field)],
+                        format!("{} should be a relative path", path.display()),
+                    )
+                    .with_hint(match field {
+                        "remote_path" => "`remote_path` is relative to the code destination",
+                        _ => "`local_path` is relative to the current directory",
+                    }),
+                );
+            }
+        }
+    }
+
+    let variants = match &task.matrix {
+        Some(matrix) => matrix.expand(),
+        None => vec![MatrixVariant::default()],
+    };
+    for variant in variants {
+        if let Err(diagnostic) = variant.apply(task) {
+            diagnostics.push(diagnostic);
+        }
+    }
+
+    if let Err(err) = resolve_ancestors(&task.run) {
+        let (TaskSetError::Failed(run, _)
+        | TaskSetError::Interrupted(run)
+        | TaskSetError::UnknownDependency(run, _)
+        | TaskSetError::CyclicDependency(run, _)) = &err;
+        let index = task
+            .run
+            .iter()
+            .position(|candidate| std::ptr::eq(candidate, *run))
+            .unwrap_or_default();
+        diagnostics.push(Diagnostic::new(
+            &[key("run"), Segment::Index(index), key("needs")],
+            err.to_string(),
+        ));
+    }
+
+    diagnostics
+}
+
+fn validate(
+    value: &Value,
+    schema: &Schema,
+    root: &Schema,
+    path: &mut Vec<Segment>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let schema = resolve(schema, root);
+
+    if let Some(types) = schema.get("type") {
+        let types: Vec<_> = match types {
+            Schema::Array(types) => types.iter().filter_map(Schema::as_str).collect(),
+            types => types.as_str().into_iter().collect(),
+        };
+        if !types.iter().any(|kind| has_type(value, kind)) {
+            diagnostics.push(Diagnostic::new(
+                path,
+                format!(
+                    "Expected {}, but found {}",
+                    join_or(types.iter().map(|kind| article(kind).to_string()).collect()),
+                    article(type_of(value))
+                ),
+            ));
+            return;
+        }
+    }
+
+    if let Some(Schema::Array(candidates)) = schema.get("enum") {
+        if !candidates.iter().any(|candidate| equals(value, candidate)) {
+            let names: Vec<_> = candidates
+                .iter()
+                .map(|candidate| format!("`{}`", candidate.as_str().unwrap_or_default()))
+                .collect();
+            let mut diagnostic =
+                Diagnostic::new(path, format!("Expected one of {}", join_or(names)));
+            if let Some(suggestion) = value
+                .as_str()
+                .and_then(|text| suggest(text, candidates.iter().filter_map(Schema::as_str)))
+            {
+                diagnostic = diagnostic.with_hint(format!("did you mean `{}`?", suggestion));
+            }
+            diagnostics.push(diagnostic);
+            return;
+        }
+    }
+
+    if let Some(number) = value.as_f64() {
+        if let Some(minimum) = schema.get("minimum").and_then(Schema::as_f64) {
+            if number < minimum {
+                diagnostics.push(Diagnostic::new(
+                    path,
+                    format!("Should be at least {}", minimum),
+                ));
+            }
+        }
+        if let Some(maximum) = schema.get("maximum").and_then(Schema::as_f64) {
+            if number > maximum {
+                diagnostics.push(Diagnostic::new(
+                    path,
+                    format!("Should be at most {}", maximum),
+                ));
+            }
+        }
+    }
+
+    if let Value::Mapping(mapping) = value {
+        validate_mapping(mapping, schema, root, path, diagnostics);
+    }
+
+    if let Value::Sequence(items) = value {
+        if let Some(minimum) = schema.get("minItems").and_then(Schema::as_u64) {
+            if (items.len() as u64) < minimum {
+                diagnostics.push(Diagnostic::new(
+                    path,
+                    format!("Should have at least {} item(s)", minimum),
+                ));
+            }
+        }
+        if let Some(item_schema) = schema.get("items") {
+            for (index, item) in items.iter().enumerate() {
+                path.push(Segment::Index(index));
+                validate(item, item_schema, root, path, diagnostics);
+                path.pop();
+            }
+        }
+    }
+
+    if let Some(Schema::Array(candidates)) = schema.get("anyOf") {
+        validate_any_of(value, candidates, root, path, diagnostics);
+    }
+}
+
+fn validate_mapping(
+    mapping: &serde_yaml::Mapping,
+    schema: &Schema,
+    root: &Schema,
+    path: &mut Vec<Segment>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let properties = schema.get("properties").and_then(Schema::as_object);
+
+    if let Some(Schema::Array(required)) = schema.get("required") {
+        for field in required.iter().filter_map(Schema::as_str) {
+            if mapping.get(field).is_none() {
+                diagnostics.push(Diagnostic::new(
+                    path,
+                    format!("Missing the field `{}`", field),
+                ));
+            }
+        }
+    }
+
+    for (key, value) in mapping {
+        let Some(name) = key.as_str() else {
+            diagnostics.push(Diagnostic::new(path, "Keys should be strings"));
+            continue;
+        };
+        path.push(Segment::Key(name.to_string()));
+
+        match (
+            properties.and_then(|properties| properties.get(name)),
+            schema.get("additionalProperties"),
+        ) {
+            (Some(property), _) => validate(value, property, root, path, diagnostics),
+            (None, Some(Schema::Bool(false))) => {
+                let mut diagnostic = Diagnostic::new(path, format!("Unknown key `{}`", name));
+                let known = properties
+                    .into_iter()
+                    .flat_map(|properties| properties.keys().map(String::as_str));
+                if let Some(suggestion) = suggest(name, known) {
+                    diagnostic = diagnostic.with_hint(format!("did you mean `{}`?", suggestion));
+                }
+                diagnostics.push(diagnostic);
+            }
+            (None, Some(additional @ Schema::Object(_))) => {
+                validate(value, additional, root, path, diagnostics)
+            }
+            (None, _) => {}
+        }
+
+        path.pop();
+    }
+}
+
+// Reports the problems of the only alternative of the right type, which is most likely the
+// intended one, or that none of them is matched
+fn validate_any_of(
+    value: &Value,
+    candidates: &[Schema],
+    root: &Schema,
+    path: &mut Vec<Segment>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let mut results = Vec::new();
+    for candidate in candidates {
+        let mut found = Vec::new();
+        validate(value, candidate, root, path, &mut found);
+        if found.is_empty() {
+            return;
+        }
+        results.push((resolve(candidate, root), found));
+    }
+
+    let typed: Vec<_> = results
+        .iter()
+        .filter(|(candidate, _)| match candidate.get("type") {
+            Some(Schema::String(kind)) => has_type(value, kind),
+            Some(Schema::Array(kinds)) => kinds
+                .iter()
+                .filter_map(Schema::as_str)
+                .any(|kind| has_type(value, kind)),
+            _ => false,
+        })
+        .collect();
+
+    if let [(_, found)] = typed.as_slice() {
+        diagnostics.extend(found.iter().cloned());
+        return;
+    }
+
+    let expected: Vec<_> = results
+        .iter()
+        .map(|(candidate, found)| describe(candidate, found))
+        .collect();
+    diagnostics.push(Diagnostic::new(
+        path,
+        format!("Expected {}", join_or(expected)),
+    ));
+}
+
+fn describe(schema: &Schema, found: &[Diagnostic]) -> String {
+    match (schema.get("type"), schema.get("required")) {
+        (Some(Schema::String(kind)), _) => article(kind).to_string(),
+        (None, Some(Schema::Array(required))) => {
+            let fields: Vec<_> = required
+                .iter()
+                .filter_map(Schema::as_str)
+                .map(|field| format!("`{}`", field))
+                .collect();
+            format!("the field {}", fields.join(", "))
+        }
+        _ => found
+            .first()
+            .map(|diagnostic| diagnostic.message.clone())
+            .unwrap_or_default(),
+    }
+}
+
+fn resolve<'s>(schema: &'s Schema, root: &'s Schema) -> &'s Schema {
+    match schema.get("$ref").and_then(Schema::as_str) {
+        Some(reference) => reference
+            .strip_prefix("#/")
+            .and_then(|pointer| root.pointer(&format!("/{}", pointer)))
+            .unwrap_or(schema),
+        None => schema,
+    }
+}
+
+fn has_type(value: &Value, kind: &str) -> bool {
+    match kind {
+        "integer" => value.is_i64() || value.is_u64(),
+        kind => type_of(value) == kind || (kind == "number" && type_of(value) == "integer"),
+    }
+}
+
+fn type_of(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(number) if number.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Sequence(_) => "array",
+        Value::Mapping(_) => "object",
+        Value::Tagged(tagged) => type_of(&tagged.value),
+    }
+}
+
+fn article(kind: &str) -> &'static str {
+    match kind {
+        "null" => "nothing",
+        "boolean" => "a boolean",
+        "number" => "a number",
+        "integer" => "an integer",
+        "string" => "a string",
+        "array" => "a list",
+        "object" => "a mapping",
+        _ => "a value",
+    }
+}
+
+fn equals(value: &Value, candidate: &Schema) -> bool {
+    match (value, candidate) {
+        (Value::String(value), Schema::String(candidate)) => value == candidate,
+        (Value::Bool(value), Schema::Bool(candidate)) => value == candidate,
+        _ => false,
+    }
+}
+
+fn join_or(mut items: Vec<String>) -> String {
+    match items.len() {
+        0 => String::new(),
+        1 => items.remove(0),
+        _ => {
+            let last = items.pop().unwrap();
+            format!("{} or {}", items.join(", "), last)
+        }
+    }
+}
+
+fn suggest<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    candidates
+        .map(|candidate| (strsim::levenshtein(name, candidate), candidate))
+        .filter(|(distance, candidate)| *distance <= (candidate.len() / 3).max(1))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+const LOCATED: &str = "difm: located";
+
+// Finds the file and the place the value at the path is written in. The last of the sources that
+// has the value wins, as it does when they are merged.
+pub fn locate(sources: &[Source], composed: &Value, diagnostic: &mut Diagnostic) {
+    let at_key = diagnostic.message.starts_with("Unknown key");
+
+    for source in sources.iter().rev() {
+        let Some(path) = source_path(&source.document, composed, &diagnostic.path) else {
+            continue;
+        };
+        let path = [source.prefix.as_slice(), &path].concat();
+        if let Some(location) = locate_in(&source.text, &path, at_key) {
+            diagnostic.file = Some(source.path.clone());
+            diagnostic.location = Some(location);
+            return;
+        }
+    }
+}
+
+// The path of the value in the document of one of the sources. The items of the keyed lists are
+// found by their key, as they are merged.
+fn source_path(document: &Value, composed: &Value, path: &[Segment]) -> Option<Vec<Segment>> {
+    let mut source = document;
+    let mut composed = Some(composed);
+    let mut result = Vec::new();
+
+    for (depth, segment) in path.iter().enumerate() {
+        let segment = match segment {
+            Segment::Key(key) => {
+                source = source.get(key)?;
+                composed = composed.and_then(|composed| composed.get(key));
+                Segment::Key(key.clone())
+            }
+            Segment::Index(index) => {
+                let keyed = match path.first() {
+                    Some(Segment::Key(list)) if depth == 1 => KEYED_LISTS
+                        .iter()
+                        .find(|(keyed, _)| keyed == list)
+                        .map(|(_, item_key)| *item_key),
+                    _ => None,
+                };
+                let position = match keyed {
+                    Some(item_key) => {
+                        let items = composed?.as_sequence()?;
+                        let key = items.get(*index)?.get(item_key)?;
+                        // A key used again, e.g. by a duplicated step, is its next item
+                        let occurrence = items[..*index]
+                            .iter()
+                            .filter(|item| item.get(item_key) == Some(key))
+                            .count();
+                        let positions: Vec<_> = source
+                            .as_sequence()?
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, item)| item.get(item_key) == Some(key))
+                            .map(|(position, _)| position)
+                            .collect();
+                        *positions.get(occurrence).or(positions.first())?
+                    }
+                    None => *index,
+                };
+                source = source.get(position)?;
+                composed = composed.and_then(|composed| composed.get(index));
+                Segment::Index(position)
+            }
+        };
+        result.push(segment);
+    }
+
+    Some(result)
+}
+
+// Reads the text again until the path and fails there, so that serde_yaml tells the location.
+// Unknown keys point at the key itself.
+fn locate_in(text: &str, path: &[Segment], at_key: bool) -> Option<(usize, usize)> {
+    let seed = Locate { path, at_key };
+
+    let err = seed
+        .deserialize(serde_yaml::Deserializer::from_str(text))
+        .err()?;
+    if !err.to_string().contains(LOCATED) {
+        return None;
+    }
+    let location = err.location()?;
+
+    Some((location.line(), location.column()))
+}
+
+struct Locate<'p> {
+    path: &'p [Segment],
+    at_key: bool,
+}
+
+impl<'de> DeserializeSeed<'de> for Locate<'_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Locate<'_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("anything")
+    }
+
+    fn visit_bool<E: de::Error>(self, _: bool) -> Result<(), E> {
+        self.scalar()
+    }
+
+    fn visit_i64<E: de::Error>(self, _: i64) -> Result<(), E> {
+        self.scalar()
+    }
+
+    fn visit_u64<E: de::Error>(self, _: u64) -> Result<(), E> {
+        self.scalar()
+    }
+
+    fn visit_f64<E: de::Error>(self, _: f64) -> Result<(), E> {
+        self.scalar()
+    }
+
+    fn visit_str<E: de::Error>(self, _: &str) -> Result<(), E> {
+        self.scalar()
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<(), E> {
+        self.scalar()
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let Some((Segment::Key(target), rest)) = self.path.split_first() else {
+            return self.scalar();
+        };
+
+        while let Some(matched) = map.next_key_seed(LocateKey {
+            target,
+            fail: self.at_key && rest.is_empty(),
+        })? {
+            if matched {
+                return map.next_value_seed(Locate {
+                    path: rest,
+                    at_key: self.at_key,
+                });
+            }
+            map.next_value::<IgnoredAny>()?;
+        }
+
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let Some((Segment::Index(target), rest)) = self.path.split_first() else {
+            return self.scalar();
+        };
+
+        for _ in 0..*target {
+            if seq.next_element::<IgnoredAny>()?.is_none() {
+                return Ok(());
+            }
+        }
+        seq.next_element_seed(Locate {
+            path: rest,
+            at_key: self.at_key,
+        })?;
+
+        Ok(())
+    }
+}
+
+impl Locate<'_> {
+    fn scalar<E: de::Error>(&self) -> Result<(), E> {
+        if self.path.is_empty() {
+            Err(E::custom(LOCATED))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+struct LocateKey<'t> {
+    target: &'t str,
+    fail: bool,
+}
+
+impl<'de> DeserializeSeed<'de> for LocateKey<'_> {
+    type Value = bool;
+
+    // Failing in the visitor of the key, rather than once it is read, so that the location is the
+    // one of the key and not of its mapping
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<bool, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for LocateKey<'_> {
+    type Value = bool;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a key")
+    }
+
+    fn visit_str<E: de::Error>(self, key: &str) -> Result<bool, E> {
+        let matched = key == self.target;
+        if matched && self.fail {
+            return Err(E::custom(LOCATED));
+        }
+
+        Ok(matched)
+    }
+
+    fn visit_bool<E: de::Error>(self, _: bool) -> Result<bool, E> {
+        Ok(false)
+    }
+
+    fn visit_i64<E: de::Error>(self, _: i64) -> Result<bool, E> {
+        Ok(false)
+    }
+
+    fn visit_u64<E: de::Error>(self, _: u64) -> Result<bool, E> {
+        Ok(false)
+    }
+
+    fn visit_f64<E: de::Error>(self, _: f64) -> Result<bool, E> {
+        Ok(false)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<bool, E> {
+        Ok(false)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<bool, A::Error> {
+        while seq.next_element::<IgnoredAny>()?.is_some() {}
+        Ok(false)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<bool, A::Error> {
+        while map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() {}
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{config::diagnose, util::TempTree};
+
+    const HOST: &str = "type: task\nhost:\n  name: box\n  base_dir: src\n";
+    const CODE: &str = "code:\n  location: .\n  dest: code\n  use: ssh\nartifact: []\n";
+
+    fn diagnose_files(files: &[(&str, &str)], profiles: &[&str]) -> Vec<String> {
+        let dir = TempTree::new(files);
+        let profiles: Vec<_> = profiles.iter().map(|name| name.to_string()).collect();
+
+        diagnose(&dir.join("difm.yaml"), &profiles, &[])
+            .unwrap()
+            .iter()
+            .map(|diagnostic| {
+                let file = diagnostic.file.as_deref().and_then(Path::file_name);
+                format!(
+                    "{}{:?} {}",
+                    file.map(|file| format!("{} ", file.to_string_lossy()))
+                        .unwrap_or_default(),
+                    diagnostic.location,
+                    diagnostic.message
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn accepts_a_boolean_condition() {
+        let diagnostics = diagnose_files(
+            &[(
+                "difm.yaml",
+                &format!(
+                    "{}{}run:\n  - name: test\n    run: make\n    if: true\n",
+                    HOST, CODE
+                ),
+            )],
+            &[],
+        );
+
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    }
+
+    #[test]
+    fn refuses_the_steps_of_the_same_name() {
+        let diagnostics = diagnose_files(
+            &[(
+                "difm.yaml",
+                &format!(
+                    "{}{}run:\n  - name: a\n    run: make\n  - name: b\n    run: make b\n  - name: a\n    run: make test\n",
+                    HOST, CODE
+                ),
+            )],
+            &[],
+        );
+
+        assert_eq!(
+            diagnostics,
+            ["Some((15, 11)) Another step is already named `a`"]
+        );
+    }
+
+    #[test]
+    fn locates_the_values_in_the_included_files() {
+        let diagnostics = diagnose_files(
+            &[
+                (
+                    "difm.yaml",
+                    &format!(
+                        "include: [hosts.yml]\n{}run:\n  - name: test\n    run: make\n",
+                        CODE
+                    ),
+                ),
+                ("hosts.yml", &format!("{}  prot: 22\n", HOST)),
+            ],
+            &[],
+        );
+
+        assert_eq!(diagnostics, ["hosts.yml Some((5, 3)) Unknown key `prot`"]);
+    }
+
+    #[test]
+    fn locates_the_steps_by_their_name() {
+        let diagnostics = diagnose_files(
+            &[
+                (
+                    "base.yml",
+                    &format!("{}{}run:\n  - name: build\n    run: make\n  - name: test\n    run: make test\n", HOST, CODE),
+                ),
+                (
+                    "difm.yaml",
+                    "extends: base.yml\nrun:\n  - name: test\n    tyy: true\nprofiles:\n  ci:\n    run:\n      - name: build\n        cdw: ci\n",
+                ),
+            ],
+            &["ci"],
+        );
+
+        assert_eq!(
+            diagnostics,
+            [
+                "Some((9, 9)) Unknown key `cdw`",
+                "Some((4, 5)) Unknown key `tyy`"
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_the_shape_along_with_the_rest() {
+        let diagnostics = diagnose_files(
+            &[(
+                "difm.yaml",
+                &format!(
+                    "{}code:\n  location: .\n  dest: code\n  use: ssh\n  gitignroe: false\nrun:\n  - name: test\n    run: make\n    needs: [missing]\nartifact:\n  - remote_path: /tmp/out\n    local_path: out\n",
+                    HOST
+                ),
+            )],
+            &[],
+        );
+
+        assert_eq!(diagnostics.len(), 3, "{:?}", diagnostics);
+        assert!(diagnostics[0].ends_with("Unknown key `gitignroe`"));
+        assert!(diagnostics[1].contains("/tmp/out should be a relative path"));
+        assert!(diagnostics[2].contains("missing"));
+    }
+}
@@ -0,0 +1,460 @@
+use std::{
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+};
+
+use serde_yaml::{Mapping, Value};
+
+use super::{check::Segment, interpolate::resolve_config_dir};
+
+// The lists merged item by item, matched by the key. The other lists are replaced as a whole.
+pub const KEYED_LISTS: &[(&str, &str)] = &[("run", "name"), ("artifact", "local_path")];
+
+// A document merged into the task definition, in the order they are merged, so that the
+// diagnostics can tell which file a value comes from
+pub struct Source {
+    pub path: PathBuf,
+    pub text: String,
+    // Where the document is in the file, e.g. `profiles.ci` for a profile
+    pub prefix: Vec<Segment>,
+    pub document: Value,
+}
+
+#[derive(Debug)]
+pub enum ComposeError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, serde_yaml::Error),
+    Invalid(PathBuf, String),
+    Cycle(Vec<PathBuf>),
+    UnknownProfile(String, Vec<String>),
+}
+
+impl Display for ComposeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ComposeError::Read(path, err) => {
+                write!(f, "Could not read {}: {}", path.display(), err)
+            }
+            ComposeError::Parse(path, err) => write!(f, "{}: {}", path.display(), err),
+            ComposeError::Invalid(path, message) => write!(f, "{}: {}", path.display(), message),
+            ComposeError::Cycle(chain) => {
+                let chain: Vec<_> = chain
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect();
+                write!(f, "The files include each other: {}", chain.join(" -> "))
+            }
+            ComposeError::UnknownProfile(name, available) => {
+                write!(f, "Unknown profile `{}`", name)?;
+                if available.is_empty() {
+                    write!(f, " (the task has no profiles)")
+                } else {
+                    write!(f, " (available: {})", available.join(", "))
+                }
+            }
+        }
+    }
+}
+
+// Whether the file is not the whole task definition by itself
+pub fn is_composed(document: &Value) -> bool {
+    ["include", "extends", "profiles"]
+        .iter()
+        .any(|key| document.get(key).is_some())
+}
+
+// Resolves `extends:` and `include:` of the file, then applies the profiles on top of it in order.
+// `extends:` is the base of the file, then the included files and the file itself are merged into it.
+pub fn compose(
+    document: Value,
+    text: &str,
+    path: &Path,
+    profiles: &[String],
+) -> Result<(Value, Vec<Source>), ComposeError> {
+    let mut stack = vec![canonical(path)];
+    let mut sources = Vec::new();
+    let mut composed = resolve(document, text, path, &mut stack, &mut sources)?;
+
+    let declared = match composed.as_mapping_mut() {
+        Some(root) => root.remove("profiles"),
+        None => None,
+    };
+    let declared = match declared {
+        Some(Value::Mapping(declared)) => declared,
+        Some(Value::Null) | None => Mapping::new(),
+        Some(_) => {
+            return Err(ComposeError::Invalid(
+                path.to_path_buf(),
+                "`profiles` should be a mapping from the names to the overrides".to_string(),
+            ))
+        }
+    };
+
+    for name in profiles {
+        let Some(profile) = declared.get(name.as_str()) else {
+            let available = declared
+                .keys()
+                .filter_map(|key| key.as_str().map(str::to_string))
+                .collect();
+            return Err(ComposeError::UnknownProfile(name.clone(), available));
+        };
+        // Declared in the last file that has it, as the profiles are merged like the rest
+        let declared_in = sources
+            .iter()
+            .rev()
+            .find(|source| {
+                source
+                    .document
+                    .get("profiles")
+                    .and_then(|profiles| profiles.get(name.as_str()))
+                    .is_some()
+            })
+            .map(|source| (source.path.clone(), source.text.clone()));
+        if let Some((path, text)) = declared_in {
+            sources.push(Source {
+                path,
+                text,
+                prefix: vec![
+                    Segment::Key("profiles".to_string()),
+                    Segment::Key(name.clone()),
+                ],
+                document: profile.clone(),
+            });
+        }
+        composed = merge_task(composed, profile.clone());
+    }
+
+    Ok((composed, sources))
+}
+
+fn resolve(
+    mut document: Value,
+    text: &str,
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    sources: &mut Vec<Source>,
+) -> Result<Value, ComposeError> {
+    let source = Source {
+        path: path.to_path_buf(),
+        text: text.to_string(),
+        prefix: Vec::new(),
+        document: document.clone(),
+    };
+    let Some(root) = document.as_mapping_mut() else {
+        sources.push(source);
+        return Ok(document);
+    };
+
+    let extends = match root.remove("extends") {
+        None | Some(Value::Null) => None,
+        Some(Value::String(base)) => Some(base),
+        Some(_) => {
+            return Err(ComposeError::Invalid(
+                path.to_path_buf(),
+                "`extends` should be the path to the task definition".to_string(),
+            ))
+        }
+    };
+    let includes = match root.remove("include") {
+        None | Some(Value::Null) => Vec::new(),
+        Some(Value::String(include)) => vec![include],
+        Some(Value::Sequence(includes)) => includes
+            .into_iter()
+            .map(|include| match include {
+                Value::String(include) => Ok(include),
+                _ => Err(ComposeError::Invalid(
+                    path.to_path_buf(),
+                    "`include` should be the paths to the files".to_string(),
+                )),
+            })
+            .collect::<Result<_, _>>()?,
+        Some(_) => {
+            return Err(ComposeError::Invalid(
+                path.to_path_buf(),
+                "`include` should be the paths to the files".to_string(),
+            ))
+        }
+    };
+
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+    let mut composed = Value::Mapping(Mapping::new());
+    for relative in extends.iter().chain(includes.iter()) {
+        let included = load(&base_dir.join(relative), stack, sources)?;
+        composed = merge_task(composed, included);
+    }
+    sources.push(source);
+
+    Ok(merge_task(composed, document))
+}
+
+fn load(
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    sources: &mut Vec<Source>,
+) -> Result<Value, ComposeError> {
+    let canonical = canonical(path);
+    if stack.contains(&canonical) {
+        let mut chain = stack.clone();
+        chain.push(canonical);
+        return Err(ComposeError::Cycle(chain));
+    }
+
+    let text =
+        std::fs::read_to_string(path).map_err(|err| ComposeError::Read(path.to_path_buf(), err))?;
+    let mut document: Value =
+        serde_yaml::from_str(&text).map_err(|err| ComposeError::Parse(path.to_path_buf(), err))?;
+    if !matches!(document, Value::Mapping(_) | Value::Null) {
+        return Err(ComposeError::Invalid(
+            path.to_path_buf(),
+            "The file should be a mapping".to_string(),
+        ));
+    }
+    relative_to_file(&mut document, path);
+
+    stack.push(canonical);
+    let resolved = resolve(document, &text, path, stack, sources);
+    stack.pop();
+
+    resolved
+}
+
+// The values of an included file that depend on where it is: `${config_dir}` is its directory,
+// and `code.location` is relative to it rather than to the current directory
+fn relative_to_file(document: &mut Value, path: &Path) {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    resolve_config_dir(document, &canonical(dir));
+
+    let location = document
+        .get_mut("code")
+        .and_then(|code| code.get_mut("location"));
+    if let Some(Value::String(location)) = location {
+        if Path::new(location).is_relative() {
+            *location = dir.join(&*location).to_string_lossy().into_owned();
+        }
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or(path.to_path_buf())
+}
+
+fn merge_task(base: Value, overlay: Value) -> Value {
+    let (mut base, overlay) = match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => (base, overlay),
+        (base, Value::Null) => return base,
+        (_, overlay) => return overlay,
+    };
+
+    for (key, value) in overlay {
+        let keyed = KEYED_LISTS
+            .iter()
+            .find(|(list, _)| key.as_str() == Some(*list))
+            .map(|(_, item_key)| *item_key);
+
+        match (base.get_mut(&key), keyed) {
+            (Some(Value::Sequence(existing)), Some(item_key)) => match value {
+                Value::Sequence(overlay) => {
+                    let merged = merge_list(std::mem::take(existing), overlay, item_key);
+                    *existing = merged;
+                }
+                value => base[&key] = value,
+            },
+            (Some(existing), _) => *existing = merge(std::mem::take(existing), value),
+            (None, _) => {
+                base.insert(key, value);
+            }
+        }
+    }
+
+    Value::Mapping(base)
+}
+
+// The items with the same key are merged in place, and the others are appended
+fn merge_list(mut base: Vec<Value>, overlay: Vec<Value>, item_key: &str) -> Vec<Value> {
+    for item in overlay {
+        let position = item.get(item_key).and_then(|key| {
+            base.iter()
+                .position(|existing| existing.get(item_key) == Some(key))
+        });
+
+        match position {
+            Some(position) => base[position] = merge(std::mem::take(&mut base[position]), item),
+            None => base.push(item),
+        }
+    }
+
+    base
+}
+
+fn merge(base: Value, overlay: Value) -> Value {
+    match (base, overlay) {
+        (Value::Mapping(mut base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => *existing = merge(std::mem::take(existing), value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+            Value::Mapping(base)
+        }
+        (_, overlay) => overlay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::util::TempTree;
+
+    use super::*;
+
+    fn yaml(text: &str) -> Value {
+        serde_yaml::from_str(text).unwrap()
+    }
+
+    // Composes `difm.yaml` among the files, written to a directory of their own
+    fn compose_files(
+        files: &[(&str, &str)],
+        profiles: &[&str],
+    ) -> (TempTree, Result<Value, ComposeError>) {
+        let dir = TempTree::new(files);
+
+        let path = dir.join("difm.yaml");
+        let text = fs::read_to_string(&path).unwrap();
+        let profiles: Vec<_> = profiles.iter().map(|name| name.to_string()).collect();
+        let composed = compose(yaml(&text), &text, &path, &profiles).map(|(composed, _)| composed);
+
+        (dir, composed)
+    }
+
+    #[test]
+    fn merges_the_mappings_deeply_and_replaces_the_rest() {
+        let merged = merge_task(
+            yaml("host: {name: a, base_dir: src}\nforward: [{listen: 1}]\nvars: {x: '1'}"),
+            yaml("host: {name: b}\nforward: [{listen: 2}]\nvars: ~\nhistory: {keep_runs: 3}"),
+        );
+
+        assert_eq!(
+            merged,
+            yaml("host: {name: b, base_dir: src}\nforward: [{listen: 2}]\nvars: ~\nhistory: {keep_runs: 3}")
+        );
+        assert_eq!(merge_task(yaml("a: 1"), Value::Null), yaml("a: 1"));
+    }
+
+    #[test]
+    fn merges_the_steps_by_name() {
+        let merged = merge_list(
+            yaml("[{name: build, run: make}, {name: test, run: make test}]")
+                .as_sequence()
+                .unwrap()
+                .clone(),
+            yaml("[{name: test, if: failure()}, {name: lint, run: make lint}, {run: echo}]")
+                .as_sequence()
+                .unwrap()
+                .clone(),
+            "name",
+        );
+
+        assert_eq!(
+            Value::Sequence(merged),
+            yaml("[{name: build, run: make}, {name: test, run: make test, if: failure()}, {name: lint, run: make lint}, {run: echo}]")
+        );
+        assert_eq!(
+            merge_task(
+                yaml("artifact: [{local_path: a, remote_path: x}]"),
+                yaml("artifact: [{local_path: a, remote_path: y}]")
+            ),
+            yaml("artifact: [{local_path: a, remote_path: y}]")
+        );
+    }
+
+    #[test]
+    fn applies_the_includes_then_the_file_then_the_profiles() {
+        let (_, composed) = compose_files(
+            &[
+                ("base.yml", "as: base\nrun: [{name: test, run: make test}]\n"),
+                ("hosts.yml", "as: hosts\nhost: {name: box}\n"),
+                (
+                    "difm.yaml",
+                    "extends: base.yml\ninclude: hosts.yml\nas: task\nprofiles:\n  ci: {run: [{name: test, run: make ci}]}\n",
+                ),
+            ],
+            &["ci"],
+        );
+
+        assert_eq!(
+            composed.unwrap(),
+            yaml("as: task\nrun: [{name: test, run: make ci}]\nhost: {name: box}")
+        );
+    }
+
+    #[test]
+    fn resolves_the_included_files_from_where_they_are() {
+        let (dir, composed) = compose_files(
+            &[
+                (
+                    "shared/base.yml",
+                    "code: {location: src, dest: '$${config_dir}'}\nhost: {base_dir: '${config_dir}/remote', name: '${alias}'}\n",
+                ),
+                ("difm.yaml", "extends: shared/base.yml\nvars: {dir: '${config_dir}'}\n"),
+            ],
+            &[],
+        );
+        let composed = composed.unwrap();
+        let shared = dir.join("shared");
+
+        assert_eq!(
+            composed["code"]["location"].as_str(),
+            Some(shared.join("src").to_str().unwrap())
+        );
+        assert_eq!(composed["code"]["dest"].as_str(), Some("$${config_dir}"));
+        assert_eq!(
+            composed["host"]["base_dir"].as_str(),
+            Some(format!("{}/remote", shared.display()).as_str())
+        );
+        assert_eq!(composed["host"]["name"].as_str(), Some("${alias}"));
+        // Left to the interpolation of the task definition
+        assert_eq!(composed["vars"]["dir"].as_str(), Some("${config_dir}"));
+    }
+
+    #[test]
+    fn refuses_the_files_including_each_other() {
+        let (_, composed) = compose_files(
+            &[
+                ("difm.yaml", "include: [a.yml]\n"),
+                ("a.yml", "include: [b.yml]\n"),
+                ("b.yml", "extends: a.yml\n"),
+            ],
+            &[],
+        );
+
+        let Err(ComposeError::Cycle(chain)) = composed else {
+            panic!("expected a cycle, found {:?}", composed);
+        };
+        let names: Vec<_> = chain
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, ["difm.yaml", "a.yml", "b.yml", "a.yml"]);
+    }
+
+    #[test]
+    fn refuses_an_unknown_profile() {
+        let (_, composed) = compose_files(
+            &[("difm.yaml", "profiles: {ci: {}, nightly: {}}\n")],
+            &["release"],
+        );
+
+        assert_eq!(
+            composed.unwrap_err().to_string(),
+            "Unknown profile `release` (available: ci, nightly)"
+        );
+    }
+}
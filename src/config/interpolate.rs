@@ -0,0 +1,474 @@
+use std::{
+    collections::BTreeMap,
+    ffi::CStr,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use serde_yaml::{Mapping, Value};
+
+use crate::adapter::git;
+
+// Left for the matrix expansion, which happens for each variant when running
+const DEFERRED_ROOTS: &[&str] = &["matrix"];
+
+// Where the matrix expansion happens, so the escaped deferred names are kept escaped for it
+const VARIANT_FIELDS: &[&str] = &["run", "artifact"];
+
+// Run by a shell, where `${NAME}` is most likely one of its variables. Only the names in one of
+// the namespaces, e.g. `${vars.NAME}`, are replaced in them.
+const COMMAND_FIELDS: &[&str] = &["run", "proxy_command"];
+
+#[derive(Debug)]
+pub struct InterpolationError {
+    location: String,
+    message: String,
+}
+
+impl Display for InterpolationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (at `{}`)", self.message, self.location)
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Field {
+    command: bool,
+    per_variant: bool,
+}
+
+struct Variables {
+    config_dir: PathBuf,
+    alias: Option<String>,
+    vars: BTreeMap<String, String>,
+    // `code.location` and `code.rev`, where `${git.*}` is read like `git.*` of the conditions
+    code_location: Option<PathBuf>,
+    rev: Option<String>,
+}
+
+impl Variables {
+    // None if the name is not one of difm's
+    fn lookup(&self, name: &str) -> Option<Result<String, String>> {
+        let value = match name.split_once('.') {
+            Some(("env", variable)) => std::env::var(variable).ok(),
+            Some(("vars", variable)) => self.vars.get(variable).cloned(),
+            Some(("git", "branch")) => git::branch(self.code_dir(), self.rev.as_deref()),
+            Some(("git", "commit")) => git::commit(self.code_dir(), self.rev.as_deref()),
+            None if name == "user" => local_user(),
+            None if name == "hostname" => local_hostname(),
+            None if name == "config_dir" => Some(self.config_dir.to_string_lossy().into_owned()),
+            None if name == "alias" => self.alias.clone(),
+            _ => return None,
+        };
+
+        Some(value.ok_or_else(|| format!("Variable `{}` is not defined", name)))
+    }
+
+    fn code_dir(&self) -> &Path {
+        self.code_location.as_deref().unwrap_or(&self.config_dir)
+    }
+}
+
+// Replaces `${...}` in every string of the task definition, except in the keys.
+// `vars` may only use the built-ins and the environment variables, and `--set` overrides them as is.
+pub fn interpolate(
+    document: &mut Value,
+    config_file: &Path,
+    overrides: &[(String, String)],
+) -> Result<(), InterpolationError> {
+    let config_dir = config_file
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let mut variables = Variables {
+        config_dir: config_dir
+            .canonicalize()
+            .unwrap_or(config_dir.to_path_buf()),
+        alias: ["as", "alias"]
+            .iter()
+            .find_map(|key| document.get(key)?.as_str())
+            .map(str::to_string),
+        vars: BTreeMap::new(),
+        code_location: None,
+        rev: None,
+    };
+
+    let Some(root) = document.as_mapping_mut() else {
+        return Ok(());
+    };
+
+    // Before `vars`, which may refer to `${git.*}`. Whatever is wrong in them is reported along
+    // with the rest of `code`.
+    let code_field = |name: &str| {
+        let text = root.get("code")?.get(name)?.as_str()?;
+        interpolate_str(text, &variables, Field::default()).ok()
+    };
+    let (code_location, rev) = (code_field("location"), code_field("rev"));
+    variables.code_location = code_location.map(PathBuf::from);
+    variables.rev = rev;
+
+    let mut vars = BTreeMap::new();
+    if let Some(declared) = root.get("vars") {
+        let Some(declared) = declared.as_mapping() else {
+            return Err(InterpolationError {
+                location: "vars".to_string(),
+                message: "`vars` should be a mapping".to_string(),
+            });
+        };
+
+        for (name, value) in declared {
+            let location = format!("vars.{}", display_key(name));
+            let value = match value {
+                Value::String(text) => interpolate_str(text, &variables, Field::default())
+                    .map_err(|message| InterpolationError {
+                        location: location.clone(),
+                        message,
+                    })?,
+                Value::Number(number) => number.to_string(),
+                Value::Bool(bool) => bool.to_string(),
+                _ => {
+                    return Err(InterpolationError {
+                        location,
+                        message: "A variable should be a string, a number or a boolean".to_string(),
+                    })
+                }
+            };
+            vars.insert(display_key(name), value);
+        }
+    }
+    vars.extend(overrides.iter().cloned());
+
+    // Stored back as strings so that the task definition knows the final values
+    root.insert(
+        "vars".into(),
+        Value::Mapping(
+            vars.iter()
+                .map(|(name, value)| (name.as_str().into(), value.as_str().into()))
+                .collect(),
+        ),
+    );
+    variables.vars = vars;
+
+    for (key, value) in root.iter_mut() {
+        if key.as_str() == Some("vars") {
+            continue;
+        }
+        let key = display_key(key);
+        let field = Field {
+            command: false,
+            per_variant: VARIANT_FIELDS.contains(&key.as_str()),
+        };
+        interpolate_value(value, &key, &variables, field)?;
+    }
+
+    Ok(())
+}
+
+fn interpolate_value(
+    value: &mut Value,
+    location: &str,
+    variables: &Variables,
+    field: Field,
+) -> Result<(), InterpolationError> {
+    match value {
+        Value::String(text) => {
+            *text =
+                interpolate_str(text, variables, field).map_err(|message| InterpolationError {
+                    location: location.to_string(),
+                    message,
+                })?;
+        }
+        Value::Sequence(items) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                let location = format!("{}[{}]", location, index);
+                interpolate_value(item, &location, variables, field)?;
+            }
+        }
+        Value::Mapping(mapping) => interpolate_mapping(mapping, location, variables, field)?,
+        Value::Tagged(tagged) => interpolate_value(&mut tagged.value, location, variables, field)?,
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+
+    Ok(())
+}
+
+fn interpolate_mapping(
+    mapping: &mut Mapping,
+    location: &str,
+    variables: &Variables,
+    field: Field,
+) -> Result<(), InterpolationError> {
+    for (key, value) in mapping.iter_mut() {
+        let key = display_key(key);
+        let field = Field {
+            command: COMMAND_FIELDS.contains(&key.as_str()),
+            ..field
+        };
+        interpolate_value(value, &format!("{}.{}", location, key), variables, field)?;
+    }
+
+    Ok(())
+}
+
+// `$${` is written as a literal `${`, by the matrix expansion for the names deferred to it
+fn interpolate_str(text: &str, variables: &Variables, field: Field) -> Result<String, String> {
+    let command = field.command;
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        let tail = &rest[start..];
+
+        if let Some(escaped) = tail.strip_prefix("$${") {
+            let deferred = field.per_variant && is_deferred(escaped);
+            result.push_str(if deferred { "$${" } else { "${" });
+            rest = escaped;
+            continue;
+        }
+        let Some(inner) = tail.strip_prefix("${") else {
+            result.push('$');
+            rest = &tail[1..];
+            continue;
+        };
+        let Some(end) = inner.find('}') else {
+            return Err("`${` is not closed; write `$${` for a literal `${`".to_string());
+        };
+
+        let name = inner[..end].trim();
+        let kept = match name.contains('.') {
+            true => is_deferred(name),
+            false => command,
+        };
+        let value = match kept {
+            true => None,
+            false => variables.lookup(name),
+        };
+        match value {
+            Some(value) => result.push_str(&value?),
+            None if kept || command => result.push_str(&tail[..end + 3]),
+            None => return Err(format!("Unknown variable `{}`", name)),
+        }
+        rest = &inner[end + 1..];
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+// Whether the name at the start of `text` is one left for the matrix expansion
+fn is_deferred(text: &str) -> bool {
+    text.trim_start()
+        .split_once('.')
+        .is_some_and(|(root, _)| DEFERRED_ROOTS.contains(&root))
+}
+
+// Replaces `${config_dir}` in the strings of a file composed into the task definition by its own
+// directory, before its values are merged with the others. The rest is left to `interpolate`.
+pub fn resolve_config_dir(value: &mut Value, config_dir: &Path) {
+    match value {
+        Value::String(text) => *text = replace_config_dir(text, config_dir),
+        Value::Sequence(items) => {
+            for item in items {
+                resolve_config_dir(item, config_dir);
+            }
+        }
+        Value::Mapping(mapping) => {
+            for (_, item) in mapping.iter_mut() {
+                resolve_config_dir(item, config_dir);
+            }
+        }
+        Value::Tagged(tagged) => resolve_config_dir(&mut tagged.value, config_dir),
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+}
+
+fn replace_config_dir(text: &str, config_dir: &Path) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        let tail = &rest[start..];
+
+        // Kept escaped for `interpolate`
+        if let Some(escaped) = tail.strip_prefix("$${") {
+            result.push_str("$${");
+            rest = escaped;
+            continue;
+        }
+        let name = tail
+            .strip_prefix("${")
+            .and_then(|inner| Some(&inner[..inner.find('}')?]));
+        match name {
+            Some(name) if name.trim() == "config_dir" => {
+                result.push_str(&config_dir.to_string_lossy());
+                rest = &tail[name.len() + 3..];
+            }
+            _ => {
+                result.push('$');
+                rest = &tail[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+
+    result
+}
+
+fn display_key(key: &Value) -> String {
+    match key {
+        Value::String(key) => key.clone(),
+        key => serde_yaml::to_string(key)
+            .map(|key| key.trim().to_string())
+            .unwrap_or_default(),
+    }
+}
+
+fn local_user() -> Option<String> {
+    ["USER", "LOGNAME"]
+        .iter()
+        .find_map(|name| std::env::var(name).ok())
+}
+
+fn local_hostname() -> Option<String> {
+    let mut buffer = [0u8; 256];
+    let result = unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) };
+    if result != 0 {
+        return None;
+    }
+
+    CStr::from_bytes_until_nul(&buffer)
+        .ok()
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::TempTree;
+
+    use super::*;
+
+    fn variables() -> Variables {
+        Variables {
+            config_dir: PathBuf::from("/work/project"),
+            alias: Some("build".to_string()),
+            vars: BTreeMap::from([("profile".to_string(), "release".to_string())]),
+            code_location: None,
+            rev: None,
+        }
+    }
+
+    fn expand(text: &str) -> Result<String, String> {
+        interpolate_str(text, &variables(), Field::default())
+    }
+
+    fn expand_command(text: &str) -> Result<String, String> {
+        let field = Field {
+            command: true,
+            per_variant: false,
+        };
+        interpolate_str(text, &variables(), field)
+    }
+
+    #[test]
+    fn replaces_the_variables() {
+        assert_eq!(
+            expand("${alias}-${ vars.profile }").unwrap(),
+            "build-release"
+        );
+        assert_eq!(
+            expand("${config_dir}/target").unwrap(),
+            "/work/project/target"
+        );
+        assert_eq!(expand("cost: $5").unwrap(), "cost: $5");
+    }
+
+    #[test]
+    fn writes_an_escaped_literal() {
+        assert_eq!(expand("echo $${HOME}").unwrap(), "echo ${HOME}");
+        assert_eq!(
+            expand("$${vars.profile} ${vars.profile}").unwrap(),
+            "${vars.profile} release"
+        );
+    }
+
+    #[test]
+    fn refuses_the_unknown_and_undefined_names() {
+        assert_eq!(expand("${HOME}").unwrap_err(), "Unknown variable `HOME`");
+        assert_eq!(
+            expand("${vars.missing}").unwrap_err(),
+            "Variable `vars.missing` is not defined"
+        );
+        assert!(expand("${vars.profile").is_err());
+    }
+
+    #[test]
+    fn leaves_the_shell_variables_in_the_commands() {
+        assert_eq!(
+            expand_command("cd ${HOME} && echo ${user} ${vars.profile}").unwrap(),
+            "cd ${HOME} && echo ${user} release"
+        );
+        assert_eq!(
+            expand_command("${vars.missing}").unwrap_err(),
+            "Variable `vars.missing` is not defined"
+        );
+    }
+
+    #[test]
+    fn defers_the_matrix_variables() {
+        assert_eq!(
+            expand("--target ${matrix.target} --${vars.profile}").unwrap(),
+            "--target ${matrix.target} --release"
+        );
+        assert_eq!(expand_command("${ matrix.os }").unwrap(), "${ matrix.os }");
+    }
+
+    #[test]
+    fn only_replaces_in_the_commands_what_is_namespaced() {
+        let mut document: Value = serde_yaml::from_str(
+            "as: build\nvars:\n  dir: ${alias}\nhost:\n  base_dir: ~/${vars.dir}\nrun:\n  - name: ${alias}\n    run: echo ${alias} ${vars.dir} ${PWD}\n",
+        )
+        .unwrap();
+        interpolate(&mut document, Path::new("difm.yaml"), &[]).unwrap();
+
+        assert_eq!(document["host"]["base_dir"].as_str(), Some("~/build"));
+        assert_eq!(document["run"][0]["name"].as_str(), Some("build"));
+        assert_eq!(
+            document["run"][0]["run"].as_str(),
+            Some("echo ${alias} build ${PWD}")
+        );
+    }
+
+    #[test]
+    fn reads_git_from_the_code_at_its_revision() {
+        let repo = TempTree::new(&[("a.rs", "1")]);
+        repo.commit();
+        let first = git::commit(&repo, None).unwrap();
+        std::fs::write(repo.join("a.rs"), "2").unwrap();
+        repo.commit();
+        let branch = git::branch(&repo, None).unwrap();
+
+        let expand = |rev: &str, text: &str| {
+            let mut document: Value = serde_yaml::from_str(&format!(
+                "code: {{location: {}, rev: {}}}\nhost: {}",
+                repo.display(),
+                rev,
+                text
+            ))
+            .unwrap();
+            interpolate(&mut document, Path::new("/difm.yaml"), &[])
+                .map(|_| document["host"].as_str().unwrap().to_string())
+                .map_err(|err| err.to_string())
+        };
+
+        assert_eq!(expand("HEAD~1", "${git.commit}").unwrap(), first);
+        assert_ne!(expand("~", "${git.commit}").unwrap(), first);
+        assert_eq!(expand("~", "${git.branch}").unwrap(), branch);
+        // Not on a branch
+        assert_eq!(
+            expand("HEAD~1", "${git.branch}").unwrap_err(),
+            "Variable `git.branch` is not defined (at `host`)"
+        );
+    }
+}
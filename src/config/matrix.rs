@@ -0,0 +1,432 @@
+use std::fmt::Display;
+
+use serde::{de::DeserializeOwned, de::Error, ser::SerializeMap, Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+
+use super::{
+    check::{Diagnostic, Segment},
+    TaskArtifact, TaskDefinition, TaskRun,
+};
+
+// Every combination of the axes becomes a variant, like the matrix of GitHub Actions. The axes
+// keep the order they are written in.
+#[derive(Clone, Debug, Default)]
+pub struct TaskMatrix {
+    pub axes: Vec<(String, Vec<String>)>,
+    pub include: Vec<Vec<(String, String)>>,
+    pub exclude: Vec<Vec<(String, String)>>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MatrixVariant(Vec<(String, String)>);
+
+impl TaskMatrix {
+    pub fn expand(&self) -> Vec<MatrixVariant> {
+        let mut variants = vec![Vec::new()];
+        for (name, values) in &self.axes {
+            variants = variants
+                .into_iter()
+                .flat_map(|variant: Vec<(String, String)>| {
+                    values.iter().map(move |value| {
+                        let mut variant = variant.clone();
+                        variant.push((name.clone(), value.clone()));
+                        variant
+                    })
+                })
+                .collect();
+        }
+
+        if self.axes.is_empty() {
+            variants.clear();
+        }
+
+        variants.retain(|variant| {
+            !self
+                .exclude
+                .iter()
+                .any(|rule| rule.iter().all(|entry| variant.contains(entry)))
+        });
+
+        // An `include` adds its extra values to the variants it matches on the axes, or becomes
+        // a variant by itself if it matches none of them
+        for rule in &self.include {
+            let is_axis = |name: &str| self.axes.iter().any(|(axis, _)| axis == name);
+            let mut matched = false;
+
+            for variant in &mut variants {
+                let matches = rule
+                    .iter()
+                    .filter(|(name, _)| is_axis(name))
+                    .all(|entry| variant.contains(entry));
+                if !matches {
+                    continue;
+                }
+
+                matched = true;
+                for (name, value) in rule {
+                    if !variant.iter().any(|(existing, _)| existing == name) {
+                        variant.push((name.clone(), value.clone()));
+                    }
+                }
+            }
+
+            if !matched {
+                variants.push(rule.clone());
+            }
+        }
+
+        variants.into_iter().map(MatrixVariant).collect()
+    }
+}
+
+impl MatrixVariant {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn values(&self) -> &[(String, String)] {
+        &self.0
+    }
+
+    // Replaces `${matrix.<name>}`. A name the variant has no value for would leave the text
+    // half-written, e.g. `--target ` for `--target ${matrix.target}`, so it is refused.
+    pub fn substitute(&self, text: &str) -> Result<String, String> {
+        let mut result = String::new();
+        let mut rest = text;
+
+        while let Some(start) = rest.find("${") {
+            // `$${` is written as a literal `${`
+            if let Some(before) = rest[..start].strip_suffix('$') {
+                result.push_str(before);
+                result.push_str("${");
+                rest = &rest[start + 2..];
+                continue;
+            }
+            result.push_str(&rest[..start]);
+            let placeholder = &rest[start..];
+
+            let Some(end) = placeholder.find('}') else {
+                rest = placeholder;
+                break;
+            };
+            let Some(name) = placeholder[2..end].trim().strip_prefix("matrix.") else {
+                result.push_str(&placeholder[..=end]);
+                rest = &placeholder[end + 1..];
+                continue;
+            };
+            match self.get(name) {
+                Some(value) => result.push_str(value),
+                None if self.0.is_empty() => {
+                    return Err(format!("`${{matrix.{}}}` is used without a `matrix`", name))
+                }
+                None => {
+                    return Err(format!(
+                        "`${{matrix.{}}}` has no value in the variant {}",
+                        name, self
+                    ))
+                }
+            }
+            rest = &placeholder[end + 1..];
+        }
+        result.push_str(rest);
+
+        Ok(result)
+    }
+
+    // The steps and the artifacts of the task for this variant
+    pub fn apply(
+        &self,
+        task: &TaskDefinition,
+    ) -> Result<(Vec<TaskRun>, Vec<TaskArtifact>), Diagnostic> {
+        Ok((
+            self.substitute_list("run", &task.run)?,
+            self.substitute_list("artifact", &task.artifact)?,
+        ))
+    }
+
+    fn substitute_list<T: Serialize + DeserializeOwned>(
+        &self,
+        field: &str,
+        items: &[T],
+    ) -> Result<Vec<T>, Diagnostic> {
+        items
+            .iter()
+            .enumerate()
+            .map(|(index, item)| {
+                self.substitute_all(item).map_err(|message| {
+                    Diagnostic::new(
+                        &[Segment::Key(field.to_string()), Segment::Index(index)],
+                        message,
+                    )
+                })
+            })
+            .collect()
+    }
+
+    // Substitutes in every string of a step or an artifact, `if:` included
+    fn substitute_all<T: Serialize + DeserializeOwned>(&self, item: &T) -> Result<T, String> {
+        let mut value = serde_yaml::to_value(item).map_err(|err| err.to_string())?;
+        self.substitute_value(&mut value)?;
+
+        serde_yaml::from_value(value).map_err(|err| err.to_string())
+    }
+
+    fn substitute_value(&self, value: &mut Value) -> Result<(), String> {
+        match value {
+            Value::String(text) => *text = self.substitute(text)?,
+            Value::Sequence(items) => {
+                for item in items {
+                    self.substitute_value(item)?;
+                }
+            }
+            Value::Mapping(mapping) => {
+                for (_, item) in mapping.iter_mut() {
+                    self.substitute_value(item)?;
+                }
+            }
+            Value::Tagged(tagged) => self.substitute_value(&mut tagged.value)?,
+            Value::Null | Value::Bool(_) | Value::Number(_) => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for MatrixVariant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let values: Vec<_> = self.0.iter().map(|(_, value)| value.as_str()).collect();
+        write!(f, "({})", values.join(", "))
+    }
+}
+
+impl<'de> Deserialize<'de> for TaskMatrix {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut matrix = TaskMatrix::default();
+
+        for (key, value) in Mapping::deserialize(deserializer)? {
+            let key =
+                scalar(&key).ok_or_else(|| D::Error::custom("matrix keys must be strings"))?;
+
+            match key.as_str() {
+                "include" => matrix.include = rules(value).map_err(D::Error::custom)?,
+                "exclude" => matrix.exclude = rules(value).map_err(D::Error::custom)?,
+                _ => {
+                    let Value::Sequence(values) = value else {
+                        return Err(D::Error::custom(format!(
+                            "matrix axis `{}` should be a list",
+                            key
+                        )));
+                    };
+                    let values = values
+                        .iter()
+                        .map(|value| {
+                            scalar(value).ok_or_else(|| {
+                                D::Error::custom(format!(
+                                    "values of matrix axis `{}` should be scalars",
+                                    key
+                                ))
+                            })
+                        })
+                        .collect::<Result<_, _>>()?;
+                    matrix.axes.push((key, values));
+                }
+            }
+        }
+
+        Ok(matrix)
+    }
+}
+
+// Written back in the same shape as it is read
+impl Serialize for TaskMatrix {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let rules = |rules: &[Vec<(String, String)>]| -> Vec<Mapping> {
+            rules
+                .iter()
+                .map(|rule| {
+                    rule.iter()
+                        .map(|(key, value)| (key.as_str().into(), value.as_str().into()))
+                        .collect()
+                })
+                .collect()
+        };
+
+        let mut map = serializer.serialize_map(None)?;
+        for (name, values) in &self.axes {
+            map.serialize_entry(name, values)?;
+        }
+        if !self.include.is_empty() {
+            map.serialize_entry("include", &rules(&self.include))?;
+        }
+        if !self.exclude.is_empty() {
+            map.serialize_entry("exclude", &rules(&self.exclude))?;
+        }
+        map.end()
+    }
+}
+
+fn rules(value: Value) -> Result<Vec<Vec<(String, String)>>, String> {
+    let Value::Sequence(rules) = value else {
+        return Err("`include` and `exclude` should be a list".to_string());
+    };
+
+    rules
+        .iter()
+        .map(|rule| {
+            let Value::Mapping(rule) = rule else {
+                return Err("each of `include` and `exclude` should be a mapping".to_string());
+            };
+            rule.iter()
+                .map(|(key, value)| match (scalar(key), scalar(value)) {
+                    (Some(key), Some(value)) => Ok((key, value)),
+                    _ => Err("values in `include` and `exclude` should be scalars".to_string()),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{condition::Condition, config::interpolate::interpolate};
+
+    use super::*;
+
+    fn matrix(yaml: &str) -> TaskMatrix {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn variants(matrix: &TaskMatrix) -> Vec<String> {
+        matrix
+            .expand()
+            .iter()
+            .map(|variant| variant.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn combines_the_axes_in_order() {
+        assert_eq!(
+            variants(&matrix("os: [linux, mac]\nrust: [stable, beta]")),
+            [
+                "(linux, stable)",
+                "(linux, beta)",
+                "(mac, stable)",
+                "(mac, beta)"
+            ]
+        );
+        assert!(variants(&matrix("include: [{os: linux}]")).len() == 1);
+    }
+
+    #[test]
+    fn excludes_and_includes_the_variants() {
+        let matrix = matrix(
+            "os: [linux, mac]\nrust: [stable, nightly]\nexclude:\n  - {os: mac, rust: nightly}\ninclude:\n  - {os: linux, extra: asan}\n  - {os: windows, rust: stable}\n",
+        );
+        let expanded = matrix.expand();
+
+        assert_eq!(
+            variants(&matrix),
+            [
+                "(linux, stable, asan)",
+                "(linux, nightly, asan)",
+                "(mac, stable)",
+                "(windows, stable)"
+            ]
+        );
+        assert_eq!(expanded[0].get("extra"), Some("asan"));
+        assert_eq!(expanded[2].get("extra"), None);
+    }
+
+    #[test]
+    fn substitutes_the_values_of_the_variant() {
+        let variant = MatrixVariant(vec![("os".to_string(), "linux".to_string())]);
+
+        assert_eq!(
+            variant
+                .substitute("build-${matrix.os}-${ matrix.os }")
+                .unwrap(),
+            "build-linux-linux"
+        );
+        assert_eq!(
+            variant.substitute("--target ${matrix.target}").unwrap_err(),
+            "`${matrix.target}` has no value in the variant (linux)"
+        );
+        assert_eq!(variant.substitute("${HOME} ${os").unwrap(), "${HOME} ${os");
+        assert_eq!(
+            variant.substitute("$${matrix.os} ${matrix.os}").unwrap(),
+            "${matrix.os} linux"
+        );
+        assert_eq!(
+            MatrixVariant::default()
+                .substitute("${matrix.os}")
+                .unwrap_err(),
+            "`${matrix.os}` is used without a `matrix`"
+        );
+    }
+
+    #[test]
+    fn substitutes_in_every_field_of_the_steps() {
+        let task: TaskDefinition = serde_yaml::from_str(
+            "host: {name: box, base_dir: src}\ncode: {location: ., dest: code, use: ssh}\nrun:\n  - name: test-${matrix.os}\n    run: make ${matrix.os}\n    cwd: build/${matrix.os}\n    if: \"'${matrix.os}' == 'linux'\"\n    tty: {term: \"${matrix.os}\"}\nartifact:\n  - remote_path: out/${matrix.os}.tar\n    local_path: ${matrix.os}.tar\n",
+        )
+        .unwrap();
+        let variant = MatrixVariant(vec![("os".to_string(), "linux".to_string())]);
+        let (runs, artifacts) = variant.apply(&task).unwrap();
+
+        assert_eq!(runs[0].name, "test-linux");
+        assert_eq!(runs[0].run, "make linux");
+        assert_eq!(runs[0].cwd.as_deref(), Some(Path::new("build/linux")));
+        assert_eq!(
+            runs[0].condition.as_ref().map(Condition::source),
+            Some("'linux' == 'linux'")
+        );
+        assert_eq!(
+            runs[0].tty().and_then(|tty| tty.term).as_deref(),
+            Some("linux")
+        );
+        assert_eq!(artifacts[0].remote_path, Path::new("out/linux.tar"));
+        assert_eq!(artifacts[0].local_path, Path::new("linux.tar"));
+
+        let other = MatrixVariant(vec![("arch".to_string(), "arm".to_string())]);
+        let diagnostic = other.apply(&task).unwrap_err();
+        assert_eq!(diagnostic.path(), "run[0]");
+    }
+
+    #[test]
+    fn keeps_an_escaped_placeholder_through_both_passes() {
+        let mut document: serde_yaml::Value = serde_yaml::from_str(
+            "host: {name: box, base_dir: '$${matrix.os}'}\ncode: {location: ., dest: code, use: ssh}\nrun:\n  - name: test\n    run: echo $${matrix.os} ${matrix.os} $${HOME}\nartifact: []\n",
+        )
+        .unwrap();
+        interpolate(&mut document, Path::new("difm.yaml"), &[]).unwrap();
+        let task: TaskDefinition = serde_yaml::from_value(document).unwrap();
+        let variant = MatrixVariant(vec![("os".to_string(), "linux".to_string())]);
+        let (runs, _) = variant.apply(&task).unwrap();
+
+        assert_eq!(runs[0].run, "echo ${matrix.os} linux ${HOME}");
+        assert_eq!(task.host[0].base_dir, Path::new("${matrix.os}"));
+
+        // Without a matrix, the escape is written out all the same
+        let diagnostic = MatrixVariant::default().apply(&task).unwrap_err();
+        assert_eq!(diagnostic.path(), "run[0]");
+        let mut task = task;
+        task.run[0].run = "echo $${matrix.os}".to_string();
+        let (runs, _) = MatrixVariant::default().apply(&task).unwrap();
+        assert_eq!(runs[0].run, "echo ${matrix.os}");
+    }
+}
@@ -1,17 +1,196 @@
-use std::{fs::File, io::BufReader, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    io,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
 
-use serde::{Deserialize, Serialize};
+use serde::{de::Error, Deserialize, Serialize};
 
+use crate::condition::Condition;
+
+use self::{
+    check::{check_document, check_task, locate, Diagnostic},
+    compose::{compose, is_composed, ComposeError, Source},
+    interpolate::{interpolate, InterpolationError},
+    matrix::TaskMatrix,
+};
+
+pub mod check;
+pub mod compose;
+pub mod interpolate;
+pub mod matrix;
+pub mod schema;
 pub mod ssh;
 
-pub fn read_config(path: Option<PathBuf>) -> ConfigContext {
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Syntax(PathBuf, serde_yaml::Error),
+    SshConfig(PathBuf, String),
+    Compose(ComposeError),
+    Interpolate(PathBuf, InterpolationError),
+    Invalid(PathBuf, Vec<Diagnostic>),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => {
+                write!(f, "Could not read {}: {}", path.display(), err)
+            }
+            ConfigError::Syntax(path, err) => write!(f, "{}: {}", path.display(), err),
+            ConfigError::SshConfig(path, err) => {
+                write!(f, "Could not parse {}: {}", path.display(), err)
+            }
+            ConfigError::Compose(err) => write!(f, "{}", err),
+            ConfigError::Interpolate(path, err) => write!(f, "{}: {}", path.display(), err),
+            ConfigError::Invalid(path, diagnostics) => {
+                for (index, diagnostic) in diagnostics.iter().enumerate() {
+                    if index > 0 {
+                        writeln!(f)?;
+                    }
+                    let file = diagnostic.file.as_ref().unwrap_or(path);
+                    match diagnostic.location {
+                        Some(_) => write!(f, "{}:{}", file.display(), diagnostic)?,
+                        None => write!(f, "{}: {}", file.display(), diagnostic)?,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// `rev` overrides `code.rev`, before the interpolation that reads it for `${git.*}`
+pub fn read_config(
+    path: Option<PathBuf>,
+    profiles: &[String],
+    overrides: &[(String, String)],
+    rev: Option<&str>,
+) -> Result<ConfigContext, ConfigError> {
     let path = path.unwrap_or("./difm.yaml".into());
-    let file = File::open(&path).unwrap();
-    let reader = BufReader::new(file);
 
-    ConfigContext {
-        config: serde_yaml::from_reader(reader).unwrap(),
+    Ok(ConfigContext {
+        config: load_config(&path, profiles, overrides, rev)?,
         config_file: path,
+    })
+}
+
+// Reads the task definition, checking its shape against the schema so that the mistakes are
+// reported with their locations rather than the first error of serde.
+pub fn load_config(
+    path: &Path,
+    profiles: &[String],
+    overrides: &[(String, String)],
+    rev: Option<&str>,
+) -> Result<Configuration, ConfigError> {
+    let document = read_document(path, profiles, overrides, rev)?;
+
+    let diagnostics = check_document(&document.value);
+    if !diagnostics.is_empty() {
+        return Err(document.invalid(diagnostics));
+    }
+
+    document.deserialize()
+}
+
+// Everything wrong with the task definition at once, for `difm check`: the shape, and what only
+// the definition read tells, unless it cannot be read at all
+pub fn diagnose(
+    path: &Path,
+    profiles: &[String],
+    overrides: &[(String, String)],
+) -> Result<Vec<Diagnostic>, ConfigError> {
+    let document = read_document(path, profiles, overrides, None)?;
+
+    let mut diagnostics = check_document(&document.value);
+    match document.deserialize() {
+        Ok(Configuration::TaskDefinition(task)) => diagnostics.extend(check_task(&task)),
+        Err(err) if diagnostics.is_empty() => return Err(err),
+        Err(_) => {}
+    }
+
+    Ok(document.located(diagnostics))
+}
+
+struct Document {
+    path: PathBuf,
+    text: String,
+    original: serde_yaml::Value,
+    // Before the interpolation, to find the values in the sources
+    composed: serde_yaml::Value,
+    sources: Vec<Source>,
+    value: serde_yaml::Value,
+}
+
+fn read_document(
+    path: &Path,
+    profiles: &[String],
+    overrides: &[(String, String)],
+    rev: Option<&str>,
+) -> Result<Document, ConfigError> {
+    let text =
+        std::fs::read_to_string(path).map_err(|err| ConfigError::Read(path.to_path_buf(), err))?;
+
+    let original: serde_yaml::Value =
+        serde_yaml::from_str(&text).map_err(|err| ConfigError::Syntax(path.to_path_buf(), err))?;
+    let (composed, sources) =
+        compose(original.clone(), &text, path, profiles).map_err(ConfigError::Compose)?;
+    let mut value = composed.clone();
+    if let (Some(rev), Some(code)) = (rev, value.get_mut("code")) {
+        if let Some(code) = code.as_mapping_mut() {
+            code.insert("rev".into(), rev.into());
+        }
+    }
+    interpolate(&mut value, path, overrides)
+        .map_err(|err| ConfigError::Interpolate(path.to_path_buf(), err))?;
+
+    Ok(Document {
+        path: path.to_path_buf(),
+        text,
+        original,
+        composed,
+        sources,
+        value,
+    })
+}
+
+impl Document {
+    fn located(&self, mut diagnostics: Vec<Diagnostic>) -> Vec<Diagnostic> {
+        for diagnostic in &mut diagnostics {
+            locate(&self.sources, &self.composed, diagnostic);
+            // The task definition itself goes without saying
+            if diagnostic.file.as_deref() == Some(&self.path) {
+                diagnostic.file = None;
+            }
+        }
+
+        diagnostics
+    }
+
+    fn invalid(&self, diagnostics: Vec<Diagnostic>) -> ConfigError {
+        ConfigError::Invalid(self.path.clone(), self.located(diagnostics))
+    }
+
+    fn deserialize(&self) -> Result<Configuration, ConfigError> {
+        serde_yaml::from_value(self.value.clone()).map_err(|err| {
+            // Anything the schema misses; the original text still tells the location if it is the
+            // whole definition
+            let err = if is_composed(&self.original) {
+                err
+            } else {
+                serde_yaml::from_str::<Configuration>(&self.text)
+                    .err()
+                    .unwrap_or(err)
+            };
+            let mut diagnostic = Diagnostic::new(&[], err.to_string());
+            diagnostic.location = err
+                .location()
+                .map(|location| (location.line(), location.column()));
+            ConfigError::Invalid(self.path.clone(), vec![diagnostic])
+        })
     }
 }
 
@@ -31,28 +210,176 @@ pub enum Configuration {
 pub struct TaskDefinition {
     #[serde(alias = "as")]
     pub alias: Option<String>,
-    pub host: TaskHost,
+    pub host: TaskHosts,
     pub code: TaskCodeDefinition,
     pub run: Vec<TaskRun>,
     pub artifact: Vec<TaskArtifact>,
+
+    #[serde(default)]
+    pub forward: Vec<TaskForward>,
+
+    #[serde(default)]
+    pub matrix: Option<TaskMatrix>,
+
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
+
+    #[serde(default)]
+    pub history: TaskHistory,
+}
+
+// `host:` is either a host, a list of hosts, or a host group, whose `name:` lists the hosts
+// sharing the rest of the settings
+#[derive(Clone, Debug, Serialize)]
+pub struct TaskHosts(Vec<TaskHost>);
+
+impl Deref for TaskHosts {
+    type Target = [TaskHost];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<'de> Deserialize<'de> for TaskHosts {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hosts = match serde_yaml::Value::deserialize(deserializer)? {
+            serde_yaml::Value::Sequence(hosts) => hosts,
+            serde_yaml::Value::Mapping(host) => match host.get("name") {
+                Some(serde_yaml::Value::Sequence(names)) => names
+                    .iter()
+                    .map(|name| {
+                        let mut host = host.clone();
+                        host.insert("name".into(), name.clone());
+                        serde_yaml::Value::Mapping(host)
+                    })
+                    .collect(),
+                _ => vec![serde_yaml::Value::Mapping(host)],
+            },
+            _ => return Err(D::Error::custom("expected a host, or a list of them")),
+        };
+
+        if hosts.is_empty() {
+            return Err(D::Error::custom("at least one host is required"));
+        }
+
+        hosts
+            .into_iter()
+            .map(serde_yaml::from_value)
+            .collect::<Result<_, _>>()
+            .map(Self)
+            .map_err(D::Error::custom)
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TaskHost {
     pub name: String,
     pub base_dir: PathBuf,
+
+    #[serde(default)]
+    pub proxy_jump: Option<String>,
+    #[serde(default)]
+    pub proxy_command: Option<String>,
+
+    // In seconds
+    #[serde(default)]
+    pub connect_timeout: Option<u64>,
+    #[serde(default)]
+    pub connection_attempts: Option<usize>,
+    #[serde(default)]
+    pub address_family: Option<AddressFamily>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressFamily {
+    #[default]
+    Any,
+    Inet,
+    Inet6,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TaskCodeDefinition {
     pub location: PathBuf,
     pub dest: PathBuf,
+
+    // Patterns in the .gitignore format. `ignore` is the older form of `exclude`, in a string.
+    #[serde(default)]
     pub ignore: String,
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+
+    #[serde(default)]
+    pub source: CodeSource,
+    // With `source: git`, the untracked files not ignored are sent too
+    #[serde(default)]
+    pub untracked: bool,
+    // Sends the files at this revision instead of the working tree. Overridden by `--rev`.
+    #[serde(default)]
+    pub rev: Option<String>,
+
+    #[serde(flatten)]
+    pub filters: TaskCodeFilters,
 
     #[serde(alias = "use")]
     pub protocol: TaskCodeProtocol,
 }
 
+impl TaskCodeDefinition {
+    pub fn exclude_patterns(&self) -> impl Iterator<Item = &str> {
+        self.ignore
+            .lines()
+            .chain(self.exclude.iter().map(String::as_str))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CodeSource {
+    // Walked through, respecting the ignore files
+    #[default]
+    Files,
+    // Listed by git, along with the ones in the submodules
+    Git,
+}
+
+// Which of the usual ignore files are respected
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TaskCodeFilters {
+    pub gitignore: bool,
+    pub global_gitignore: bool,
+    // Whether the hidden files are sent. `.git` and `.difm` never are.
+    pub hidden: bool,
+    pub symlinks: SymlinkPolicy,
+}
+
+impl Default for TaskCodeFilters {
+    fn default() -> Self {
+        Self {
+            gitignore: true,
+            global_gitignore: true,
+            hidden: false,
+            symlinks: SymlinkPolicy::Recreate,
+        }
+    }
+}
+
+// The links looping or pointing outside of `code.location` are skipped whichever is chosen
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkPolicy {
+    // Created as a link to the same target on the host
+    Recreate,
+    // Sent as the file or the directory it points to
+    Follow,
+    Skip,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum TaskCodeProtocol {
@@ -64,10 +391,46 @@ pub struct TaskRun {
     pub name: String,
     pub run: String,
 
+    #[serde(default, rename = "if")]
+    pub condition: Option<Condition>,
+
+    #[serde(default)]
+    pub needs: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
+
+    #[serde(default)]
+    pub tty: Option<TaskRunTty>,
+
     #[serde(default)]
     pub platform: TaskRunPlatform,
 }
 
+impl TaskRun {
+    pub fn tty(&self) -> Option<TaskRunTtyConfig> {
+        match &self.tty {
+            Some(TaskRunTty::Enabled(true)) => Some(TaskRunTtyConfig::default()),
+            Some(TaskRunTty::Configured(config)) => Some(config.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TaskRunTty {
+    Enabled(bool),
+    Configured(TaskRunTtyConfig),
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TaskRunTtyConfig {
+    pub term: Option<String>,
+    pub cols: Option<u32>,
+    pub rows: Option<u32>,
+}
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum TaskRunPlatform {
@@ -80,3 +443,40 @@ pub struct TaskArtifact {
     pub remote_path: PathBuf,
     pub local_path: PathBuf,
 }
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TaskForward {
+    #[serde(rename = "type")]
+    pub kind: TaskForwardKind,
+    #[serde(default)]
+    pub bind: Option<String>,
+    pub listen: u16,
+    pub connect: String,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskForwardKind {
+    Local,
+    Remote,
+}
+
+// The runs are stored under `.difm/runs` next to the task definition
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TaskHistory {
+    pub enabled: bool,
+    // The older ones are removed after each run
+    pub keep_runs: usize,
+    pub keep_days: u64,
+}
+
+impl Default for TaskHistory {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            keep_runs: 50,
+            keep_days: 30,
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use serde_json::{json, Value};
+
+// JSON Schema of the task definition, after `include:`, `extends:` and `profiles:` are resolved.
+// `difm check` validates the definition with this too, so keep it in sync with the types in `config`.
+pub fn schema() -> Value {
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "difm task definition",
+        "type": "object",
+        "required": ["type", "host", "code", "run", "artifact"],
+        "additionalProperties": false,
+        "properties": {
+            "type": {
+                "description": "Kind of the definition",
+                "enum": ["task", "TaskDefinition"]
+            },
+            "as": { "$ref": "#/$defs/alias" },
+            "alias": { "$ref": "#/$defs/alias" },
+            "host": {
+                "description": "Host to work on, a list of them, or a group whose `name` lists the hosts",
+                "anyOf": [
+                    { "$ref": "#/$defs/hostGroup" },
+                    { "type": "array", "items": { "$ref": "#/$defs/host" }, "minItems": 1 }
+                ]
+            },
+            "code": { "$ref": "#/$defs/code" },
+            "run": {
+                "description": "Steps to run on the host",
+                "type": "array",
+                "items": { "$ref": "#/$defs/step" }
+            },
+            "artifact": {
+                "description": "Files fetched from the host after the steps",
+                "type": "array",
+                "items": { "$ref": "#/$defs/artifact" }
+            },
+            "forward": {
+                "description": "Ports forwarded while the steps run, or with `difm tunnel`",
+                "type": "array",
+                "items": { "$ref": "#/$defs/forward" }
+            },
+            "matrix": { "$ref": "#/$defs/matrix" },
+            "vars": {
+                "description": "Variables available as ${vars.NAME}",
+                "type": "object",
+                "additionalProperties": { "type": ["string", "number", "boolean"] }
+            },
+            "history": {
+                "description": "How the runs are kept in .difm/runs next to this file, for `difm history` and `difm logs`",
+                "type": "object",
+                "additionalProperties": false,
+                "properties": {
+                    "enabled": { "type": "boolean" },
+                    "keep_runs": {
+                        "description": "Number of the runs kept, 50 by default",
+                        "type": "integer",
+                        "minimum": 1
+                    },
+                    "keep_days": {
+                        "description": "Runs older than this are removed, 30 by default",
+                        "type": "integer",
+                        "minimum": 1
+                    }
+                }
+            },
+            "include": {
+                "description": "Files merged into the task, relative to this file",
+                "anyOf": [
+                    { "type": "string" },
+                    { "type": "array", "items": { "type": "string" } }
+                ]
+            },
+            "extends": {
+                "description": "Task definition inherited by this one, relative to this file",
+                "type": "string"
+            },
+            "profiles": {
+                "description": "Overrides applied on top of the task with `--profile`",
+                "type": "object",
+                "additionalProperties": { "type": "object" }
+            }
+        },
+        "$defs": {
+            "alias": {
+                "description": "Short name of the task",
+                "type": "string"
+            },
+            "host": {
+                "type": "object",
+                "required": ["name", "base_dir"],
+                "additionalProperties": false,
+                "properties": host_properties(json!({
+                    "description": "Host in ~/.ssh/config, or the hostname",
+                    "type": "string"
+                }))
+            },
+            "hostGroup": {
+                "type": "object",
+                "required": ["name", "base_dir"],
+                "additionalProperties": false,
+                "properties": host_properties(json!({
+                    "description": "Host in ~/.ssh/config, or the list of them sharing the settings",
+                    "anyOf": [
+                        { "type": "string" },
+                        { "type": "array", "items": { "type": "string" }, "minItems": 1 }
+                    ]
+                }))
+            },
+            "code": {
+                "type": "object",
+                "required": ["location", "dest"],
+                "additionalProperties": false,
+                "properties": {
+                    "location": {
+                        "description": "Local directory to send",
+                        "type": "string"
+                    },
+                    "dest": {
+                        "description": "Destination relative to `host.base_dir`",
+                        "type": "string"
+                    },
+                    "ignore": {
+                        "description": "Patterns of the files not to send, in the .gitignore format, one per line",
+                        "type": "string"
+                    },
+                    "include": {
+                        "description": "Only send the files matching these patterns, in the .gitignore format",
+                        "type": "array",
+                        "items": { "type": "string" }
+                    },
+                    "exclude": {
+                        "description": "Patterns of the files not to send, in the .gitignore format",
+                        "type": "array",
+                        "items": { "type": "string" }
+                    },
+                    "gitignore": {
+                        "description": "Leave out the files ignored by .gitignore and .git/info/exclude (default: true)",
+                        "type": "boolean"
+                    },
+                    "global_gitignore": {
+                        "description": "Leave out the files ignored by the global gitignore of git (default: true)",
+                        "type": "boolean"
+                    },
+                    "hidden": {
+                        "description": "Send the hidden files too, except for .git and .difm (default: false)",
+                        "type": "boolean"
+                    },
+                    "source": {
+                        "description": "Whether the files in `location` are sent, or the ones tracked by git (default: files)",
+                        "enum": ["files", "git"]
+                    },
+                    "untracked": {
+                        "description": "With `source: git`, also send the untracked files not ignored (default: false)",
+                        "type": "boolean"
+                    },
+                    "rev": {
+                        "description": "Send the files at this git revision instead of the working tree, e.g. `HEAD~1`",
+                        "type": "string"
+                    },
+                    "symlinks": {
+                        "description": "Whether the symlinks are recreated on the host, followed, or skipped (default: recreate)",
+                        "enum": ["recreate", "follow", "skip"]
+                    },
+                    "use": { "$ref": "#/$defs/protocol" },
+                    "protocol": { "$ref": "#/$defs/protocol" }
+                },
+                "anyOf": [
+                    { "required": ["use"] },
+                    { "required": ["protocol"] }
+                ]
+            },
+            "protocol": {
+                "description": "How the code is sent",
+                "enum": ["ssh"]
+            },
+            "step": {
+                "type": "object",
+                "required": ["name", "run"],
+                "additionalProperties": false,
+                "properties": {
+                    "name": { "type": "string" },
+                    "run": {
+                        "description": "Shell command to run",
+                        "type": "string"
+                    },
+                    "if": {
+                        "description": "Only run the step if the expression is true, e.g. `failure() && env.CI == 'true'` or `git.branch == 'main'`",
+                        "type": ["string", "boolean"]
+                    },
+                    "needs": {
+                        "description": "Steps to finish before this one; the steps run in parallel otherwise",
+                        "type": "array",
+                        "items": { "type": "string" }
+                    },
+                    "cwd": {
+                        "description": "Working directory relative to the code destination",
+                        "type": "string"
+                    },
+                    "tty": {
+                        "description": "Run the step in a pseudo terminal",
+                        "anyOf": [
+                            { "type": "boolean" },
+                            {
+                                "type": "object",
+                                "additionalProperties": false,
+                                "properties": {
+                                    "term": { "type": "string" },
+                                    "cols": { "type": "integer", "minimum": 1 },
+                                    "rows": { "type": "integer", "minimum": 1 }
+                                }
+                            }
+                        ]
+                    },
+                    "platform": { "enum": ["remote"] }
+                }
+            },
+            "artifact": {
+                "type": "object",
+                "required": ["remote_path", "local_path"],
+                "additionalProperties": false,
+                "properties": {
+                    "remote_path": {
+                        "description": "Path relative to the code destination",
+                        "type": "string"
+                    },
+                    "local_path": {
+                        "description": "Path relative to the current directory",
+                        "type": "string"
+                    }
+                }
+            },
+            "forward": {
+                "type": "object",
+                "required": ["type", "listen", "connect"],
+                "additionalProperties": false,
+                "properties": {
+                    "type": {
+                        "description": "`local` listens locally and connects from the host, `remote` the other way around",
+                        "enum": ["local", "remote"]
+                    },
+                    "bind": { "type": "string" },
+                    "listen": { "type": "integer", "minimum": 0, "maximum": 65535 },
+                    "connect": {
+                        "description": "Address to connect to, e.g. localhost:3000",
+                        "type": "string"
+                    }
+                }
+            },
+            "matrix": {
+                "description": "Runs the steps for each combination of the values, available as ${matrix.NAME}",
+                "type": "object",
+                "properties": {
+                    "include": { "$ref": "#/$defs/matrixCombinations" },
+                    "exclude": { "$ref": "#/$defs/matrixCombinations" }
+                },
+                "additionalProperties": {
+                    "type": "array",
+                    "items": { "type": ["string", "number", "boolean"] }
+                }
+            },
+            "matrixCombinations": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "additionalProperties": { "type": ["string", "number", "boolean"] }
+                }
+            }
+        }
+    })
+}
+
+fn host_properties(name: Value) -> Value {
+    json!({
+        "name": name,
+        "base_dir": {
+            "description": "Directory on the host the code is sent under; `~` is the home directory",
+            "type": "string"
+        },
+        "proxy_jump": {
+            "description": "Hosts to go through, like ProxyJump in ~/.ssh/config",
+            "type": "string"
+        },
+        "proxy_command": {
+            "description": "Command whose stdio is used as the connection, like ProxyCommand in ~/.ssh/config",
+            "type": "string"
+        },
+        "connect_timeout": {
+            "description": "In seconds",
+            "type": "integer",
+            "minimum": 0
+        },
+        "connection_attempts": { "type": "integer", "minimum": 1 },
+        "address_family": { "enum": ["any", "inet", "inet6"] }
+    })
+}
@@ -1,32 +1,289 @@
-use std::{fs::File, io::BufReader};
+use std::{fs, io, path::PathBuf, time::Duration};
 
+use glob::Pattern;
 use ssh2_config::{HostParams, ParseRule};
 
-use crate::adapter::ssh::SSHSession;
+use crate::{
+    adapter::ssh::{proxy::Transport, Prompt, SSHSession},
+    config::{AddressFamily, ConfigError, TaskHost},
+    error::DifmError,
+};
 
 pub struct SSHConfig {
     hostname: String,
     config: HostParams,
+    proxy: Option<ProxyConfig>,
+    address_family: AddressFamily,
+}
+
+#[derive(Clone, Debug)]
+pub enum ProxyConfig {
+    Jump(Vec<String>),
+    Command(String),
+}
+
+impl ProxyConfig {
+    // `none` disables the proxy, just like in ssh_config
+    fn parse_jump(value: &str) -> Option<Self> {
+        (!value.eq_ignore_ascii_case("none"))
+            .then(|| Self::Jump(value.split(',').map(|hop| hop.trim().to_string()).collect()))
+    }
+
+    fn parse_command(value: &str) -> Option<Self> {
+        (!value.eq_ignore_ascii_case("none")).then(|| Self::Command(value.to_string()))
+    }
 }
 
 impl SSHConfig {
-    pub fn new(hostname: &str) -> Self {
-        let file = File::open("/Users/flisan/.ssh/config").unwrap();
-        let mut reader = BufReader::new(file);
+    pub fn new(hostname: &str) -> Result<Self, ConfigError> {
+        let path = ssh_config_path();
+        // Everything is left to the defaults without the file
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(ConfigError::Read(path, err)),
+        };
 
         let config = ssh2_config::SshConfig::default()
-            .parse(&mut reader, ParseRule::STRICT)
-            .unwrap();
+            .parse(&mut content.as_bytes(), ParseRule::ALLOW_UNKNOWN_FIELDS)
+            .map_err(|err| ConfigError::SshConfig(path.clone(), err.to_string()))?;
 
         let config = config.query(hostname);
 
-        Self {
+        // ssh2_config does not handle these, so they are looked up by ourselves
+        let proxy = match find_host_option(&content, hostname, "proxyjump") {
+            Some(jump) => ProxyConfig::parse_jump(&jump),
+            None => find_host_option(&content, hostname, "proxycommand")
+                .and_then(|command| ProxyConfig::parse_command(&command)),
+        };
+        let address_family = find_host_option(&content, hostname, "addressfamily")
+            .and_then(|family| parse_address_family(&family))
+            .unwrap_or_default();
+
+        Ok(Self {
             hostname: hostname.to_string(),
             config,
+            proxy,
+            address_family,
+        })
+    }
+
+    pub fn for_task_host(host: &TaskHost) -> Result<Self, ConfigError> {
+        let mut config = Self::new(&host.name)?;
+
+        if let Some(jump) = &host.proxy_jump {
+            config.proxy = ProxyConfig::parse_jump(jump);
+        } else if let Some(command) = &host.proxy_command {
+            config.proxy = ProxyConfig::parse_command(command);
+        }
+
+        if let Some(timeout) = host.connect_timeout {
+            config.config.connect_timeout = Some(Duration::from_secs(timeout));
+        }
+        if let Some(attempts) = host.connection_attempts {
+            config.config.connection_attempts = Some(attempts);
+        }
+        if let Some(family) = host.address_family {
+            config.address_family = family;
+        }
+
+        Ok(config)
+    }
+
+    // Accepts `[user@]host[:port]`, as ProxyJump does
+    fn for_jump_host(hop: &str) -> Result<Self, ConfigError> {
+        let (user, host) = match hop.split_once('@') {
+            Some((user, host)) => (Some(user), host),
+            None => (None, hop),
+        };
+        let (host, port) = match host.rsplit_once(':') {
+            Some((host, port)) if port.parse::<u16>().is_ok() => (host, port.parse().ok()),
+            _ => (host, None),
+        };
+
+        let mut config = Self::new(host)?;
+        if let Some(user) = user {
+            config.config.user = Some(user.to_string());
         }
+        if port.is_some() {
+            config.config.port = port;
+        }
+
+        Ok(config)
+    }
+
+    // Each jump host is connected through the previous one. Proxies configured for the jump hosts
+    // themselves are not taken into account.
+    pub async fn open(&self, prompt: Prompt<'_>) -> Result<SSHSession, DifmError> {
+        let transport = match &self.proxy {
+            None => Transport::Direct(self.address_family),
+            Some(ProxyConfig::Command(command)) => {
+                Transport::Command(self.expand_proxy_command(command))
+            }
+            Some(ProxyConfig::Jump(hops)) => {
+                let mut transport = None;
+                for hop in hops {
+                    let jump = Self::for_jump_host(hop)?;
+                    let through = transport.unwrap_or(Transport::Direct(jump.address_family));
+                    transport = Some(Transport::Jump(
+                        SSHSession::open(&jump.hostname, &jump.config, through, prompt).await?,
+                    ));
+                }
+
+                transport.unwrap_or(Transport::Direct(self.address_family))
+            }
+        };
+
+        SSHSession::open(&self.hostname, &self.config, transport, prompt).await
+    }
+
+    // What the session is connected to and how, so that the daemon does not share a session between
+    // the hosts named alike that differ, e.g. in their user or key
+    pub fn session_key(&self) -> String {
+        let proxy = match &self.proxy {
+            None => String::new(),
+            Some(ProxyConfig::Jump(hops)) => format!("jump {}", hops.join(",")),
+            Some(ProxyConfig::Command(command)) => format!("command {}", command),
+        };
+
+        format!(
+            "{}@{}:{} {:?} {:?} {}",
+            self.config.user.as_deref().unwrap_or(""),
+            self.config.host_name.as_deref().unwrap_or(&self.hostname),
+            self.config.port.unwrap_or(22),
+            self.config.identity_file.as_deref().unwrap_or_default(),
+            self.address_family,
+            proxy
+        )
     }
 
-    pub fn open(&self) -> SSHSession {
-        SSHSession::open(&self.hostname, &self.config)
+    fn expand_proxy_command(&self, command: &str) -> String {
+        let host = self.config.host_name.as_deref().unwrap_or(&self.hostname);
+        let port = self.config.port.unwrap_or(22).to_string();
+        let user = self.config.user.as_deref().unwrap_or("");
+
+        let mut expanded = String::new();
+        let mut chars = command.chars();
+        while let Some(char) = chars.next() {
+            if char != '%' {
+                expanded.push(char);
+                continue;
+            }
+
+            match chars.next() {
+                Some('h') => expanded.push_str(host),
+                Some('p') => expanded.push_str(&port),
+                Some('r') => expanded.push_str(user),
+                Some('%') => expanded.push('%'),
+                Some(other) => expanded.extend(['%', other]),
+                None => expanded.push('%'),
+            }
+        }
+
+        expanded
+    }
+}
+
+fn parse_address_family(value: &str) -> Option<AddressFamily> {
+    match value.to_ascii_lowercase().as_str() {
+        "any" => Some(AddressFamily::Any),
+        "inet" => Some(AddressFamily::Inet),
+        "inet6" => Some(AddressFamily::Inet6),
+        _ => None,
+    }
+}
+
+fn ssh_config_path() -> PathBuf {
+    let home = std::env::var("HOME").unwrap_or_default();
+    PathBuf::from(home).join(".ssh").join("config")
+}
+
+// The first obtained value is used, as ssh does. `Match` blocks are not supported and skipped.
+fn find_host_option(content: &str, hostname: &str, key: &str) -> Option<String> {
+    let mut matching = true;
+
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some((keyword, value)) = line.split_once(|c: char| c.is_whitespace() || c == '=')
+        else {
+            continue;
+        };
+        let value = value
+            .trim_start_matches(|c: char| c.is_whitespace() || c == '=')
+            .trim();
+
+        match keyword.to_ascii_lowercase().as_str() {
+            "host" => matching = host_matches(value, hostname),
+            "match" => matching = false,
+            keyword if matching && keyword == key => return Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+fn host_matches(patterns: &str, hostname: &str) -> bool {
+    let mut matched = false;
+
+    for pattern in patterns.split_whitespace() {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+
+        if Pattern::new(pattern).is_ok_and(|pattern| pattern.matches(hostname)) {
+            if negated {
+                return false;
+            }
+            matched = true;
+        }
+    }
+
+    matched
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(user: &str, port: u16) -> SSHConfig {
+        SSHConfig {
+            hostname: "box".to_string(),
+            config: HostParams {
+                user: Some(user.to_string()),
+                port: Some(port),
+                ..HostParams::default()
+            },
+            proxy: None,
+            address_family: AddressFamily::Any,
+        }
+    }
+
+    #[test]
+    fn tells_the_sessions_apart_by_how_they_connect() {
+        assert_eq!(
+            config("dev", 22).session_key(),
+            config("dev", 22).session_key()
+        );
+        assert_ne!(
+            config("dev", 22).session_key(),
+            config("ci", 22).session_key()
+        );
+        assert_ne!(
+            config("dev", 22).session_key(),
+            config("dev", 2222).session_key()
+        );
+
+        let mut keyed = config("dev", 22);
+        keyed.config.identity_file = Some(vec![PathBuf::from("~/.ssh/ci")]);
+        assert_ne!(keyed.session_key(), config("dev", 22).session_key());
+
+        let mut jumped = config("dev", 22);
+        jumped.proxy = ProxyConfig::parse_jump("bastion");
+        assert_ne!(jumped.session_key(), config("dev", 22).session_key());
     }
 }
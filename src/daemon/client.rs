@@ -0,0 +1,181 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use tokio::{net::UnixStream, sync::mpsc::UnboundedSender};
+
+use crate::{
+    adapter::ssh::{
+        exec::{ExecChannelCompleteInfo, ExecOutput, PtyRequest},
+        Prompt,
+    },
+    config::TaskHost,
+};
+
+use super::{
+    protocol::{read_frame, read_message, write_frame, write_message, Request, Response},
+    socket_path,
+};
+
+// A session held by the daemon. Each operation uses its own connection to the daemon,
+// so that they can run at the same time.
+#[derive(Clone)]
+pub struct DaemonClient {
+    socket: PathBuf,
+    session: String,
+}
+
+impl DaemonClient {
+    // Returns `None` if the daemon is not running or could not connect to the host
+    pub async fn connect(host: &TaskHost, prompt: Prompt<'_>) -> Option<Self> {
+        let socket = socket_path().ok()?;
+        let mut stream = UnixStream::connect(&socket).await.ok()?;
+
+        let request = Request::Connect { host: host.clone() };
+        write_message(&mut stream, &request).await.ok()?;
+
+        let session = loop {
+            match read_message(&mut stream).await.ok()? {
+                Response::Prompt { text, hidden } => {
+                    let text = tokio::task::block_in_place(|| prompt(hidden, &text)).ok()?;
+                    write_message(&mut stream, &Request::Answer { text })
+                        .await
+                        .ok()?;
+                }
+                Response::Connected { session } => break session,
+                Response::Error { message } => {
+                    eprintln!(
+                        "[!] The daemon could not connect to {}: {}",
+                        host.name, message
+                    );
+                    return None;
+                }
+                _ => return None,
+            }
+        };
+
+        Some(Self { socket, session })
+    }
+
+    // Without a daemon behind, for the tests of what a held session cannot do
+    #[cfg(test)]
+    pub fn detached(session: &str) -> Self {
+        Self {
+            socket: PathBuf::new(),
+            session: session.to_string(),
+        }
+    }
+
+    pub async fn exec(
+        &self,
+        command: &str,
+        pty: Option<&PtyRequest>,
+        sender: UnboundedSender<ExecOutput>,
+    ) -> ExecChannelCompleteInfo {
+        let mut stdout = String::new();
+        let mut stderr = String::new();
+
+        let request = Request::Exec {
+            session: self.session.clone(),
+            command: command.to_string(),
+            pty: pty.cloned(),
+        };
+        let mut stream = match self.open().await {
+            Ok(mut stream) => match write_message(&mut stream, &request).await {
+                Ok(()) => stream,
+                Err(_) => return interrupted(stdout, stderr),
+            },
+            Err(_) => return interrupted(stdout, stderr),
+        };
+
+        loop {
+            // The daemon has gone, taking the command with it
+            let Ok(response) = read_message(&mut stream).await else {
+                return interrupted(stdout, stderr);
+            };
+
+            match response {
+                Response::Stdout { chunk } => {
+                    stdout.push_str(&chunk);
+                    sender.send(ExecOutput::Stdout(chunk)).ok();
+                }
+                Response::Stderr { chunk } => {
+                    stderr.push_str(&chunk);
+                    sender.send(ExecOutput::Stderr(chunk)).ok();
+                }
+                Response::Exited { exit_code } => {
+                    return ExecChannelCompleteInfo {
+                        stdout,
+                        stderr,
+                        exit_code,
+                    }
+                }
+                Response::Error { message } => {
+                    eprintln!("[!] The daemon could not execute the command: {}", message);
+                    return interrupted(stdout, stderr);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub async fn transfer(&self, dest: &Path, content: &[u8]) -> io::Result<()> {
+        let mut stream = self.open().await?;
+
+        let request = Request::Transfer {
+            session: self.session.clone(),
+            dest: dest.to_path_buf(),
+        };
+        write_message(&mut stream, &request).await?;
+        write_frame(&mut stream, content).await?;
+
+        match read_message(&mut stream).await? {
+            Response::Done => Ok(()),
+            Response::Error { message } => Err(io::Error::other(message)),
+            response => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unexpected response from the daemon: {:?}", response),
+            )),
+        }
+    }
+
+    pub async fn fetch(&self, source: &Path) -> io::Result<Vec<u8>> {
+        let mut stream = self.open().await?;
+
+        let request = Request::Fetch {
+            session: self.session.clone(),
+            source: source.to_path_buf(),
+        };
+        write_message(&mut stream, &request).await?;
+
+        match read_message(&mut stream).await? {
+            Response::Fetched => read_frame(&mut stream).await,
+            Response::Error { message } => Err(io::Error::other(message)),
+            response => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unexpected response from the daemon: {:?}", response),
+            )),
+        }
+    }
+
+    async fn open(&self) -> io::Result<UnixStream> {
+        UnixStream::connect(&self.socket).await
+    }
+}
+
+fn interrupted(stdout: String, stderr: String) -> ExecChannelCompleteInfo {
+    ExecChannelCompleteInfo {
+        stdout,
+        stderr,
+        exit_code: None,
+    }
+}
+
+// For the requests that are not bound to a host, e.g. `status` or `stop`
+pub async fn request(request: &Request) -> io::Result<Response> {
+    let mut stream = UnixStream::connect(socket_path()?).await?;
+    write_message(&mut stream, request).await?;
+
+    read_message(&mut stream).await
+}
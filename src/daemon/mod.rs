@@ -0,0 +1,40 @@
+use std::{
+    fs::{self, DirBuilder},
+    io,
+    os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+    path::PathBuf,
+    time::Duration,
+};
+
+pub mod client;
+pub mod protocol;
+pub mod server;
+
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+// The socket lives in a directory only the user can access, so that nobody else can borrow
+// the authenticated sessions.
+pub fn socket_path() -> io::Result<PathBuf> {
+    let uid = unsafe { libc::getuid() };
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) => PathBuf::from(runtime_dir).join("difm"),
+        None => std::env::temp_dir().join(format!("difm-{}", uid)),
+    };
+
+    if !dir.exists() {
+        DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    }
+
+    let metadata = fs::metadata(&dir)?;
+    if metadata.uid() != uid || metadata.permissions().mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} should be owned by the current user and not accessible by others",
+                dir.display()
+            ),
+        ));
+    }
+
+    Ok(dir.join("daemon.sock"))
+}
@@ -0,0 +1,94 @@
+use std::{io, path::PathBuf};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{adapter::ssh::exec::PtyRequest, config::TaskHost};
+
+// Every message is a length-prefixed frame. Requests and responses are JSON, while the content
+// of a transfer follows its request (or the response, for a fetch) as a raw frame.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    Connect {
+        host: TaskHost,
+    },
+    Exec {
+        session: String,
+        command: String,
+        pty: Option<PtyRequest>,
+    },
+    Transfer {
+        session: String,
+        dest: PathBuf,
+    },
+    Fetch {
+        session: String,
+        source: PathBuf,
+    },
+    Answer {
+        text: String,
+    },
+    Status,
+    Stop,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Prompt { text: String, hidden: bool },
+    // `session` names the session in the next requests
+    Connected { session: String },
+    Stdout { chunk: String },
+    Stderr { chunk: String },
+    Exited { exit_code: Option<u8> },
+    Done,
+    // Followed by the content as a raw frame
+    Fetched,
+    Status(DaemonStatus),
+    Error { message: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DaemonStatus {
+    pub pid: u32,
+    pub uptime_secs: u64,
+    pub idle_timeout_secs: u64,
+    pub sessions: Vec<SessionStatus>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionStatus {
+    pub host: String,
+    pub idle_secs: u64,
+}
+
+pub async fn write_frame(stream: &mut (impl AsyncWrite + Unpin), content: &[u8]) -> io::Result<()> {
+    let length = u32::try_from(content.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "The frame is too large"))?;
+
+    stream.write_all(&length.to_be_bytes()).await?;
+    stream.write_all(content).await?;
+    stream.flush().await
+}
+
+pub async fn read_frame(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Vec<u8>> {
+    let length = stream.read_u32().await?;
+    let mut content = vec![0; length as usize];
+    stream.read_exact(&mut content).await?;
+
+    Ok(content)
+}
+
+pub async fn write_message(
+    stream: &mut (impl AsyncWrite + Unpin),
+    message: &impl Serialize,
+) -> io::Result<()> {
+    write_frame(stream, &serde_json::to_vec(message)?).await
+}
+
+pub async fn read_message<T: DeserializeOwned>(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> io::Result<T> {
+    Ok(serde_json::from_slice(&read_frame(stream).await?)?)
+}
@@ -0,0 +1,356 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    net::{UnixListener, UnixStream},
+    runtime::Handle,
+    sync::{Mutex, Notify},
+};
+
+use crate::{
+    adapter::ssh::{
+        exec::{ExecChannel, ExecOutput},
+        transfer::{receive_content, transfer_content, FileTransferError},
+        SSHSession,
+    },
+    config::ssh::SSHConfig,
+    error::DifmError,
+};
+
+use super::{
+    client,
+    protocol::{
+        read_frame, read_message, write_frame, write_message, DaemonStatus, Request, Response,
+        SessionStatus,
+    },
+    socket_path,
+};
+
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+struct DaemonState {
+    sessions: Mutex<HashMap<String, HostSession>>,
+    // Held while a session is opened, so that the clients asking at once share it
+    connecting: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    started_at: Instant,
+    last_activity: std::sync::Mutex<Instant>,
+    active_connections: AtomicUsize,
+    idle_timeout: Duration,
+    stop: Notify,
+}
+
+// Keyed by `SSHConfig::session_key`
+struct HostSession {
+    host: String,
+    session: SSHSession,
+    last_used: Instant,
+}
+
+pub async fn serve(idle_timeout: Duration) -> Result<(), DifmError> {
+    let failed = |action: &str| {
+        let action = action.to_string();
+        move |source| DifmError::Daemon { action, source }
+    };
+    let path = socket_path().map_err(failed("prepare the socket of the daemon"))?;
+
+    if path.exists() {
+        if client::request(&Request::Status).await.is_ok() {
+            eprintln!("[!] The daemon is already running");
+            return Ok(());
+        }
+        fs::remove_file(&path).map_err(failed("remove the stale socket of the daemon"))?;
+    }
+
+    let listener =
+        UnixListener::bind(&path).map_err(failed("listen on the socket of the daemon"))?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+        .map_err(failed("restrict the socket of the daemon"))?;
+
+    let state = Arc::new(DaemonState {
+        sessions: Mutex::new(HashMap::new()),
+        connecting: Mutex::new(HashMap::new()),
+        started_at: Instant::now(),
+        last_activity: std::sync::Mutex::new(Instant::now()),
+        active_connections: AtomicUsize::new(0),
+        idle_timeout,
+        stop: Notify::new(),
+    });
+
+    tokio::spawn(watch_idle(state.clone()));
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let Ok((stream, _)) = accepted else {
+                    continue;
+                };
+                tokio::spawn(handle(stream, state.clone()));
+            }
+            _ = state.stop.notified() => break,
+        }
+    }
+
+    fs::remove_file(&path).ok();
+
+    Ok(())
+}
+
+async fn watch_idle(state: Arc<DaemonState>) {
+    loop {
+        tokio::time::sleep(IDLE_CHECK_INTERVAL).await;
+
+        state
+            .sessions
+            .lock()
+            .await
+            .retain(|_, session| session.last_used.elapsed() < state.idle_timeout);
+
+        let idle = state.last_activity.lock().unwrap().elapsed() >= state.idle_timeout;
+        if idle && state.active_connections.load(Ordering::SeqCst) == 0 {
+            state.stop.notify_one();
+            return;
+        }
+    }
+}
+
+async fn handle(mut stream: UnixStream, state: Arc<DaemonState>) {
+    let same_user = stream
+        .peer_cred()
+        .is_ok_and(|cred| cred.uid() == unsafe { libc::getuid() });
+    if !same_user {
+        return;
+    }
+
+    state.active_connections.fetch_add(1, Ordering::SeqCst);
+
+    if let Ok(request) = read_message::<Request>(&mut stream).await {
+        *state.last_activity.lock().unwrap() = Instant::now();
+
+        let result = match request {
+            Request::Connect { host } => {
+                let stream = Arc::new(Mutex::new(stream));
+                let response = connect(&state, &host, stream.clone()).await;
+                let mut stream = stream.lock().await;
+                write_message(&mut *stream, &response).await
+            }
+            Request::Exec {
+                session: key,
+                command,
+                pty,
+            } => match session_for(&state, &key).await {
+                Some(session) => {
+                    let generation = session.generation();
+                    let mut channel = ExecChannel::with_pty(&session, &command, pty.as_ref()).await;
+                    let mut result = Ok(());
+
+                    while let Some(output) = channel.next_output().await {
+                        let response = match output {
+                            ExecOutput::Stdout(chunk) => Response::Stdout { chunk },
+                            ExecOutput::Stderr(chunk) => Response::Stderr { chunk },
+                        };
+                        result = result.and(write_message(&mut stream, &response).await);
+                    }
+
+                    let exit_code = channel.wait_done().await.exit_code;
+                    let result = result
+                        .and(write_message(&mut stream, &Response::Exited { exit_code }).await);
+
+                    // Ready for the next request of the client
+                    if exit_code.is_none() {
+                        session.reconnect(generation).await.ok();
+                    }
+
+                    result
+                }
+                None => write_message(&mut stream, &not_connected(&key)).await,
+            },
+            Request::Transfer { session: key, dest } => match read_frame(&mut stream).await {
+                Ok(content) => match session_for(&state, &key).await {
+                    Some(session) => {
+                        let response = match transfer(&session, &content, &dest).await {
+                            Ok(()) => Response::Done,
+                            Err(err) => Response::Error {
+                                message: err.to_string(),
+                            },
+                        };
+                        write_message(&mut stream, &response).await
+                    }
+                    None => write_message(&mut stream, &not_connected(&key)).await,
+                },
+                Err(err) => Err(err),
+            },
+            Request::Fetch {
+                session: key,
+                source,
+            } => match session_for(&state, &key).await {
+                Some(session) => match fetch(&session, &source).await {
+                    Ok(content) => match write_message(&mut stream, &Response::Fetched).await {
+                        Ok(()) => write_frame(&mut stream, &content).await,
+                        Err(err) => Err(err),
+                    },
+                    Err(err) => {
+                        let response = Response::Error {
+                            message: err.to_string(),
+                        };
+                        write_message(&mut stream, &response).await
+                    }
+                },
+                None => write_message(&mut stream, &not_connected(&key)).await,
+            },
+            Request::Status => {
+                let sessions = state
+                    .sessions
+                    .lock()
+                    .await
+                    .values()
+                    .map(|session| SessionStatus {
+                        host: session.host.clone(),
+                        idle_secs: session.last_used.elapsed().as_secs(),
+                    })
+                    .collect();
+
+                let status = DaemonStatus {
+                    pid: std::process::id(),
+                    uptime_secs: state.started_at.elapsed().as_secs(),
+                    idle_timeout_secs: state.idle_timeout.as_secs(),
+                    sessions,
+                };
+                write_message(&mut stream, &Response::Status(status)).await
+            }
+            Request::Stop => {
+                state.stop.notify_one();
+                write_message(&mut stream, &Response::Done).await
+            }
+            Request::Answer { .. } => Ok(()),
+        };
+
+        if let Err(err) = result {
+            eprintln!("[!] Could not respond to the client: {}", err);
+        }
+    }
+
+    state.active_connections.fetch_sub(1, Ordering::SeqCst);
+}
+
+// Prompts for the credentials are relayed to the client, since the daemon has no terminal
+async fn connect(
+    state: &DaemonState,
+    host: &crate::config::TaskHost,
+    stream: Arc<Mutex<UnixStream>>,
+) -> Response {
+    let config = match SSHConfig::for_task_host(host) {
+        Ok(config) => config,
+        Err(err) => {
+            return Response::Error {
+                message: err.to_string(),
+            }
+        }
+    };
+    let key = config.session_key();
+    let connecting = state
+        .connecting
+        .lock()
+        .await
+        .entry(key.clone())
+        .or_default()
+        .clone();
+    let _connecting = connecting.lock().await;
+    if session_for(state, &key).await.is_some() {
+        return Response::Connected { session: key };
+    }
+
+    let prompt = move |hidden: bool, text: &str| {
+        Handle::current().block_on(async {
+            let mut stream = stream.lock().await;
+            let prompt = Response::Prompt {
+                text: text.to_string(),
+                hidden,
+            };
+            write_message(&mut *stream, &prompt).await?;
+
+            match read_message(&mut *stream).await? {
+                Request::Answer { text } => Ok(text),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "The client did not answer the prompt",
+                )),
+            }
+        })
+    };
+
+    let session = match config.open(&prompt).await {
+        Ok(session) => session,
+        Err(err) => {
+            return Response::Error {
+                message: err.to_string(),
+            }
+        }
+    };
+
+    state.sessions.lock().await.insert(
+        key.clone(),
+        HostSession {
+            host: host.name.clone(),
+            session,
+            last_used: Instant::now(),
+        },
+    );
+
+    Response::Connected { session: key }
+}
+
+// The client cannot reconnect the session held by us, so it is done here
+async fn transfer(
+    session: &SSHSession,
+    content: &[u8],
+    dest: &Path,
+) -> Result<(), FileTransferError> {
+    let generation = session.generation();
+    match transfer_content(session, content, dest, &mut |_| {}).await {
+        Err(FileTransferError::Disconnected(_)) => {
+            session
+                .reconnect(generation)
+                .await
+                .map_err(FileTransferError::Disconnected)?;
+            transfer_content(session, content, dest, &mut |_| {}).await
+        }
+        result => result,
+    }
+}
+
+async fn fetch(session: &SSHSession, source: &Path) -> Result<Vec<u8>, FileTransferError> {
+    let generation = session.generation();
+    match receive_content(session, source).await {
+        Err(FileTransferError::Disconnected(_)) => {
+            session
+                .reconnect(generation)
+                .await
+                .map_err(FileTransferError::Disconnected)?;
+            receive_content(session, source).await
+        }
+        result => result,
+    }
+}
+
+async fn session_for(state: &DaemonState, key: &str) -> Option<SSHSession> {
+    let mut sessions = state.sessions.lock().await;
+    let session = sessions.get_mut(key)?;
+    session.last_used = Instant::now();
+
+    Some(session.session.shared_clone())
+}
+
+fn not_connected(key: &str) -> Response {
+    Response::Error {
+        message: format!("The daemon is not connected to {}", key),
+    }
+}
@@ -0,0 +1,171 @@
+use std::{fmt::Display, io};
+
+use crate::{
+    adapter::ssh::transfer::FileTransferError, config::ConfigError,
+    remote::integrity::IntegrityError,
+};
+
+// Exit codes of the process, one per kind of the error
+pub const EXIT_STEP_FAILED: i32 = 1;
+// The same as clap's
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_CONFIG: i32 = 3;
+pub const EXIT_CONNECTION: i32 = 4;
+pub const EXIT_AUTH: i32 = 5;
+pub const EXIT_HOST_KEY: i32 = 6;
+pub const EXIT_TRANSFER: i32 = 7;
+pub const EXIT_EXEC: i32 = 8;
+pub const EXIT_INTEGRITY: i32 = 9;
+pub const EXIT_DAEMON: i32 = 10;
+
+#[derive(Debug)]
+pub enum DifmError {
+    Usage(String),
+    Config(ConfigError),
+    Connection {
+        host: String,
+        source: io::Error,
+    },
+    // Also if the password could not be read, e.g. without a terminal
+    Auth {
+        host: String,
+        user: String,
+        source: io::Error,
+    },
+    HostKey {
+        host: String,
+        known_hosts: String,
+    },
+    Transfer {
+        host: String,
+        action: String,
+        source: FileTransferError,
+    },
+    // The command could not be run to the end, rather than it has failed
+    Exec {
+        host: String,
+        action: String,
+        source: io::Error,
+    },
+    Integrity {
+        host: String,
+        source: IntegrityError,
+    },
+    // e.g. the socket could not be bound, or the daemon started did not respond
+    Daemon {
+        action: String,
+        source: io::Error,
+    },
+    // The steps have run, but some of them have failed
+    StepFailed(String),
+}
+
+impl DifmError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            DifmError::Usage(_) => EXIT_USAGE,
+            DifmError::Config(_) => EXIT_CONFIG,
+            DifmError::Connection { .. } => EXIT_CONNECTION,
+            DifmError::Auth { .. } => EXIT_AUTH,
+            DifmError::HostKey { .. } => EXIT_HOST_KEY,
+            DifmError::Transfer { .. } => EXIT_TRANSFER,
+            DifmError::Exec { .. } => EXIT_EXEC,
+            DifmError::Integrity { .. } => EXIT_INTEGRITY,
+            DifmError::Daemon { .. } => EXIT_DAEMON,
+            DifmError::StepFailed(_) => EXIT_STEP_FAILED,
+        }
+    }
+
+    pub fn hint(&self) -> Option<String> {
+        let hint = match self {
+            DifmError::Usage(_) => return None,
+            DifmError::Config(ConfigError::Invalid(..)) => {
+                "Run `difm check` for all the problems of the task definition".to_string()
+            }
+            DifmError::Config(ConfigError::SshConfig(..)) => {
+                "ssh_config(5) describes the format of the file".to_string()
+            }
+            DifmError::Config(_) => return None,
+            DifmError::Connection { host, .. } => format!(
+                "Check that {} is reachable, or tune `connect_timeout` / `connection_attempts` of the host",
+                host
+            ),
+            DifmError::Auth { .. } => {
+                "Check the user (`User` in ~/.ssh/config) and the password".to_string()
+            }
+            DifmError::HostKey { host, .. } => format!(
+                "If the host has been reinstalled, remove the old key with `ssh-keygen -R {}`",
+                host
+            ),
+            DifmError::Transfer {
+                source: FileTransferError::Read(_),
+                ..
+            } => "Check the permissions of the local files".to_string(),
+            DifmError::Transfer { .. } => {
+                "Check the permissions and the free space under `base_dir` on the host".to_string()
+            }
+            DifmError::Exec { .. } => return None,
+            DifmError::Integrity {
+                source: IntegrityError::Local(..),
+                ..
+            } => "Check the permissions of the local files".to_string(),
+            DifmError::Integrity { .. } => {
+                "`sha256sum` is needed on the host to tell the changed files".to_string()
+            }
+            DifmError::Daemon { .. } => {
+                "Check the permissions of $XDG_RUNTIME_DIR/difm, or pass --no-daemon".to_string()
+            }
+            DifmError::StepFailed(_) => return None,
+        };
+
+        Some(hint)
+    }
+
+    pub fn report(&self) {
+        eprintln!("[!] {}", self);
+        if let Some(hint) = self.hint() {
+            eprintln!("    hint: {}", hint);
+        }
+    }
+}
+
+impl Display for DifmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DifmError::Usage(message) => write!(f, "{}", message),
+            DifmError::Config(err) => write!(f, "{}", err),
+            DifmError::Connection { host, source } => {
+                write!(f, "Could not connect to {}: {}", host, source)
+            }
+            DifmError::Auth { host, user, source } => {
+                write!(f, "Could not log in to {} as {}: {}", host, user, source)
+            }
+            DifmError::HostKey { host, known_hosts } => write!(
+                f,
+                "The host key of {} does not match the one in {}. Someone may be impersonating the host!",
+                host, known_hosts
+            ),
+            DifmError::Transfer {
+                host,
+                action,
+                source,
+            } => write!(f, "Could not {} on {}: {}", action, host, source),
+            DifmError::Exec {
+                host,
+                action,
+                source,
+            } => write!(f, "Could not {} on {}: {}", action, host, source),
+            DifmError::Integrity { host, source } => {
+                write!(f, "Could not tell the changed files on {}: {}", host, source)
+            }
+            DifmError::Daemon { action, source } => write!(f, "Could not {}: {}", action, source),
+            DifmError::StepFailed(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<ConfigError> for DifmError {
+    fn from(err: ConfigError) -> Self {
+        DifmError::Config(err)
+    }
+}
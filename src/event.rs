@@ -0,0 +1,496 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    io::{stdout, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::{
+    adapter::{fs::EntryType, ssh::exec::ExecOutput},
+    config::matrix::MatrixVariant,
+    progress::{self, Verbosity, ESEQ_GREEN, ESEQ_RED, ESEQ_RESET, ESEQ_WEAK},
+    remote::task::TaskRunStatus,
+    util::indent_str,
+};
+
+// Bumped whenever a field is removed or changes its meaning; new events and fields may be added
+// without bumping it, so the consumers should ignore what they do not know
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Human,
+    // Newline-delimited JSON events on stdout, see README.md for the schema
+    Json,
+}
+
+static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+
+pub fn init(format: OutputFormat) {
+    FORMAT.set(format).ok();
+}
+
+pub fn is_json() -> bool {
+    FORMAT.get() == Some(&OutputFormat::Json)
+}
+
+tokio::task_local! {
+    static SCOPE: EventScope;
+}
+
+// Which host and matrix variant the events emitted in the scope are about
+#[derive(Clone, Debug, Default)]
+pub struct EventScope {
+    host: Option<String>,
+    variant: Option<BTreeMap<String, String>>,
+}
+
+impl EventScope {
+    pub fn host(name: &str) -> Self {
+        Self {
+            host: Some(name.to_string()),
+            variant: None,
+        }
+    }
+
+    pub fn with_variant(&self, variant: &MatrixVariant) -> Self {
+        Self {
+            host: self.host.clone(),
+            variant: Some(variant.values().iter().cloned().collect()),
+        }
+    }
+
+    pub fn current() -> Self {
+        SCOPE.try_with(Clone::clone).unwrap_or_default()
+    }
+
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        SCOPE.scope(self, future).await
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl From<&ExecOutput> for OutputStream {
+    fn from(output: &ExecOutput) -> Self {
+        match output {
+            ExecOutput::Stdout(_) => OutputStream::Stdout,
+            ExecOutput::Stderr(_) => OutputStream::Stderr,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Connected {
+        daemon: bool,
+    },
+    HashStarted {
+        files: usize,
+    },
+    HashFinished {
+        changed: usize,
+        duration_ms: u64,
+    },
+    TransferStarted {
+        files: usize,
+        bytes: u64,
+    },
+    FileSent {
+        path: PathBuf,
+        kind: EntryType,
+        bytes: u64,
+    },
+    TransferFinished {
+        files: usize,
+        bytes: u64,
+        duration_ms: u64,
+    },
+    StepStarted {
+        step: String,
+        // The output is then stdout only, with stderr merged into it
+        tty: bool,
+    },
+    Output {
+        step: String,
+        stream: OutputStream,
+        text: String,
+    },
+    StepFinished {
+        step: String,
+        status: TaskRunStatus,
+        exit_code: Option<u8>,
+        duration_ms: u64,
+        // The `if:` of the step
+        condition: Option<String>,
+    },
+    ArtifactFetched {
+        remote_path: PathBuf,
+        local_path: PathBuf,
+        duration_ms: u64,
+    },
+    // Told by `--dry-run` instead of doing them
+    PlannedUpload {
+        path: PathBuf,
+        bytes: u64,
+    },
+    PlannedStep {
+        step: String,
+        command: String,
+        cwd: PathBuf,
+        env: BTreeMap<String, String>,
+        condition: Option<String>,
+        // Whether the step would run if all of the steps before pass
+        would_run: bool,
+    },
+    PlannedArtifact {
+        remote_path: PathBuf,
+        local_path: PathBuf,
+    },
+    RunFinished {
+        success: bool,
+        exit_code: i32,
+        error: Option<String>,
+        duration_ms: u64,
+    },
+}
+
+// An event along with where and when it happened, as written to the JSON lines
+#[derive(Serialize)]
+pub struct Record<'a> {
+    pub version: u32,
+    pub timestamp_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variant: Option<&'a BTreeMap<String, String>>,
+    #[serde(flatten)]
+    pub event: &'a Event,
+}
+
+// Anything built from the events besides the output, e.g. the reports
+pub trait Listener: Send + Sync {
+    fn on_event(&self, record: &Record);
+}
+
+static LISTENERS: Mutex<Vec<Arc<dyn Listener>>> = Mutex::new(Vec::new());
+
+pub fn subscribe(listener: Arc<dyn Listener>) {
+    LISTENERS.lock().unwrap().push(listener);
+}
+
+// Every event goes through here, whether it ends up as a JSON line or as the human output
+pub fn emit(event: Event) {
+    let scope = EventScope::current();
+    let record = Record {
+        version: SCHEMA_VERSION,
+        timestamp_ms: now_ms(),
+        host: scope.host.as_deref(),
+        variant: scope.variant.as_ref(),
+        event: &event,
+    };
+
+    for listener in LISTENERS.lock().unwrap().iter() {
+        listener.on_event(&record);
+    }
+
+    if !is_json() {
+        return render_human(&record);
+    }
+
+    // A line is written at once, so the events of the hosts worked on in parallel do not mix
+    if let Ok(line) = serde_json::to_string(&record) {
+        let mut stdout = stdout().lock();
+        writeln!(stdout, "{}", line).ok();
+        stdout.flush().ok();
+    }
+}
+
+// The output of the running steps, printed at once when the step finishes so that the output of
+// the steps running in parallel does not mix. The output from a PTY is printed line by line
+// instead, as it is usually meant to be watched, e.g. a progress bar.
+#[derive(Default)]
+struct StepOutput {
+    tty: bool,
+    streamed: bool,
+    stdout: String,
+    stderr: String,
+}
+
+impl StepOutput {
+    // The lines not ended yet are kept for the next chunk, unless the step is finished
+    fn take_lines(&mut self, finished: bool) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Some(end) = self.stdout.find('\n') {
+            lines.push(self.stdout.drain(..=end).collect::<String>());
+        }
+        if finished && !self.stdout.is_empty() {
+            lines.push(std::mem::take(&mut self.stdout));
+        }
+
+        // A line redrawn with `\r` is shown as it was left
+        lines
+            .iter()
+            .map(|line| {
+                let line = line.trim_end_matches(['\n', '\r']);
+                line.rsplit('\r').next().unwrap_or_default().to_string()
+            })
+            .collect()
+    }
+
+    fn print_lines(&mut self, step: &str, finished: bool) {
+        for line in self.take_lines(finished) {
+            progress::report(&format!(
+                "{ESEQ_WEAK}{} |{ESEQ_RESET}  {}{ESEQ_RESET}",
+                step, line
+            ));
+        }
+    }
+}
+
+type StepKey = (Option<String>, Option<BTreeMap<String, String>>, String);
+
+static STEP_OUTPUTS: Mutex<BTreeMap<StepKey, StepOutput>> = Mutex::new(BTreeMap::new());
+
+// The progress views only draw what is going on; everything left on the screen is printed here
+fn render_human(record: &Record) {
+    let step_key = |step: &str| -> StepKey {
+        (
+            record.host.map(str::to_string),
+            record.variant.cloned(),
+            step.to_string(),
+        )
+    };
+
+    match record.event {
+        Event::Connected { daemon: false } => progress::report("✅ Connected to the remote server"),
+        Event::Connected { daemon: true } => {
+            progress::report("✅ Connected to the remote server through the daemon")
+        }
+        Event::HashFinished { changed: 0, .. } => {
+            progress::report("No files is required to be send")
+        }
+        Event::TransferStarted { files, bytes } => progress::report_started(&format!(
+            "Transferring {} files, {}",
+            files,
+            progress::format_bytes(*bytes)
+        )),
+        Event::FileSent { path, bytes, .. } => progress::report_at(
+            Verbosity::Verbose,
+            &format!(
+                "  + {} {ESEQ_WEAK}({}){ESEQ_RESET}",
+                path.display(),
+                progress::format_bytes(*bytes)
+            ),
+        ),
+        Event::TransferFinished {
+            files,
+            bytes,
+            duration_ms,
+        } => {
+            let elapsed = Duration::from_millis(*duration_ms);
+            progress::report(&format!(
+                "{ESEQ_GREEN}✓ Transferring files - Sent {} files, {} in {}{}{ESEQ_RESET}",
+                files,
+                progress::format_bytes(*bytes),
+                progress::format_duration(elapsed),
+                progress::throughput(*bytes, elapsed)
+                    .map(|rate| format!(" ({}/s)", progress::format_bytes(rate)))
+                    .unwrap_or_default()
+            ))
+        }
+        Event::StepStarted { step, tty } => {
+            STEP_OUTPUTS.lock().unwrap().insert(
+                step_key(step),
+                StepOutput {
+                    tty: *tty,
+                    streamed: *tty && progress::verbosity() >= Verbosity::Normal,
+                    ..Default::default()
+                },
+            );
+            progress::report_started(&format!("Running task: {}", step));
+        }
+        Event::Output { step, stream, text } => {
+            let mut outputs = STEP_OUTPUTS.lock().unwrap();
+            let output = outputs.entry(step_key(step)).or_default();
+            match stream {
+                OutputStream::Stdout => output.stdout.push_str(text),
+                OutputStream::Stderr => output.stderr.push_str(text),
+            }
+            if output.streamed {
+                output.print_lines(step, false);
+            }
+        }
+        Event::StepFinished {
+            step,
+            status,
+            condition,
+            ..
+        } => {
+            let mut output = STEP_OUTPUTS
+                .lock()
+                .unwrap()
+                .remove(&step_key(step))
+                .unwrap_or_default();
+            if output.streamed {
+                output.print_lines(step, true);
+            }
+            render_step(step, *status, condition.as_deref(), &output);
+        }
+        Event::ArtifactFetched {
+            remote_path,
+            local_path,
+            ..
+        } => progress::report(&format!(
+            "{ESEQ_GREEN}✓ Fetching {} - {}{ESEQ_RESET}",
+            remote_path.display(),
+            local_path.display()
+        )),
+        Event::PlannedUpload { path, bytes } => progress::report(&format!(
+            "  + {} {ESEQ_WEAK}({}){ESEQ_RESET}",
+            path.display(),
+            progress::format_bytes(*bytes)
+        )),
+        Event::PlannedStep {
+            step,
+            command,
+            env,
+            condition,
+            would_run,
+            ..
+        } => {
+            let condition = condition
+                .as_ref()
+                .map(|condition| format!(" (if: {})", condition))
+                .unwrap_or_default();
+            match would_run {
+                true => progress::report(&format!("  - {}{}", step, condition)),
+                false => progress::report(&format!(
+                    "  {ESEQ_WEAK}- {}{} - skipped even if the steps before pass{ESEQ_RESET}",
+                    step, condition
+                )),
+            }
+            for (name, value) in env {
+                progress::report(&format!(
+                    "      {ESEQ_WEAK}env: {}={}{ESEQ_RESET}",
+                    name, value
+                ));
+            }
+            progress::report(&format!("      $ {}", command));
+        }
+        Event::PlannedArtifact {
+            remote_path,
+            local_path,
+        } => progress::report(&format!(
+            "  {} -> {}",
+            remote_path.display(),
+            local_path.display()
+        )),
+        _ => {}
+    }
+}
+
+// Only the failures are shown with `-q`
+fn render_step(step: &str, status: TaskRunStatus, condition: Option<&str>, output: &StepOutput) {
+    let status_line = match status {
+        TaskRunStatus::Skipped => {
+            let condition = condition
+                .map(|condition| format!(" (if: {})", condition))
+                .unwrap_or_default();
+            return progress::report(&format!(
+                "\n{ESEQ_WEAK}- Skipped task: {}{}{ESEQ_RESET}",
+                step, condition
+            ));
+        }
+        TaskRunStatus::Success => {
+            format!("{ESEQ_GREEN}✓ Running task: {} - done{ESEQ_RESET}", step)
+        }
+        TaskRunStatus::Failure(code) => format!(
+            "{ESEQ_RED}! Running task: {} - Exited with code {}{ESEQ_RESET}",
+            step, code
+        ),
+        TaskRunStatus::Interrupted => format!(
+            "{ESEQ_RED}! Running task: {} - Interrupted, as the connection was lost{ESEQ_RESET}",
+            step
+        ),
+    };
+
+    let level = match status {
+        TaskRunStatus::Success => Verbosity::Normal,
+        _ => Verbosity::Quiet,
+    };
+    if output.streamed {
+        return progress::report_at(level, &status_line);
+    }
+
+    // Output from a PTY already has its own colors, and stderr is merged into it
+    let text = match output.tty {
+        true => [
+            status_line,
+            "\x1b[1m----- Output (tty) -----\x1b[m".to_string(),
+            format!(
+                "{}{ESEQ_RESET}",
+                indent_str(&output.stdout.replace("\r\n", "\n"), 1)
+            ),
+            "\x1b[1m----- End of Output -----\x1b[m".to_string(),
+        ]
+        .join("\n"),
+        false => [
+            status_line,
+            "\x1b[1m----- Standard output -----\x1b[m".to_string(),
+            format!("\x1b[38;5;14m{}\x1b[m", indent_str(&output.stdout, 1)),
+            "\x1b[1m----- Standard Error  -----\x1b[m".to_string(),
+            format!("\x1b[38;5;11m{}\x1b[m", indent_str(&output.stderr, 1)),
+            "\x1b[1m----- End of Standard output -----\x1b[m".to_string(),
+        ]
+        .join("\n"),
+    };
+
+    progress::report_at(level, &format!("\n{}", text));
+}
+
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+// Since the Unix epoch
+pub fn now_ms() -> u64 {
+    millis(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_the_finished_lines_of_the_pty() {
+        let mut output = StepOutput {
+            stdout: "first\r\nsecond\r\n".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(output.take_lines(false), ["first", "second"]);
+
+        output.stdout.push_str("10%\r50%");
+        assert!(output.take_lines(false).is_empty());
+        output.stdout.push_str("\r100%\r\ndone");
+        assert_eq!(output.take_lines(false), ["100%"]);
+        assert_eq!(output.take_lines(true), ["done"]);
+        assert!(output.take_lines(true).is_empty());
+    }
+}
@@ -0,0 +1,490 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    adapter::fs::EntryType,
+    config::{ConfigContext, Configuration, TaskHistory},
+    event::{self, now_ms, Event, Listener, Record},
+};
+
+const METADATA_FILE: &str = "run.json";
+const CHANGED_FILES: &str = "changed.txt";
+const LOGS_DIR: &str = "logs";
+
+// Each run is stored in a directory named by its UUID v7, so that they sort by the time started
+pub fn history_dir(config_file: &Path) -> PathBuf {
+    config_file
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+        .join(".difm")
+        .join("runs")
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RunMetadata {
+    pub id: String,
+    pub alias: Option<String>,
+    pub args: Vec<String>,
+    pub started_ms: u64,
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    #[serde(default)]
+    pub steps: Vec<StepRecord>,
+    // Missing while running, or if difm was killed
+    #[serde(default)]
+    pub finished: Option<RunOutcome>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RunOutcome {
+    pub success: bool,
+    pub exit_code: i32,
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StepRecord {
+    pub host: String,
+    pub variant: Option<BTreeMap<String, String>>,
+    pub step: String,
+    // `running` until the step finishes
+    pub status: String,
+    pub exit_code: Option<u8>,
+    pub duration_ms: u64,
+    // Relative to the directory of the run. Missing for the skipped steps.
+    pub log: Option<PathBuf>,
+}
+
+// Records the run as its events arrive, so that whatever was done is kept even if difm is killed
+pub struct RunHistory {
+    dir: PathBuf,
+    state: Mutex<HistoryState>,
+}
+
+struct HistoryState {
+    metadata: RunMetadata,
+    logs: HashMap<PathBuf, File>,
+    changed: Option<File>,
+}
+
+impl RunHistory {
+    // Failing to record the run is warned about, but does not stop the run
+    pub fn start(config_ctx: &ConfigContext) -> Option<Arc<Self>> {
+        let Configuration::TaskDefinition(task) = &config_ctx.config;
+        if !task.history.enabled {
+            return None;
+        }
+
+        let id = Uuid::now_v7().to_string();
+        let dir = history_dir(&config_ctx.config_file).join(&id);
+        if let Err(err) = fs::create_dir_all(&dir) {
+            eprintln!("[!] Could not record the run in {}: {}", dir.display(), err);
+            return None;
+        }
+
+        let state = HistoryState {
+            metadata: RunMetadata {
+                id,
+                alias: task.alias.clone(),
+                args: redact_args(std::env::args()),
+                started_ms: now_ms(),
+                hosts: Vec::new(),
+                steps: Vec::new(),
+                finished: None,
+            },
+            logs: HashMap::new(),
+            changed: None,
+        };
+        state.save(&dir);
+
+        let history = Arc::new(Self {
+            dir,
+            state: Mutex::new(state),
+        });
+        event::subscribe(history.clone());
+
+        Some(history)
+    }
+
+    pub fn id(&self) -> String {
+        self.state.lock().unwrap().metadata.id.clone()
+    }
+}
+
+impl Listener for RunHistory {
+    fn on_event(&self, record: &Record) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        if let Event::RunFinished {
+            success,
+            exit_code,
+            error,
+            duration_ms,
+        } = record.event
+        {
+            state.metadata.finished = Some(RunOutcome {
+                success: *success,
+                exit_code: *exit_code,
+                error: error.clone(),
+                duration_ms: *duration_ms,
+            });
+            state.logs.clear();
+            state.changed = None;
+            return state.save(&self.dir);
+        }
+
+        let Some(host) = record.host else {
+            return;
+        };
+
+        match record.event {
+            Event::Connected { .. } if !state.metadata.hosts.iter().any(|known| known == host) => {
+                state.metadata.hosts.push(host.to_string());
+                state.save(&self.dir);
+            }
+            Event::FileSent { path, kind, .. } => {
+                if state.changed.is_none() {
+                    state.changed = File::create(self.dir.join(CHANGED_FILES)).ok();
+                }
+                let suffix = match kind {
+                    EntryType::Dir => "/",
+                    EntryType::File => "",
+                    EntryType::Symlink => "@",
+                };
+                if let Some(changed) = &mut state.changed {
+                    writeln!(changed, "{}\t{}{}", host, path.display(), suffix).ok();
+                }
+            }
+            Event::StepStarted { step, .. } => {
+                let log = log_path(host, record.variant, step);
+                if let Some(file) = self
+                    .dir
+                    .join(&log)
+                    .parent()
+                    .and_then(|dir| fs::create_dir_all(dir).ok())
+                    .and_then(|()| File::create(self.dir.join(&log)).ok())
+                {
+                    state.logs.insert(log.clone(), file);
+                }
+
+                state.metadata.steps.push(StepRecord {
+                    host: host.to_string(),
+                    variant: record.variant.cloned(),
+                    step: step.clone(),
+                    status: "running".to_string(),
+                    exit_code: None,
+                    duration_ms: 0,
+                    log: Some(log),
+                });
+                state.save(&self.dir);
+            }
+            Event::Output { step, text, .. } => {
+                let log = log_path(host, record.variant, step);
+                if let Some(file) = state.logs.get_mut(&log) {
+                    file.write_all(text.as_bytes()).ok();
+                }
+            }
+            Event::StepFinished {
+                step,
+                status,
+                exit_code,
+                duration_ms,
+                ..
+            } => {
+                let index = state.metadata.steps.iter().position(|record_step| {
+                    record_step.host == host
+                        && record_step.variant.as_ref() == record.variant
+                        && &record_step.step == step
+                });
+                let index = index.unwrap_or_else(|| {
+                    state.metadata.steps.push(StepRecord {
+                        host: host.to_string(),
+                        variant: record.variant.cloned(),
+                        step: step.clone(),
+                        status: String::new(),
+                        exit_code: None,
+                        duration_ms: 0,
+                        log: None,
+                    });
+                    state.metadata.steps.len() - 1
+                });
+
+                let step_record = &mut state.metadata.steps[index];
+                step_record.status = status.to_string();
+                step_record.exit_code = *exit_code;
+                step_record.duration_ms = *duration_ms;
+
+                if let Some(log) = &step_record.log {
+                    state.logs.remove(log);
+                }
+                state.save(&self.dir);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl HistoryState {
+    // Written to the side and renamed, so that the file is never seen half-written
+    fn save(&self, dir: &Path) {
+        let Ok(json) = serde_json::to_string_pretty(&self.metadata) else {
+            return;
+        };
+
+        let temporary = dir.join(format!("{}.tmp", METADATA_FILE));
+        let saved = fs::write(&temporary, json)
+            .and_then(|()| fs::rename(&temporary, dir.join(METADATA_FILE)));
+        if let Err(err) = saved {
+            eprintln!("[!] Could not record the run in {}: {}", dir.display(), err);
+        }
+    }
+}
+
+fn log_path(host: &str, variant: Option<&BTreeMap<String, String>>, step: &str) -> PathBuf {
+    let mut path = PathBuf::from(LOGS_DIR).join(file_name(host));
+    if let Some(variant) = variant {
+        let values: Vec<_> = variant
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        path.push(file_name(&values.join(",")));
+    }
+
+    path.join(format!("{}.log", file_name(step)))
+}
+
+// The values of `--set` may be secrets, so only their names are recorded
+fn redact_args(args: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut redacted = Vec::new();
+    let mut value_next = false;
+    for arg in args {
+        let arg = if value_next {
+            redact_variable(&arg)
+        } else if let Some(variable) = arg.strip_prefix("--set=") {
+            format!("--set={}", redact_variable(variable))
+        } else {
+            arg
+        };
+        value_next = arg == "--set";
+        redacted.push(arg);
+    }
+
+    redacted
+}
+
+fn redact_variable(variable: &str) -> String {
+    match variable.split_once('=') {
+        Some((name, _)) => format!("{}=***", name),
+        None => variable.to_string(),
+    }
+}
+
+// Suffixed with a digest of the text when characters were replaced, so that e.g. `a b` and `a_b`
+// do not share a log
+fn file_name(text: &str) -> String {
+    let name: String = text
+        .chars()
+        .map(|char| match char {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' | '=' | ',' => char,
+            _ => '_',
+        })
+        .collect();
+
+    let name = match name.trim_start_matches('.') {
+        "" => "_".to_string(),
+        _ => name,
+    };
+    match name == text {
+        true => name,
+        false => format!("{}-{}", name, &sha256::digest(text)[..8]),
+    }
+}
+
+// Newest first, along with the time the runs started, told by their UUIDs
+fn list_runs(root: &Path) -> io::Result<Vec<(Uuid, PathBuf)>> {
+    let mut runs: Vec<_> = fs::read_dir(root)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_dir()))
+        .filter_map(|entry| {
+            let id = Uuid::parse_str(&entry.file_name().to_string_lossy()).ok()?;
+            Some((id, entry.path()))
+        })
+        .collect();
+    runs.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+    Ok(runs)
+}
+
+// A run without `run.json` yet is starting. The one that cannot be read, e.g. of another version,
+// is taken as finished.
+fn is_finished(dir: &Path) -> bool {
+    let Ok(json) = fs::read_to_string(dir.join(METADATA_FILE)) else {
+        return false;
+    };
+
+    serde_json::from_str::<RunMetadata>(&json).map_or(true, |run| run.finished.is_some())
+}
+
+// Keeps the `keep_runs` newest runs that are not older than `keep_days`, along with `current`.
+// The runs still going on, e.g. in another terminal, are kept until they are older than `keep_days`,
+// as the ones of a killed difm never finish.
+pub fn prune(config_file: &Path, settings: &TaskHistory, current: &str) {
+    let root = history_dir(config_file);
+    let Ok(runs) = list_runs(&root) else {
+        return;
+    };
+
+    let oldest = SystemTime::now()
+        .checked_sub(Duration::from_secs(settings.keep_days * 24 * 60 * 60))
+        .unwrap_or(UNIX_EPOCH);
+
+    for (index, (id, dir)) in runs.iter().enumerate() {
+        let started = id
+            .get_timestamp()
+            .map(|timestamp| UNIX_EPOCH + Duration::from_secs(timestamp.to_unix().0))
+            .unwrap_or(UNIX_EPOCH);
+        let expired = started < oldest || (index >= settings.keep_runs && is_finished(dir));
+
+        if expired && id.to_string() != current {
+            if let Err(err) = fs::remove_dir_all(dir) {
+                eprintln!(
+                    "[!] Could not remove the old run {}: {}",
+                    dir.display(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+// Newest first. The runs that cannot be read, e.g. the ones of another version, are left out.
+pub fn load_runs(config_file: &Path) -> io::Result<Vec<RunMetadata>> {
+    let root = history_dir(config_file);
+    if !root.exists() {
+        return Ok(Vec::new());
+    }
+
+    Ok(list_runs(&root)?
+        .into_iter()
+        .filter_map(|(_, dir)| {
+            let json = fs::read_to_string(dir.join(METADATA_FILE)).ok()?;
+            serde_json::from_str(&json).ok()
+        })
+        .collect())
+}
+
+pub fn read_log(config_file: &Path, run: &RunMetadata, log: &Path) -> io::Result<String> {
+    fs::read_to_string(history_dir(config_file).join(&run.id).join(log))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::TempTree;
+
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn redacts_the_values_of_set() {
+        assert_eq!(
+            redact_args(strings(&[
+                "difm",
+                "--set",
+                "TOKEN=secret",
+                "--set=KEY=a=b",
+                "--host",
+                "box",
+            ])),
+            strings(&[
+                "difm",
+                "--set",
+                "TOKEN=***",
+                "--set=KEY=***",
+                "--host",
+                "box"
+            ])
+        );
+    }
+
+    #[test]
+    fn tells_apart_the_names_replaced() {
+        assert_eq!(file_name("build-1.2"), "build-1.2");
+        assert!(file_name("a b").starts_with("a_b-"));
+        assert_ne!(file_name("a b"), file_name("a_b"));
+        assert_ne!(file_name("a/b"), file_name("a b"));
+        assert_eq!(
+            log_path("box", None, "test"),
+            Path::new("logs/box/test.log")
+        );
+    }
+
+    fn save_run(root: &Path, finished: bool) -> String {
+        let id = Uuid::now_v7().to_string();
+        let dir = root.join(&id);
+        fs::create_dir_all(&dir).unwrap();
+        let state = HistoryState {
+            metadata: RunMetadata {
+                id: id.clone(),
+                alias: None,
+                args: Vec::new(),
+                started_ms: now_ms(),
+                hosts: Vec::new(),
+                steps: Vec::new(),
+                finished: finished.then_some(RunOutcome {
+                    success: true,
+                    exit_code: 0,
+                    error: None,
+                    duration_ms: 0,
+                }),
+            },
+            logs: HashMap::new(),
+            changed: None,
+        };
+        state.save(&dir);
+
+        id
+    }
+
+    #[test]
+    fn keeps_the_runs_going_on() {
+        let base = TempTree::new(&[]);
+        let config_file = base.join("difm.yaml");
+        let root = history_dir(&config_file);
+
+        let finished = save_run(&root, true);
+        let running = save_run(&root, false);
+        let old = save_run(&root, true);
+        let current = save_run(&root, false);
+        let settings = TaskHistory {
+            enabled: true,
+            keep_runs: 1,
+            keep_days: 30,
+        };
+        prune(&config_file, &settings, &current);
+
+        let mut kept: Vec<_> = list_runs(&root)
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id.to_string())
+            .collect();
+
+        kept.sort();
+        assert_eq!(kept, [running, current]);
+        assert!(!kept.contains(&finished) && !kept.contains(&old));
+    }
+}
@@ -1,17 +1,146 @@
 mod adapter;
+mod cli;
+mod condition;
 mod config;
+mod daemon;
+mod error;
+mod event;
+mod history;
 mod progress;
 mod remote;
+mod report;
 mod services;
 mod util;
 
-use services::run_task::run_task;
+use std::{path::Path, time::Instant};
 
-use crate::config::read_config;
+use clap::Parser;
+use cli::{Cli, Command};
+use services::{
+    check::check_config,
+    daemon::manage_daemon,
+    history::{show_history, show_logs},
+    ls_files::list_files,
+    run_task::run_task,
+    shell::open_shell,
+    tunnel::open_tunnel,
+};
+
+use crate::{
+    config::{read_config, ConfigContext, Configuration},
+    error::DifmError,
+    event::{millis, Event},
+    history::{prune, RunHistory},
+    report::RunReport,
+};
 
 #[tokio::main]
 async fn main() {
-    let config = read_config(Some("./examples/cross-compile.yml".into()));
+    let mut cli = Cli::parse();
+    event::init(cli.output);
+    progress::init(cli.color, cli.verbosity());
+
+    // The daemon is not bound to any task
+    if let Some(Command::Daemon { command }) = cli.command {
+        if let Err(err) = manage_daemon(command).await {
+            exit_with(err);
+        }
+        return;
+    }
+    if let Some(Command::Check { schema }) = cli.command {
+        std::process::exit(check_config(cli.config, &cli.profile, &cli.set, schema));
+    }
+    // The history is read without loading the task definition, which may have changed since
+    if let Some(Command::History { limit }) = cli.command {
+        if let Err(err) = show_history(cli.config, limit) {
+            exit_with(err);
+        }
+        return;
+    }
+    if let Some(Command::Logs { run, step }) = &cli.command {
+        if let Err(err) = show_logs(cli.config.clone(), run, step.as_deref()) {
+            exit_with(err);
+        }
+        return;
+    }
+
+    let config = match read_config(
+        cli.config.clone(),
+        &cli.profile,
+        &cli.set,
+        cli.rev.as_deref(),
+    ) {
+        Ok(config) => config,
+        Err(err) => exit_with(err.into()),
+    };
+
+    let result = match cli.command.take().unwrap_or(Command::Run) {
+        Command::Run => run(&config, &cli).await,
+        Command::Shell { sync } => open_shell(&config, cli.host.as_deref(), sync)
+            .await
+            .map(|status| std::process::exit(status)),
+        Command::Tunnel => open_tunnel(&config, cli.host.as_deref()).await,
+        Command::LsFiles => list_files(&config),
+        Command::Check { .. }
+        | Command::History { .. }
+        | Command::Logs { .. }
+        | Command::Daemon { .. } => unreachable!(),
+    };
+
+    if let Err(err) = result {
+        exit_with(err);
+    }
+}
+
+// The JSON event stream always ends with `run_finished`, whichever way the run ends
+async fn run(config: &ConfigContext, cli: &Cli) -> Result<(), DifmError> {
+    let report = (cli.junit.is_some() || cli.markdown.is_some()).then(RunReport::subscribe);
+    // A dry run is not worth recording, as nothing has been done
+    let history = match cli.dry_run {
+        true => None,
+        false => RunHistory::start(config),
+    };
+
+    let started = Instant::now();
+    let result = run_task(config, cli.host.as_deref(), !cli.no_daemon, cli.dry_run).await;
+
+    event::emit(Event::RunFinished {
+        success: result.is_ok(),
+        exit_code: result.as_ref().map_or_else(DifmError::exit_code, |_| 0),
+        error: result.as_ref().err().map(ToString::to_string),
+        duration_ms: millis(started.elapsed()),
+    });
+
+    if let Some(history) = history {
+        let Configuration::TaskDefinition(task) = &config.config;
+        prune(&config.config_file, &task.history, &history.id());
+    }
+
+    if let Some(report) = report {
+        let reports = [
+            (
+                &cli.junit,
+                RunReport::write_junit as fn(&RunReport, &Path) -> _,
+            ),
+            (&cli.markdown, RunReport::write_markdown),
+        ];
+        for (path, write) in reports {
+            if let Some(path) = path {
+                if let Err(err) = write(&report, path) {
+                    eprintln!(
+                        "[!] Could not write the report to {}: {}",
+                        path.display(),
+                        err
+                    );
+                }
+            }
+        }
+    }
+
+    result
+}
 
-    run_task(&config).await
+fn exit_with(err: DifmError) -> ! {
+    err.report();
+    std::process::exit(err.exit_code())
 }
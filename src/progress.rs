@@ -1,6 +1,15 @@
-use std::time::{Duration, Instant};
+use std::{
+    future::Future,
+    io::{stdout, IsTerminal, Write},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
 
+use clap::ValueEnum;
 use spinners_rs::{Spinner, Spinners};
+use tokio::task::JoinHandle;
+
+use crate::{event, util::strip_ansi, when};
 
 // ESEQ is for "escape sequence"
 pub const ESEQ_DELETE_LINE: &str = "\x1b[0J";
@@ -11,21 +20,198 @@ pub const ESEQ_RESET: &str = "\x1b[m";
 
 pub const SPINNER_MS: u64 = 50;
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    // Unless stdout is not a terminal, or NO_COLOR is set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    // Only the failures and the summary
+    Quiet,
+    #[default]
+    Normal,
+    // Also each file sent
+    Verbose,
+    // Also each command run on the host
+    Debug,
+}
+
+struct RenderSettings {
+    // Spinners redrawn in place, rather than a line per change
+    live: bool,
+    color: bool,
+    verbosity: Verbosity,
+}
+
+static SETTINGS: OnceLock<RenderSettings> = OnceLock::new();
+
+pub fn init(color: ColorChoice, verbosity: Verbosity) {
+    SETTINGS.set(RenderSettings::new(color, verbosity)).ok();
+}
+
+impl RenderSettings {
+    fn new(color: ColorChoice, verbosity: Verbosity) -> Self {
+        let terminal = stdout().is_terminal();
+        let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+
+        Self {
+            live: terminal,
+            color: match color {
+                ColorChoice::Auto => terminal && !no_color,
+                ColorChoice::Always => true,
+                ColorChoice::Never => false,
+            },
+            verbosity,
+        }
+    }
+
+    fn current() -> &'static Self {
+        SETTINGS.get_or_init(|| Self::new(ColorChoice::Auto, Verbosity::Normal))
+    }
+}
+
+pub fn verbosity() -> Verbosity {
+    RenderSettings::current().verbosity
+}
+
+// Every view draws in one of these, so that the output suits where it goes
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RenderMode {
+    Live,
+    // A line per change, for the CI logs and the redirected output
+    Plain,
+    // The JSON events are written instead, or only the results are wanted
+    Hidden,
+}
+
+fn render_mode() -> RenderMode {
+    let settings = RenderSettings::current();
+    when! {
+        event::is_json() || settings.verbosity == Verbosity::Quiet => RenderMode::Hidden,
+        settings.live => RenderMode::Live,
+        _ => RenderMode::Plain,
+    }
+}
+
+// Drops the colors if they are not wanted
+pub fn paint(text: &str) -> String {
+    match RenderSettings::current().color {
+        true => text.to_string(),
+        false => strip_ansi(text),
+    }
+}
+
+tokio::task_local! {
+    static GROUP: ProgressGroup;
+}
+
+// While several hosts are worked on at once, every view is drawn as a row of one shared view
+// instead, labelled with the host the work is for.
+#[derive(Clone)]
+pub struct ProgressGroup {
+    view: Arc<MultiProgressView>,
+    label: String,
+}
+
+impl ProgressGroup {
+    pub fn new(view: Arc<MultiProgressView>, label: &str) -> Self {
+        Self {
+            view,
+            label: format!("{ESEQ_WEAK}[{label}]{ESEQ_RESET} "),
+        }
+    }
+
+    // For the work on a single host, so that the lines are still printed above the rows
+    pub fn unlabelled(view: Arc<MultiProgressView>) -> Self {
+        Self {
+            view,
+            label: String::new(),
+        }
+    }
+
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        GROUP.scope(self, future).await
+    }
+
+    // For a part of the work for the host, e.g. one of the matrix variants
+    pub fn relabel(&self, label: &str) -> Self {
+        Self::new(self.view.clone(), label)
+    }
+
+    pub fn current() -> Option<Self> {
+        GROUP.try_with(Clone::clone).ok()
+    }
+
+    pub fn view(&self) -> &MultiProgressView {
+        &self.view
+    }
+}
+
+// The prefix telling which host the line is about, if several are worked on
+pub fn label() -> String {
+    ProgressGroup::current()
+        .map(|group| group.label)
+        .unwrap_or_default()
+}
+
+pub fn report(text: &str) {
+    report_at(Verbosity::Normal, text);
+}
+
+// Printed only if the verbosity is at least `level`. Nothing is printed with the JSON events.
+pub fn report_at(level: Verbosity, text: &str) {
+    if event::is_json() || verbosity() < level {
+        return;
+    }
+
+    // The blank lines before the text are left without the label
+    let body = text.trim_start_matches('\n');
+    let blank = &text[..text.len() - body.len()];
+    match ProgressGroup::current() {
+        Some(group) => group
+            .view
+            .println(&format!("{}{}{}", blank, group.label, body)),
+        None => println!("{}", paint(text)),
+    }
+}
+
+// Told only without a terminal, where there is no spinner to tell that the work has started
+pub fn report_started(task: &str) {
+    if render_mode() == RenderMode::Plain {
+        report(&pending(task));
+    }
+}
+
 pub struct ProgressView {
     task: String,
     spinner: Spinner,
-    previous_update: Instant,
+    grouped: Option<(ProgressGroup, usize)>,
+    mode: RenderMode,
 }
 
 impl ProgressView {
     pub fn new(task: impl ToString) -> Self {
-        let mut spinner = Spinner::new(Spinners::BouncingBar, task.to_string());
+        let mode = render_mode();
+        let mut spinner = Spinner::new(Spinners::BouncingBar, paint(&task.to_string()));
         spinner.set_interval(SPINNER_MS);
 
+        let grouped = ProgressGroup::current().map(|group| {
+            let row = group
+                .view
+                .add(format!("{}{}", group.label, task.to_string()));
+            (group, row)
+        });
+
         Self {
             task: task.to_string(),
             spinner,
-            previous_update: Instant::now(),
+            grouped,
+            mode,
         }
     }
 
@@ -37,58 +223,478 @@ impl ProgressView {
     }
 
     pub fn start(&mut self) {
-        self.spinner.start();
-    }
-
-    pub fn update_task(&mut self, new_task: &str) {
-        self.task = new_task.to_string();
-        self.spinner.set_message(new_task);
-    }
-
-    pub fn report_intermediate(&mut self, progress: (usize, usize), comment: Option<&str>) {
-        if self.previous_update.elapsed() <= Duration::from_millis(SPINNER_MS * 2) {
-            return;
+        match (self.mode, &self.grouped) {
+            (RenderMode::Live, None) => self.spinner.start(),
+            (RenderMode::Plain, _) => self.print_line(&pending(&self.task)),
+            _ => {}
         }
-        self.previous_update = Instant::now();
-
-        self.spinner.set_message(format!(
-            "{ESEQ_DELETE_LINE}[{}/{}] {}{}{ESEQ_RESET}",
-            progress.0,
-            progress.1,
-            self.task,
-            comment
-                .map(|comment| format!("{ESEQ_WEAK} - {comment}"))
-                .unwrap_or("".to_owned())
-        ));
     }
 
     pub fn success(&mut self, message: Option<&str>) {
-        self.previous_update = Instant::now();
-
-        self.spinner.stop_with_message(format!(
-            "{ESEQ_DELETE_LINE}{ESEQ_GREEN}✓ {}{}{ESEQ_RESET}",
+        self.finish_with(format!(
+            "{ESEQ_GREEN}✓ {}{}{ESEQ_RESET}",
             self.task,
             message
                 .map(|message| format!(" - {}", message))
                 .unwrap_or("".to_owned())
         ));
-        println!();
     }
 
     pub fn failure(&mut self, message: Option<&str>) {
-        self.previous_update = Instant::now();
-
-        self.spinner.stop_with_message(format!(
-            "{ESEQ_DELETE_LINE}{ESEQ_RED}! {}{}{ESEQ_RESET}",
+        self.finish_with(format!(
+            "{ESEQ_RED}! {}{}{ESEQ_RESET}",
             self.task,
             message
                 .map(|message| format!(" - {}", message))
                 .unwrap_or("".to_owned())
         ));
-        println!();
     }
 
     pub fn stop(&mut self) {
-        self.spinner.stop();
+        match (self.grouped.take(), self.mode) {
+            (Some((group, row)), _) => {
+                group.view.remove(row);
+            }
+            (None, RenderMode::Live) => {
+                self.spinner.stop_with_message(ESEQ_DELETE_LINE);
+            }
+            (None, _) => {}
+        }
+    }
+
+    fn finish_with(&mut self, message: String) {
+        if self.mode == RenderMode::Live && self.grouped.is_none() {
+            self.spinner
+                .stop_with_message(format!("{ESEQ_DELETE_LINE}{}", paint(&message)));
+            println!();
+        } else if self.mode != RenderMode::Hidden {
+            self.print_line(&message);
+        }
+
+        if let Some((group, row)) = self.grouped.take() {
+            group.view.remove(row);
+        }
+    }
+
+    fn print_line(&self, text: &str) {
+        match &self.grouped {
+            Some((group, _)) => group.view.println(&format!("{}{}", group.label, text)),
+            None => println!("{}", paint(text)),
+        }
+    }
+}
+
+// Some of the tasks already end with the dots
+fn pending(task: &str) -> String {
+    format!("{}...", task.trim_end_matches('.'))
+}
+
+impl Drop for ProgressView {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+const MULTI_SPINNER_FRAMES: &[&str] = &["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+const MULTI_COMMENT_WIDTH: usize = 60;
+const BAR_WIDTH: usize = 24;
+
+// Shows one spinner row per running task. Anything printed through `println` goes above the rows,
+// so that finished tasks can report while others are still running.
+// The rows are only drawn live; otherwise `println` just prints the lines.
+pub struct MultiProgressView {
+    state: Arc<Mutex<MultiProgressState>>,
+    ticker: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct MultiProgressState {
+    rows: Vec<MultiProgressRow>,
+    next_id: usize,
+    drawn_lines: usize,
+    frame: usize,
+    live: bool,
+}
+
+struct MultiProgressRow {
+    id: usize,
+    task: String,
+    comment: Option<String>,
+    // How much is done, and what is told next to the bar
+    bar: Option<(f64, String)>,
+}
+
+impl MultiProgressView {
+    pub fn new() -> Self {
+        let live = render_mode() == RenderMode::Live;
+        let state = Arc::new(Mutex::new(MultiProgressState {
+            live,
+            ..Default::default()
+        }));
+
+        let state_for_ticker = state.clone();
+        let ticker = live.then(|| {
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_millis(SPINNER_MS));
+                loop {
+                    interval.tick().await;
+
+                    let mut state = state_for_ticker.lock().unwrap();
+                    state.frame = state.frame.wrapping_add(1);
+                    state.redraw(None);
+                }
+            })
+        });
+
+        Self { state, ticker }
+    }
+
+    pub fn add(&self, task: impl ToString) -> usize {
+        let mut state = self.state.lock().unwrap();
+
+        let id = state.next_id;
+        state.next_id += 1;
+        state.rows.push(MultiProgressRow {
+            id,
+            task: task.to_string(),
+            comment: None,
+            bar: None,
+        });
+        state.redraw(None);
+
+        id
+    }
+
+    // Turns the row into a bar, redrawn with the spinners
+    pub fn update_bar(&self, id: usize, fraction: f64, detail: String) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(row) = state.rows.iter_mut().find(|row| row.id == id) {
+            row.bar = Some((fraction.clamp(0.0, 1.0), detail));
+        }
+    }
+
+    pub fn report_intermediate(&self, id: usize, comment: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(row) = state.rows.iter_mut().find(|row| row.id == id) {
+            row.comment = Some(comment.chars().take(MULTI_COMMENT_WIDTH).collect());
+        }
+    }
+
+    pub fn remove(&self, id: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.rows.retain(|row| row.id != id);
+        state.redraw(None);
+    }
+
+    // Printed even with `-q`, as the callers tell what is worth it
+    pub fn println(&self, text: &str) {
+        if event::is_json() {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        match state.live {
+            true => state.redraw(Some(text)),
+            false => println!("{}", paint(text)),
+        }
+    }
+
+    pub fn finish(&self) {
+        if let Some(ticker) = &self.ticker {
+            ticker.abort();
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.rows.clear();
+        state.redraw(None);
+    }
+}
+
+// Also on an early return, so that the ticker does not keep redrawing over the later output
+impl Drop for MultiProgressView {
+    fn drop(&mut self) {
+        if let Some(ticker) = &self.ticker {
+            ticker.abort();
+        }
+
+        if let Ok(mut state) = self.state.lock() {
+            if !state.rows.is_empty() {
+                state.rows.clear();
+                state.redraw(None);
+            }
+        }
+    }
+}
+
+impl MultiProgressState {
+    fn redraw(&mut self, above: Option<&str>) {
+        if !self.live {
+            return;
+        }
+
+        let mut output = String::new();
+
+        if self.drawn_lines > 0 {
+            output.push_str(&format!("\x1b[{}F", self.drawn_lines));
+        }
+        output.push_str(ESEQ_DELETE_LINE);
+
+        if let Some(above) = above {
+            output.push_str(&paint(above));
+            output.push('\n');
+        }
+
+        let frame = MULTI_SPINNER_FRAMES[self.frame % MULTI_SPINNER_FRAMES.len()];
+        for row in &self.rows {
+            let status = match &row.bar {
+                Some((fraction, detail)) => {
+                    let filled = (fraction * BAR_WIDTH as f64).round() as usize;
+                    format!(
+                        " [{}{ESEQ_WEAK}{}{ESEQ_RESET}] {:>3}% {ESEQ_WEAK}{detail}",
+                        "█".repeat(filled),
+                        "░".repeat(BAR_WIDTH - filled),
+                        (fraction * 100.0) as usize
+                    )
+                }
+                None => row
+                    .comment
+                    .as_ref()
+                    .map(|comment| format!("{ESEQ_WEAK} - {comment}"))
+                    .unwrap_or_default(),
+            };
+            output.push_str(&paint(&format!(
+                "{frame} {}{status}{ESEQ_RESET}\n",
+                row.task
+            )));
+        }
+        self.drawn_lines = self.rows.len();
+
+        print!("{output}");
+        stdout().flush().ok();
+    }
+}
+
+// Bytes sent, drawn as a bar for all the files and another for the file being sent.
+// Without a terminal, only the start and the end are printed.
+pub struct TransferProgress {
+    view: Arc<MultiProgressView>,
+    own_view: bool,
+    label: String,
+    task: String,
+    mode: RenderMode,
+    overall: Option<usize>,
+    current: Option<usize>,
+    total_files: usize,
+    total_bytes: u64,
+    done_files: usize,
+    // Of the files already sent
+    done_bytes: u64,
+    current_bytes: u64,
+    current_size: u64,
+    started: Instant,
+}
+
+impl TransferProgress {
+    pub fn new(task: &str, total_files: usize, total_bytes: u64) -> Self {
+        let mode = render_mode();
+        let (view, own_view, label) = match ProgressGroup::current() {
+            Some(group) => (group.view, false, group.label),
+            None => (Arc::new(MultiProgressView::new()), true, String::new()),
+        };
+
+        let overall = view.add(format!("{label}{task}"));
+
+        let progress = Self {
+            view,
+            own_view,
+            label,
+            task: task.to_string(),
+            mode,
+            overall: Some(overall),
+            current: None,
+            total_files,
+            total_bytes,
+            done_files: 0,
+            done_bytes: 0,
+            current_bytes: 0,
+            current_size: 0,
+            started: Instant::now(),
+        };
+        progress.redraw();
+
+        progress
+    }
+
+    // Starting the same file again, e.g. after reconnecting, starts its bar over
+    pub fn start_file(&mut self, name: &str, size: u64) {
+        if let Some(current) = self.current.take() {
+            self.view.remove(current);
+        }
+
+        self.current_bytes = 0;
+        self.current_size = size;
+        self.current = Some(self.view.add(format!("{}  {}", self.label, name)));
+        self.redraw();
+    }
+
+    pub fn file_progress(&mut self, written: u64) {
+        self.current_bytes = written.min(self.current_size);
+        self.redraw();
+    }
+
+    pub fn finish_file(&mut self) {
+        if let Some(current) = self.current.take() {
+            self.view.remove(current);
+        }
+
+        self.done_files += 1;
+        self.done_bytes += self.current_size;
+        self.current_bytes = 0;
+        self.current_size = 0;
+        self.redraw();
+    }
+
+    // What was sent is told by the events
+    pub fn success(&mut self) {
+        if self.overall.is_some() {
+            self.close();
+        }
+    }
+
+    pub fn failure(&mut self, message: &str) {
+        if self.overall.is_none() {
+            return;
+        }
+
+        self.close();
+        if self.mode != RenderMode::Hidden {
+            self.view.println(&format!(
+                "{}{ESEQ_RED}! {} - {}{ESEQ_RESET}",
+                self.label, self.task, message
+            ));
+        }
+    }
+
+    fn close(&mut self) {
+        for row in [self.overall.take(), self.current.take()]
+            .into_iter()
+            .flatten()
+        {
+            self.view.remove(row);
+        }
+        if self.own_view {
+            self.view.finish();
+        }
+    }
+
+    // Only the rows are updated here; they are drawn by the ticker of the view
+    fn redraw(&self) {
+        if self.mode != RenderMode::Live {
+            return;
+        }
+
+        let sent = self.done_bytes + self.current_bytes;
+        let elapsed = self.started.elapsed();
+        let rate = throughput(sent, elapsed);
+        let eta = rate
+            .filter(|rate| *rate > 0)
+            .map(|rate| Duration::from_secs(self.total_bytes.saturating_sub(sent) / rate));
+
+        if let Some(overall) = self.overall {
+            self.view.update_bar(
+                overall,
+                fraction(sent, self.total_bytes),
+                format!(
+                    "{}/{} files  {} / {}{}{}",
+                    self.done_files,
+                    self.total_files,
+                    format_bytes(sent),
+                    format_bytes(self.total_bytes),
+                    rate.map(|rate| format!("  {}/s", format_bytes(rate)))
+                        .unwrap_or_default(),
+                    eta.map(|eta| format!("  ETA {}", format_duration(eta)))
+                        .unwrap_or_default()
+                ),
+            );
+        }
+        if let Some(current) = self.current {
+            self.view.update_bar(
+                current,
+                fraction(self.current_bytes, self.current_size),
+                format!(
+                    "{} / {}",
+                    format_bytes(self.current_bytes),
+                    format_bytes(self.current_size)
+                ),
+            );
+        }
+    }
+}
+
+impl Drop for TransferProgress {
+    fn drop(&mut self) {
+        if self.overall.is_some() {
+            self.close();
+        }
+    }
+}
+
+fn fraction(done: u64, total: u64) -> f64 {
+    match total {
+        0 => 1.0,
+        total => done as f64 / total as f64,
+    }
+}
+
+// Bytes per second, once there has been long enough to tell
+pub fn throughput(bytes: u64, elapsed: Duration) -> Option<u64> {
+    (elapsed >= Duration::from_millis(500)).then(|| (bytes as f64 / elapsed.as_secs_f64()) as u64)
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..=9 => format!("{:.1}s", duration.as_secs_f64()),
+        10..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stops_the_ticker_when_the_view_is_dropped() {
+        let alive = Arc::new(());
+        let held = alive.clone();
+        let view = MultiProgressView {
+            state: Arc::default(),
+            ticker: Some(tokio::spawn(async move {
+                let _held = held;
+                std::future::pending::<()>().await
+            })),
+        };
+
+        drop(view);
+        tokio::task::yield_now().await;
+        assert_eq!(Arc::strong_count(&alive), 1);
     }
 }
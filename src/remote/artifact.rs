@@ -0,0 +1,75 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use crate::{
+    adapter::ssh::{
+        transfer::{receive_file, FileTransferError},
+        SSHSession,
+    },
+    config::TaskArtifact,
+    event::{self, millis, Event},
+    progress::ProgressView,
+};
+
+// `host_dir` is given if several hosts are worked on. Their artifacts are stored in a directory
+// per host next to `local_path`, e.g. `received/exe/<host>/difm`.
+pub async fn fetch_artifacts(
+    session: &SSHSession,
+    code_dir: &Path,
+    artifacts: &[TaskArtifact],
+    host_dir: Option<&str>,
+) -> Result<(), FileTransferError> {
+    for artifact in artifacts {
+        let remote_source = code_dir.join(&artifact.remote_path);
+        let local_dest = local_dest(artifact, host_dir);
+
+        let mut progress =
+            ProgressView::new(format!("Fetching {}", artifact.remote_path.display()));
+        progress.start();
+        let started = Instant::now();
+
+        loop {
+            let generation = session.generation();
+
+            match receive_file(session, &remote_source, &local_dest).await {
+                Ok(()) => {
+                    progress.stop();
+                    event::emit(Event::ArtifactFetched {
+                        remote_path: artifact.remote_path.clone(),
+                        local_path: local_dest.clone(),
+                        duration_ms: millis(started.elapsed()),
+                    });
+                    break;
+                }
+                Err(FileTransferError::Disconnected(_)) => {
+                    if let Err(err) = session.reconnect(generation).await {
+                        progress.failure(Some(&err.to_string()));
+                        return Err(FileTransferError::Disconnected(err));
+                    }
+                }
+                Err(err) => {
+                    progress.failure(Some(&err.to_string()));
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn local_dest(artifact: &TaskArtifact, host_dir: Option<&str>) -> PathBuf {
+    match host_dir {
+        Some(host) => path_for_host(&artifact.local_path, host),
+        None => artifact.local_path.clone(),
+    }
+}
+
+fn path_for_host(path: &Path, host: &str) -> PathBuf {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => parent.join(host).join(name),
+        _ => Path::new(host).join(path),
+    }
+}
@@ -0,0 +1,113 @@
+use std::{fmt::Display, io};
+
+use tokio::{net::TcpListener, task::JoinHandle};
+
+use crate::{
+    adapter::ssh::{
+        forward::{forward_local, forward_remote},
+        SSHSession,
+    },
+    config::{TaskForward, TaskForwardKind},
+    progress::ProgressView,
+};
+
+// Either side listens on the loopback interface only, unless `bind` says otherwise
+const DEFAULT_BIND: &str = "127.0.0.1";
+
+// Forwarding stops when this is dropped
+pub struct PortForwarding {
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl PortForwarding {
+    // Along with the reasons of the forwards that could not be started
+    pub async fn start(session: &SSHSession, forwards: &[TaskForward]) -> (Self, Vec<io::Error>) {
+        let mut handles = Vec::new();
+        let mut failures = Vec::new();
+
+        for forward in forwards {
+            let mut progress = ProgressView::new(format!("Forwarding {}", forward));
+            progress.start();
+
+            match start_forward(session, forward).await {
+                Ok(handle) => {
+                    progress.success(None);
+                    handles.push(handle);
+                }
+                Err(err) => {
+                    progress.failure(Some(&err.to_string()));
+                    failures.push(err);
+                }
+            }
+        }
+
+        (Self { handles }, failures)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+}
+
+impl Drop for PortForwarding {
+    fn drop(&mut self) {
+        self.handles.iter().for_each(JoinHandle::abort);
+    }
+}
+
+impl Display for TaskForward {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (listen_side, connect_side) = match self.kind {
+            TaskForwardKind::Local => ("local", "remote"),
+            TaskForwardKind::Remote => ("remote", "local"),
+        };
+
+        write!(
+            f,
+            "{} {}:{} -> {} {}",
+            listen_side,
+            self.bind.as_deref().unwrap_or(DEFAULT_BIND),
+            self.listen,
+            connect_side,
+            self.connect
+        )
+    }
+}
+
+async fn start_forward(session: &SSHSession, forward: &TaskForward) -> io::Result<JoinHandle<()>> {
+    // Only a session of our own can open the channels
+    session.ensure_direct()?;
+    let bind = forward.bind.as_deref().unwrap_or(DEFAULT_BIND);
+
+    match forward.kind {
+        TaskForwardKind::Local => {
+            let Some((host, port)) = split_address(&forward.connect) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "`connect` should be in the form of `host:port`",
+                ));
+            };
+            let listener = TcpListener::bind((bind, forward.listen)).await?;
+
+            Ok(tokio::spawn(forward_local(
+                session.shared_clone(),
+                listener,
+                host.to_string(),
+                port,
+            )))
+        }
+        TaskForwardKind::Remote => {
+            let (listener, _) = session.forward_listen(bind, forward.listen).await?;
+
+            Ok(tokio::spawn(forward_remote(
+                listener,
+                forward.connect.clone(),
+            )))
+        }
+    }
+}
+
+fn split_address(address: &str) -> Option<(&str, u16)> {
+    let (host, port) = address.rsplit_once(':')?;
+    Some((host, port.parse().ok()?))
+}
@@ -1,8 +1,9 @@
 use std::{
     collections::{HashMap, HashSet},
+    fmt::Display,
     io,
-    ops::Deref,
     path::{Path, PathBuf},
+    time::Instant,
 };
 
 use sha256::try_digest;
@@ -12,71 +13,141 @@ use crate::{
         fs::{Entry, EntryType, FileTransferList},
         ssh::{exec::ExecChannel, SSHSession},
     },
+    event::{self, millis, Event},
     progress::ProgressView,
+    util::shell_quote,
 };
 
-pub async fn check_file_change<'a>(
-    session: &'a SSHSession,
+#[derive(Debug)]
+pub enum IntegrityError {
+    Local(PathBuf, io::Error),
+    Remote(String),
+}
+
+impl Display for IntegrityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IntegrityError::Local(path, err) => {
+                write!(f, "Could not read {}: {}", path.display(), err)
+            }
+            IntegrityError::Remote(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+pub async fn check_file_change(
+    session: &SSHSession,
     transfer_list: &FileTransferList,
-) -> Result<Vec<Entry>, ()> {
-    ProgressView::with("Checking if the file changed", |progress| async {
-        let files: Vec<_> = transfer_list
-            .traverse_dir()
-            .filter(|entry| entry.kind == EntryType::File)
-            .collect();
-
-        let remote = tokio::spawn(calculate_remote_sha256(
-            session.shared_clone(),
-            files.clone(),
-        ));
+) -> Result<Vec<Entry>, IntegrityError> {
+    let started = Instant::now();
+
+    let changed: Vec<Entry> =
+        ProgressView::with("Checking if the file changed", |mut progress| async move {
+            let files: Vec<_> = match transfer_list.traverse_dir() {
+                Ok(entries) => entries
+                    .filter(|entry| entry.kind != EntryType::Dir)
+                    .collect(),
+                Err(err) => {
+                    progress.failure(None);
+                    return Err(IntegrityError::Local(
+                        transfer_list.local_source_origin().to_path_buf(),
+                        err,
+                    ));
+                }
+            };
+            event::emit(Event::HashStarted { files: files.len() });
+
+            let remote = tokio::spawn(calculate_remote_sha256(
+                session.shared_clone(),
+                files.clone(),
+            ));
+
+            let local = calculate_local_sha256(&files);
+            let remote = remote
+                .await
+                .map_err(|err| IntegrityError::Remote(err.to_string()))
+                .and_then(|remote| remote);
+            let remote_links = read_remote_links(session, &files).await;
+            let (local, remote, remote_links) = match (local, remote, remote_links) {
+                (Ok(local), Ok(remote), Ok(remote_links)) => (local, remote, remote_links),
+                (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) => {
+                    progress.failure(None);
+                    return Err(err);
+                }
+            };
+
+            let diff_path = check_differences(
+                &local,
+                &remote,
+                transfer_list.local_source_origin(),
+                transfer_list.remote_dest_origin(),
+            );
+
+            Ok(files
+                .into_iter()
+                .filter(|entry| match entry.kind {
+                    EntryType::Symlink => {
+                        remote_links.get(&entry.remote_dest) != entry.link_target.as_ref()
+                    }
+                    _ => diff_path.iter().any(|path| entry.is_same(path)),
+                })
+                .collect())
+        })
+        .await?;
 
-        let local = calculate_local_sha256(&files).unwrap();
-        let remote = remote.await.unwrap().unwrap();
-
-        let diff_path = check_differences(
-            &local,
-            &remote,
-            transfer_list.local_source_origin(),
-            transfer_list.remote_dest_origin(),
-        );
-
-        Ok(files
-            .into_iter()
-            .filter(|entry| diff_path.iter().any(|path| entry.is_same(path)))
-            .collect())
-    })
-    .await
+    event::emit(Event::HashFinished {
+        changed: changed.len(),
+        duration_ms: millis(started.elapsed()),
+    });
+
+    Ok(changed)
 }
 
-fn calculate_local_sha256(files: &[Entry]) -> Result<HashMap<PathBuf, String>, io::Error> {
+fn calculate_local_sha256(files: &[Entry]) -> Result<HashMap<PathBuf, String>, IntegrityError> {
     files
         .iter()
         .filter(|entry| entry.kind == EntryType::File)
         .map(|entry| {
-            try_digest(&*entry.local_source).map(|digest| (entry.local_source.clone(), digest))
+            let digest = match &entry.blob {
+                Some(_) => entry.read().map(|bytes| sha256::digest(&bytes[..])),
+                None => try_digest(&*entry.local_source),
+            };
+            digest
+                .map(|digest| (entry.local_source.clone(), digest))
+                .map_err(|err| IntegrityError::Local(entry.local_source.clone(), err))
         })
         .collect()
 }
 
+// `sha256sum` fails for the files missing on the host, which is expected, so only the failures
+// of running it at all are errors
 async fn calculate_remote_sha256(
     session: SSHSession,
     files: Vec<Entry>,
-) -> Result<HashMap<PathBuf, String>, io::Error> {
+) -> Result<HashMap<PathBuf, String>, IntegrityError> {
     let file_paths: Vec<String> = files
         .iter()
         .filter(|entry| entry.kind == EntryType::File)
-        .map(|entry| format!("'{}'", entry.remote_dest.to_str().unwrap()))
+        .map(|entry| shell_quote(&entry.remote_dest.to_string_lossy()))
         .collect();
 
-    // dbg!(&file_paths);
-
     let executed =
         ExecChannel::execute(&session, &format!("sha256sum {}", file_paths.join(" "))).await;
 
-    // dbg!(&executed.stdout);
-    // dbg!(&executed.stderr);
-
-    // dbg!(ExecChannel::execute(&session, "echo $PATH").await.stdout);
+    match executed.exit_code {
+        None => {
+            return Err(IntegrityError::Remote(
+                "The connection was lost while calculating the hashes".to_string(),
+            ))
+        }
+        Some(127) => {
+            return Err(IntegrityError::Remote(format!(
+                "`sha256sum` is not available: {}",
+                executed.stderr.trim()
+            )))
+        }
+        Some(_) => {}
+    }
 
     Ok(executed
         .stdout
@@ -86,28 +157,66 @@ async fn calculate_remote_sha256(
         .collect())
 }
 
+// `readlink` prints nothing for the links missing on the host, which are then recreated
+async fn read_remote_links(
+    session: &SSHSession,
+    entries: &[Entry],
+) -> Result<HashMap<PathBuf, PathBuf>, IntegrityError> {
+    let link_paths: Vec<String> = entries
+        .iter()
+        .filter(|entry| entry.kind == EntryType::Symlink)
+        .map(|entry| shell_quote(&entry.remote_dest.to_string_lossy()))
+        .collect();
+    if link_paths.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let executed = ExecChannel::execute(
+        session,
+        &format!(
+            "for link in {}; do printf '%s\\t%s\\n' \"$link\" \"$(readlink -- \"$link\")\"; done",
+            link_paths.join(" ")
+        ),
+    )
+    .await;
+
+    if executed.exit_code.is_none() {
+        return Err(IntegrityError::Remote(
+            "The connection was lost while reading the symlinks".to_string(),
+        ));
+    }
+
+    Ok(executed
+        .stdout
+        .lines()
+        .flat_map(|line| line.split_once('\t'))
+        .filter(|(_, target)| !target.is_empty())
+        .map(|(path, target)| (PathBuf::from(path), PathBuf::from(target)))
+        .collect())
+}
+
 fn check_differences(
     local: &HashMap<PathBuf, String>,
     remote: &HashMap<PathBuf, String>,
     local_base: &Path,
     remote_base: &Path,
 ) -> Vec<PathBuf> {
+    // Anything else printed by `sha256sum`, e.g. the escaped names, is treated as missing
     let local_keys: HashSet<_> = local
         .keys()
-        .map(|path| path.strip_prefix(local_base).unwrap())
+        .filter_map(|path| path.strip_prefix(local_base).ok())
         .collect();
     let remote_keys: HashSet<_> = remote
         .keys()
-        .map(|path| path.strip_prefix(remote_base).unwrap())
+        .filter_map(|path| path.strip_prefix(remote_base).ok())
         .collect();
 
     let missing_in_local = local_keys.difference(&remote_keys);
     let missing_in_remote = remote_keys.difference(&local_keys);
     let exist_in_both = local_keys.intersection(&remote_keys);
 
-    let content_differs = exist_in_both.filter(|path| {
-        local.get(&local_base.join(path)).unwrap() != remote.get(&remote_base.join(path)).unwrap()
-    });
+    let content_differs = exist_in_both
+        .filter(|path| local.get(&local_base.join(path)) != remote.get(&remote_base.join(path)));
 
     missing_in_local
         .chain(missing_in_remote)
@@ -1,3 +1,6 @@
+pub mod artifact;
+pub mod forward;
 pub mod integrity;
+pub mod path;
 pub mod task;
 pub mod transfer;
@@ -0,0 +1,45 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    adapter::ssh::{exec::ExecChannel, SSHSession},
+    progress::ProgressView,
+};
+
+const HOME_PREFIXES: &[&str] = &["~", "$HOME", "${HOME}"];
+
+pub async fn expand_remote_home(session: &SSHSession, path: &Path) -> io::Result<PathBuf> {
+    let Some(rest) = HOME_PREFIXES
+        .iter()
+        .find_map(|prefix| path.strip_prefix(prefix).ok())
+    else {
+        return Ok(path.to_path_buf());
+    };
+
+    ProgressView::with(
+        "Resolving the remote home directory",
+        |mut progress| async move {
+            let executed = ExecChannel::execute(session, "echo \"$HOME\"").await;
+            let home = executed.stdout.trim();
+
+            if executed.exit_code != Some(0) || home.is_empty() {
+                progress.failure(None);
+                return Err(match executed.exit_code {
+                    None => {
+                        io::Error::new(io::ErrorKind::ConnectionAborted, "The connection was lost")
+                    }
+                    Some(_) if executed.stderr.trim().is_empty() => {
+                        io::Error::other("$HOME is empty")
+                    }
+                    Some(_) => io::Error::other(executed.stderr.trim().to_string()),
+                });
+            }
+
+            progress.success(Some(home));
+            Ok(Path::new(home).join(rest))
+        },
+    )
+    .await
+}
@@ -1,12 +1,89 @@
-use std::{num::NonZeroU8, path::Path};
+use std::{
+    collections::{BTreeSet, VecDeque},
+    fmt::Display,
+    num::NonZeroU8,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use futures::{stream::FuturesUnordered, StreamExt};
+use serde::{Serialize, Serializer};
 
 use crate::{
-    adapter::ssh::{exec::ExecChannel, SSHSession},
+    adapter::ssh::{
+        exec::{ExecChannel, ExecOutput, PtyRequest},
+        SSHSession,
+    },
+    condition::ConditionContext,
     config::TaskRun,
-    progress::ProgressView,
-    util::indent_str,
+    event::{self, millis, Event, OutputStream},
+    progress::{self, MultiProgressView, ProgressGroup},
+    util::{shell_quote, strip_ansi},
 };
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskRunStatus {
+    Success,
+    Failure(NonZeroU8),
+    // The connection was lost while running
+    Interrupted,
+    Skipped,
+}
+
+impl Display for TaskRunStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TaskRunStatus::Success => write!(f, "success"),
+            TaskRunStatus::Failure(_) => write!(f, "failure"),
+            TaskRunStatus::Interrupted => write!(f, "interrupted"),
+            TaskRunStatus::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+impl Serialize for TaskRunStatus {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Debug)]
+pub enum TaskSetError<'a> {
+    Failed(&'a TaskRun, NonZeroU8),
+    Interrupted(&'a TaskRun),
+    UnknownDependency(&'a TaskRun, String),
+    // The names along the cycle, starting and ending with the task
+    CyclicDependency(&'a TaskRun, Vec<String>),
+}
+
+impl Display for TaskSetError<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TaskSetError::Failed(run, code) => {
+                write!(f, "Task '{}' exited with code {}", run.name, code)
+            }
+            TaskSetError::Interrupted(run) => {
+                write!(
+                    f,
+                    "Task '{}' was interrupted by the lost connection",
+                    run.name
+                )
+            }
+            TaskSetError::UnknownDependency(run, need) => {
+                write!(f, "Task '{}' needs unknown task '{}'", run.name, need)
+            }
+            TaskSetError::CyclicDependency(run, cycle) => {
+                write!(
+                    f,
+                    "Task '{}' depends on itself: {}",
+                    run.name,
+                    cycle.join(" -> ")
+                )
+            }
+        }
+    }
+}
+
 pub struct TaskRunner<'s> {
     pub session: &'s SSHSession,
 }
@@ -16,47 +93,329 @@ impl<'s> TaskRunner<'s> {
         Self { session }
     }
 
-    pub async fn perform(&self, pwd: &Path, run: &TaskRun) -> Result<(), NonZeroU8> {
-        // TODO: Stream the output of the execution
-        ProgressView::with(
-            format!("Running task: {}", run.name),
-            |mut progress| async move {
-                let exit_info = ExecChannel::execute(
-                    self.session,
-                    &format!("cd {} && {}", pwd.to_str().unwrap(), run.run),
-                )
-                .await;
+    // The output is printed from the events once the task is finished
+    pub async fn perform(
+        &self,
+        pwd: &Path,
+        run: &TaskRun,
+        progress: &MultiProgressView,
+    ) -> TaskRunStatus {
+        let row = progress.add(format!("{}Running task: {}", progress::label(), run.name));
+        event::emit(Event::StepStarted {
+            step: run.name.clone(),
+            tty: run.tty().is_some(),
+        });
+        let started = Instant::now();
 
-                match exit_info.exit_code {
-                    0 => progress.success(Some("done")),
-                    i => progress.failure(Some(&format!("Exited with code {}\x1b[m", i))),
-                };
+        let generation = self.session.generation();
+        let mut channel = ExecChannel::with_pty(
+            self.session,
+            &Self::command(pwd, run),
+            Self::pty(run).as_ref(),
+        )
+        .await;
 
-                println!("\x1b[1m----- Standard output -----\x1b[m");
-                println!("\x1b[38;5;14m{}\x1b[m", indent_str(&exit_info.stdout, 1));
-                println!("\x1b[1m----- Standard Error  -----\x1b[m");
-                println!("\x1b[38;5;11m{}\x1b[m", indent_str(&exit_info.stderr, 1));
-                println!("\x1b[1m----- End of Standard output -----\x1b[m");
+        while let Some(output) = channel.next_output().await {
+            let stream = OutputStream::from(&output);
+            let (ExecOutput::Stdout(chunk) | ExecOutput::Stderr(chunk)) = output;
+            event::emit(Event::Output {
+                step: run.name.clone(),
+                stream,
+                text: chunk.clone(),
+            });
 
-                match exit_info.exit_code {
-                    0 => Ok(()),
-                    i => Err(i.try_into().unwrap()),
-                }
-            },
+            // Progress bars redraw the line with `\r`, so only the last state is interesting
+            let line = chunk
+                .lines()
+                .rev()
+                .find_map(|line| line.rsplit('\r').find(|part| !part.trim().is_empty()));
+
+            if let Some(line) = line {
+                progress.report_intermediate(row, strip_ansi(line).trim());
+            }
+        }
+
+        let exit_info = channel.wait_done().await;
+        progress.remove(row);
+
+        let status = match exit_info.exit_code {
+            Some(0) => TaskRunStatus::Success,
+            Some(i) => TaskRunStatus::Failure(i.try_into().unwrap()),
+            None => TaskRunStatus::Interrupted,
+        };
+        event::emit(Event::StepFinished {
+            step: run.name.clone(),
+            status,
+            exit_code: exit_info.exit_code,
+            duration_ms: millis(started.elapsed()),
+            condition: run
+                .condition
+                .as_ref()
+                .map(|condition| condition.source().to_string()),
+        });
+
+        // The command is gone along with the connection, but the following tasks (e.g. the ones
+        // with `if: always()`) can still run once reconnected
+        if status == TaskRunStatus::Interrupted {
+            if let Err(err) = self.session.reconnect(generation).await {
+                progress.println(&format!("[!] Could not reconnect: {}", err));
+            }
+        }
+
+        status
+    }
+
+    pub fn cwd(pwd: &Path, run: &TaskRun) -> PathBuf {
+        match &run.cwd {
+            Some(cwd) => pwd.join(cwd),
+            None => pwd.to_path_buf(),
+        }
+    }
+
+    // As sent to the host, e.g. shown by `--dry-run`
+    pub fn command(pwd: &Path, run: &TaskRun) -> String {
+        format!(
+            "cd {} && {}",
+            shell_quote(&Self::cwd(pwd, run).to_string_lossy()),
+            run.run
         )
-        .await
     }
 
+    pub fn pty(run: &TaskRun) -> Option<PtyRequest> {
+        run.tty().map(|tty| {
+            let mut pty = PtyRequest::from_local_terminal();
+            pty.term = tty.term.unwrap_or(pty.term);
+            pty.size.cols = tty.cols.unwrap_or(pty.size.cols);
+            pty.size.rows = tty.rows.unwrap_or(pty.size.rows);
+            pty
+        })
+    }
+
+    // A task starts as soon as all of the tasks it `needs` are finished. Tasks without `needs:`
+    // wait for the previous one, so the set runs sequentially unless told otherwise.
+    // Conditions only see the results of the tasks the task (transitively) depends on.
     pub async fn perform_task_set<'a>(
         &self,
         pwd: &Path,
         runs: &'a [TaskRun],
-    ) -> Result<(), (&'a TaskRun, NonZeroU8)> {
-        for run in runs {
-            println!();
-            self.perform(pwd, run).await.map_err(|exit| (run, exit))?
+        context: &mut ConditionContext,
+    ) -> Result<(), TaskSetError<'a>> {
+        let ancestors = resolve_ancestors(runs)?;
+
+        let mut statuses: Vec<Option<TaskRunStatus>> = vec![None; runs.len()];
+        let mut started = vec![false; runs.len()];
+
+        // Shares the view with the other hosts if there are
+        let group = ProgressGroup::current();
+        let own_progress;
+        let progress = match &group {
+            Some(group) => group.view(),
+            None => {
+                own_progress = MultiProgressView::new();
+                &own_progress
+            }
+        };
+        let mut running = FuturesUnordered::new();
+
+        loop {
+            for (index, run) in runs.iter().enumerate() {
+                if started[index] || !ancestors[index].iter().all(|i| statuses[*i].is_some()) {
+                    continue;
+                }
+                started[index] = true;
+
+                let mut step_context = context.clone();
+                step_context.steps.extend(
+                    ancestors[index]
+                        .iter()
+                        .map(|i| (runs[*i].name.clone(), statuses[*i].unwrap())),
+                );
+
+                let should_run = match &run.condition {
+                    Some(condition) => condition.evaluate(&step_context),
+                    None => step_context
+                        .steps
+                        .iter()
+                        .all(|(_, status)| *status == TaskRunStatus::Success),
+                };
+
+                if should_run {
+                    running.push(async move { (index, self.perform(pwd, run, progress).await) });
+                } else {
+                    statuses[index] = Some(TaskRunStatus::Skipped);
+                    event::emit(Event::StepFinished {
+                        step: run.name.clone(),
+                        status: TaskRunStatus::Skipped,
+                        exit_code: None,
+                        duration_ms: 0,
+                        condition: run
+                            .condition
+                            .as_ref()
+                            .map(|condition| condition.source().to_string()),
+                    });
+                }
+            }
+
+            let Some((index, status)) = running.next().await else {
+                break;
+            };
+
+            statuses[index] = Some(status);
+        }
+
+        drop(running);
+        if group.is_none() {
+            progress.finish();
+        }
+
+        context.steps.extend(
+            runs.iter()
+                .zip(&statuses)
+                .map(|(run, status)| (run.name.clone(), status.unwrap())),
+        );
+
+        let first_failure = runs
+            .iter()
+            .zip(&statuses)
+            .find_map(|(run, status)| match status {
+                Some(TaskRunStatus::Failure(exit)) => Some(TaskSetError::Failed(run, *exit)),
+                Some(TaskRunStatus::Interrupted) => Some(TaskSetError::Interrupted(run)),
+                _ => None,
+            });
+
+        match first_failure {
+            Some(failure) => Err(failure),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn resolve_ancestors(runs: &[TaskRun]) -> Result<Vec<BTreeSet<usize>>, TaskSetError<'_>> {
+    let dependencies = runs
+        .iter()
+        .enumerate()
+        .map(|(index, run)| match &run.needs {
+            Some(needs) => needs
+                .iter()
+                .map(|need| {
+                    runs.iter()
+                        .position(|candidate| &candidate.name == need)
+                        .ok_or_else(|| TaskSetError::UnknownDependency(run, need.clone()))
+                })
+                .collect::<Result<Vec<_>, _>>(),
+            None => Ok(index.checked_sub(1).into_iter().collect()),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    runs.iter()
+        .enumerate()
+        .map(|(index, run)| {
+            let mut ancestors = BTreeSet::new();
+            let mut pending = dependencies[index].clone();
+
+            while let Some(dependency) = pending.pop() {
+                if dependency == index {
+                    let cycle = find_cycle(&dependencies, index)
+                        .into_iter()
+                        .map(|i| runs[i].name.clone())
+                        .collect();
+                    return Err(TaskSetError::CyclicDependency(run, cycle));
+                }
+                if ancestors.insert(dependency) {
+                    pending.extend(&dependencies[dependency]);
+                }
+            }
+
+            Ok(ancestors)
+        })
+        .collect()
+}
+
+// The shortest chain of `needs` leading from the task back to itself
+fn find_cycle(dependencies: &[Vec<usize>], start: usize) -> Vec<usize> {
+    let mut previous = vec![None; dependencies.len()];
+    let mut pending = VecDeque::from([start]);
+
+    while let Some(current) = pending.pop_front() {
+        for &next in &dependencies[current] {
+            if next == start {
+                let mut cycle = vec![start];
+                let mut node = Some(current);
+                while let Some(step) = node.filter(|step| *step != start) {
+                    cycle.push(step);
+                    node = previous[step];
+                }
+                cycle[1..].reverse();
+                cycle.push(start);
+                return cycle;
+            }
+            if previous[next].is_none() {
+                previous[next] = Some(current);
+                pending.push_back(next);
+            }
         }
+    }
+
+    vec![start, start]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runs(yaml: &str) -> Vec<TaskRun> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn waits_for_the_previous_task_without_needs() {
+        let runs = runs("[{name: a, run: 'true'}, {name: b, run: 'true'}, {name: c, run: 'true'}]");
+        let ancestors = resolve_ancestors(&runs).unwrap();
+
+        assert_eq!(ancestors[0], BTreeSet::new());
+        assert_eq!(ancestors[1], BTreeSet::from([0]));
+        assert_eq!(ancestors[2], BTreeSet::from([0, 1]));
+    }
+
+    #[test]
+    fn follows_the_needs_transitively() {
+        let runs = runs(
+            "[{name: a, run: 'true', needs: []}, {name: b, run: 'true', needs: []}, \
+             {name: c, run: 'true', needs: [b]}, {name: d, run: 'true', needs: [c]}]",
+        );
+        let ancestors = resolve_ancestors(&runs).unwrap();
+
+        assert_eq!(ancestors[1], BTreeSet::new());
+        assert_eq!(ancestors[3], BTreeSet::from([1, 2]));
+    }
+
+    #[test]
+    fn refuses_an_unknown_dependency() {
+        let runs = runs("[{name: a, run: 'true', needs: [missing]}]");
+        let err = resolve_ancestors(&runs).unwrap_err();
+
+        assert_eq!(err.to_string(), "Task 'a' needs unknown task 'missing'");
+    }
+
+    #[test]
+    fn shows_the_cycle() {
+        let runs = runs(
+            "[{name: a, run: 'true', needs: [c]}, {name: b, run: 'true', needs: [a]}, \
+             {name: c, run: 'true', needs: [b]}]",
+        );
+        let err = resolve_ancestors(&runs).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Task 'a' depends on itself: a -> c -> b -> a"
+        );
+    }
+
+    #[test]
+    fn shows_a_task_needing_itself() {
+        let runs = runs("[{name: a, run: 'true', needs: [a]}]");
+        let err = resolve_ancestors(&runs).unwrap_err();
 
-        Ok(())
+        assert_eq!(err.to_string(), "Task 'a' depends on itself: a -> a");
     }
 }
@@ -1,55 +1,151 @@
-use std::{sync::Arc, time::Duration};
-
-use tokio::sync::Mutex;
+use std::{
+    io::{self, ErrorKind},
+    path::Path,
+    time::Instant,
+};
 
 use crate::{
     adapter::{
         fs::{Entry, EntryType},
         ssh::{
             exec::ExecChannel,
-            transfer::{transfer_file, FileTransferError},
+            transfer::{transfer_content, FileTransferError},
             SSHSession,
         },
     },
-    progress::ProgressView,
+    event::{self, millis, Event},
+    progress::TransferProgress,
+    util::shell_quote,
 };
 
 pub async fn send_directory(
     session: &SSHSession,
     transfer_entries: &[Entry],
 ) -> Result<(), FileTransferError> {
-    let mut progress = ProgressView::new("Enumerating contents");
-    progress.start();
+    // A file that cannot be read is reported when it is sent
+    let sizes: Vec<u64> = transfer_entries.iter().map(Entry::size).collect();
+    let total_files = transfer_entries
+        .iter()
+        .filter(|entry| entry.kind == EntryType::File)
+        .count();
+
+    let total_bytes = sizes.iter().sum();
 
-    let progress = Arc::new(Mutex::new(progress));
-    let total_items = transfer_entries.len();
+    event::emit(Event::TransferStarted {
+        files: total_files,
+        bytes: total_bytes,
+    });
+    let mut progress = TransferProgress::new("Transferring files", total_files, total_bytes);
+    let started = Instant::now();
 
-    progress.lock().await.update_task("Transferring files");
+    // The entries are sent in order, so the transfer resumes from the one being sent when
+    // the connection was lost
+    for (dir, size) in transfer_entries.iter().zip(sizes) {
+        loop {
+            let generation = session.generation();
 
-    for (i, dir) in transfer_entries.iter().enumerate() {
-        match dir.kind {
-            EntryType::File => {
-                transfer_file(session, &dir.local_source, &dir.remote_dest)
+            let result = match dir.kind {
+                EntryType::File => {
+                    progress.start_file(&dir.path_name.display().to_string(), size);
+                    match dir.read() {
+                        Ok(content) => {
+                            transfer_content(session, &content, &dir.remote_dest, &mut |written| {
+                                progress.file_progress(written)
+                            })
+                            .await
+                        }
+                        Err(err) => Err(FileTransferError::Read(err)),
+                    }
+                }
+                EntryType::Dir => create_dir(session, &dir.remote_dest).await,
+                EntryType::Symlink => {
+                    create_symlink(
+                        session,
+                        dir.link_target.as_deref().unwrap_or(Path::new("")),
+                        &dir.remote_dest,
+                    )
                     .await
-                    .unwrap();
-            }
-            EntryType::Dir => {
-                ExecChannel::execute(
-                    session,
-                    &format!("mkdir -p {}", dir.remote_dest.to_str().unwrap()),
-                )
-                .await;
+                }
+            };
+
+            match result {
+                Ok(()) => {
+                    if dir.kind == EntryType::File {
+                        progress.finish_file();
+                    }
+                    event::emit(Event::FileSent {
+                        path: dir.path_name.clone(),
+                        kind: dir.kind,
+                        bytes: size,
+                    });
+                    break;
+                }
+                Err(FileTransferError::Disconnected(_)) => {
+                    if let Err(err) = session.reconnect(generation).await {
+                        progress.failure(&err.to_string());
+                        return Err(FileTransferError::Disconnected(err));
+                    }
+                }
+                Err(err) => {
+                    progress.failure(&format!("{}: {}", dir.local_source.display(), err));
+                    return Err(err);
+                }
             }
         }
-
-        progress.lock().await.report_intermediate(
-            (i + 1, total_items),
-            Some(&dir.local_source.display().to_string()),
-        );
     }
 
-    tokio::time::sleep(Duration::from_millis(20)).await;
-    progress.lock().await.success(Some("Sent all files"));
+    progress.success();
+    event::emit(Event::TransferFinished {
+        files: total_files,
+        bytes: total_bytes,
+        duration_ms: millis(started.elapsed()),
+    });
 
     Ok(())
 }
+
+async fn create_dir(session: &SSHSession, path: &Path) -> Result<(), FileTransferError> {
+    execute(
+        session,
+        &format!("mkdir -p {}", shell_quote(&path.to_string_lossy())),
+        "creating the directory",
+    )
+    .await
+}
+
+// Whatever is in place of the link is replaced, unless it is a directory
+async fn create_symlink(
+    session: &SSHSession,
+    target: &Path,
+    path: &Path,
+) -> Result<(), FileTransferError> {
+    let path = shell_quote(&path.to_string_lossy());
+    execute(
+        session,
+        &format!(
+            "mkdir -p \"$(dirname -- {path})\" && rm -f -- {path} && ln -s -- {} {path}",
+            shell_quote(&target.to_string_lossy())
+        ),
+        "creating the symlink",
+    )
+    .await
+}
+
+async fn execute(
+    session: &SSHSession,
+    command: &str,
+    doing: &str,
+) -> Result<(), FileTransferError> {
+    let executed = ExecChannel::execute(session, command).await;
+
+    match executed.exit_code {
+        Some(0) => Ok(()),
+        Some(_) => Err(FileTransferError::Write(io::Error::other(
+            executed.stderr.trim().to_string(),
+        ))),
+        None => Err(FileTransferError::Disconnected(io::Error::new(
+            ErrorKind::ConnectionAborted,
+            format!("The connection was lost while {}", doing),
+        ))),
+    }
+}
@@ -0,0 +1,689 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    adapter::fs::EntryType,
+    event::{self, Event, Listener, OutputStream, Record},
+    progress::format_bytes,
+    remote::task::TaskRunStatus,
+    util::strip_ansi,
+};
+
+// Lines of the output quoted for a failed step in the Markdown summary
+const MARKDOWN_OUTPUT_LINES: usize = 30;
+
+// Collects the events of a run into the reports for the CI, e.g. `--junit` and `--markdown`
+#[derive(Default)]
+pub struct RunReport {
+    state: Mutex<ReportState>,
+}
+
+#[derive(Default)]
+struct ReportState {
+    hosts: Vec<HostReport>,
+    finished: Option<RunOutcome>,
+}
+
+struct RunOutcome {
+    success: bool,
+    exit_code: i32,
+    error: Option<String>,
+    duration_ms: u64,
+}
+
+struct HostReport {
+    name: String,
+    sync: SyncStats,
+    suites: Vec<SuiteReport>,
+}
+
+#[derive(Default)]
+struct SyncStats {
+    checked: usize,
+    changed: usize,
+    files_sent: usize,
+    bytes_sent: u64,
+    dirs_created: usize,
+    hash_ms: u64,
+}
+
+struct SuiteReport {
+    variant: Option<BTreeMap<String, String>>,
+    cases: Vec<CaseReport>,
+}
+
+struct CaseReport {
+    step: String,
+    status: Option<TaskRunStatus>,
+    exit_code: Option<u8>,
+    duration_ms: u64,
+    stdout: String,
+    stderr: String,
+}
+
+impl RunReport {
+    pub fn subscribe() -> Arc<Self> {
+        let report = Arc::new(Self::default());
+        event::subscribe(report.clone());
+
+        report
+    }
+
+    pub fn write_junit(&self, path: &Path) -> io::Result<()> {
+        std::fs::write(path, self.state.lock().unwrap().junit())
+    }
+
+    pub fn write_markdown(&self, path: &Path) -> io::Result<()> {
+        std::fs::write(path, self.state.lock().unwrap().markdown())
+    }
+}
+
+impl Listener for RunReport {
+    fn on_event(&self, record: &Record) {
+        let mut state = self.state.lock().unwrap();
+
+        if let Event::RunFinished {
+            success,
+            exit_code,
+            error,
+            duration_ms,
+        } = record.event
+        {
+            state.finished = Some(RunOutcome {
+                success: *success,
+                exit_code: *exit_code,
+                error: error.clone(),
+                duration_ms: *duration_ms,
+            });
+            return;
+        }
+
+        let Some(host) = record.host else {
+            return;
+        };
+        let host = state.host(host);
+
+        match record.event {
+            Event::HashStarted { files } => host.sync.checked = *files,
+            Event::HashFinished {
+                changed,
+                duration_ms,
+            } => {
+                host.sync.changed = *changed;
+                host.sync.hash_ms = *duration_ms;
+            }
+            Event::FileSent {
+                kind: EntryType::File | EntryType::Symlink,
+                bytes,
+                ..
+            } => {
+                host.sync.files_sent += 1;
+                host.sync.bytes_sent += bytes;
+            }
+            Event::FileSent {
+                kind: EntryType::Dir,
+                ..
+            } => host.sync.dirs_created += 1,
+            Event::StepStarted { step, .. } => {
+                host.case(record.variant, step);
+            }
+            Event::Output { step, stream, text } => {
+                let case = host.case(record.variant, step);
+                match stream {
+                    OutputStream::Stdout => case.stdout.push_str(text),
+                    OutputStream::Stderr => case.stderr.push_str(text),
+                }
+            }
+            Event::StepFinished {
+                step,
+                status,
+                exit_code,
+                duration_ms,
+                ..
+            } => {
+                let case = host.case(record.variant, step);
+                case.status = Some(*status);
+                case.exit_code = *exit_code;
+                case.duration_ms = *duration_ms;
+            }
+            _ => {}
+        }
+    }
+}
+
+impl ReportState {
+    fn host(&mut self, name: &str) -> &mut HostReport {
+        let index = match self.hosts.iter().position(|host| host.name == name) {
+            Some(index) => index,
+            None => {
+                self.hosts.push(HostReport {
+                    name: name.to_string(),
+                    sync: SyncStats::default(),
+                    suites: Vec::new(),
+                });
+                self.hosts.len() - 1
+            }
+        };
+
+        &mut self.hosts[index]
+    }
+
+    fn suites(&self) -> impl Iterator<Item = (&HostReport, &SuiteReport)> {
+        self.hosts
+            .iter()
+            .flat_map(|host| host.suites.iter().map(move |suite| (host, suite)))
+    }
+
+    fn junit(&self) -> String {
+        let cases = || self.suites().flat_map(|(_, suite)| &suite.cases);
+        let count = |kind: CaseKind| cases().filter(|case| case.kind() == kind).count();
+        let duration_ms = self
+            .finished
+            .as_ref()
+            .map(|outcome| outcome.duration_ms)
+            .unwrap_or_else(|| cases().map(|case| case.duration_ms).sum());
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        writeln!(
+            xml,
+            "<testsuites name=\"difm\" tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{}\">",
+            cases().count(),
+            count(CaseKind::Failure),
+            count(CaseKind::Error),
+            count(CaseKind::Skipped),
+            seconds(duration_ms)
+        )
+        .unwrap();
+
+        for (host, suite) in self.suites() {
+            let name = suite_name(host, suite);
+            let count = |kind: CaseKind| {
+                suite
+                    .cases
+                    .iter()
+                    .filter(|case| case.kind() == kind)
+                    .count()
+            };
+
+            writeln!(
+                xml,
+                "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{}\">",
+                escape_xml(&name),
+                suite.cases.len(),
+                count(CaseKind::Failure),
+                count(CaseKind::Error),
+                count(CaseKind::Skipped),
+                seconds(suite.cases.iter().map(|case| case.duration_ms).sum())
+            )
+            .unwrap();
+
+            for case in &suite.cases {
+                writeln!(
+                    xml,
+                    "    <testcase name=\"{}\" classname=\"{}\" time=\"{}\">",
+                    escape_xml(&case.step),
+                    escape_xml(&name),
+                    seconds(case.duration_ms)
+                )
+                .unwrap();
+
+                if let Some(exit_code) = case.exit_code {
+                    xml.push_str("      <properties>\n");
+                    writeln!(
+                        xml,
+                        "        <property name=\"exit_code\" value=\"{}\"/>",
+                        exit_code
+                    )
+                    .unwrap();
+                    xml.push_str("      </properties>\n");
+                }
+
+                match case.kind() {
+                    CaseKind::Passed => {}
+                    CaseKind::Skipped => xml.push_str("      <skipped/>\n"),
+                    CaseKind::Failure => writeln!(
+                        xml,
+                        "      <failure message=\"{}\" type=\"exit_code\"/>",
+                        escape_xml(&case.message())
+                    )
+                    .unwrap(),
+                    CaseKind::Error => writeln!(
+                        xml,
+                        "      <error message=\"{}\" type=\"interrupted\"/>",
+                        escape_xml(&case.message())
+                    )
+                    .unwrap(),
+                }
+
+                if !case.stdout.is_empty() {
+                    writeln!(
+                        xml,
+                        "      <system-out>{}</system-out>",
+                        escape_xml(&strip_ansi(&case.stdout))
+                    )
+                    .unwrap();
+                }
+                if !case.stderr.is_empty() {
+                    writeln!(
+                        xml,
+                        "      <system-err>{}</system-err>",
+                        escape_xml(&strip_ansi(&case.stderr))
+                    )
+                    .unwrap();
+                }
+
+                xml.push_str("    </testcase>\n");
+            }
+
+            xml.push_str("  </testsuite>\n");
+        }
+
+        xml.push_str("</testsuites>\n");
+        xml
+    }
+
+    fn markdown(&self) -> String {
+        let mut markdown = String::new();
+
+        let headline = match &self.finished {
+            Some(outcome) if outcome.success => {
+                format!("✅ difm passed in {}s", seconds(outcome.duration_ms))
+            }
+            Some(outcome) => format!(
+                "❌ difm failed with exit code {} in {}s",
+                outcome.exit_code,
+                seconds(outcome.duration_ms)
+            ),
+            None => "⚠️ difm did not finish".to_string(),
+        };
+        writeln!(markdown, "### {}\n", headline).unwrap();
+
+        if let Some(error) = self
+            .finished
+            .as_ref()
+            .and_then(|outcome| outcome.error.as_ref())
+        {
+            writeln!(
+                markdown,
+                "> {}\n",
+                error.lines().collect::<Vec<_>>().join("\n> ")
+            )
+            .unwrap();
+        }
+
+        markdown.push_str("#### Sync\n\n");
+        markdown.push_str(
+            "| Host | Checked | Changed | Files sent | Size sent | Directories created | Hashing |\n",
+        );
+        markdown.push_str(
+            "| ---- | ------: | ------: | ---------: | --------: | ------------------: | ------: |\n",
+        );
+        for host in &self.hosts {
+            writeln!(
+                markdown,
+                "| {} | {} | {} | {} | {} | {} | {}s |",
+                escape_markdown(&host.name),
+                host.sync.checked,
+                host.sync.changed,
+                host.sync.files_sent,
+                format_bytes(host.sync.bytes_sent),
+                host.sync.dirs_created,
+                seconds(host.sync.hash_ms)
+            )
+            .unwrap();
+        }
+
+        markdown.push_str("\n#### Steps\n\n");
+        markdown.push_str("| | Suite | Step | Exit code | Duration |\n");
+        markdown.push_str("| - | ----- | ---- | --------: | -------: |\n");
+        for (host, suite) in self.suites() {
+            for case in &suite.cases {
+                writeln!(
+                    markdown,
+                    "| {} | {} | {} | {} | {}s |",
+                    case.kind().mark(),
+                    escape_markdown(&suite_name(host, suite)),
+                    escape_markdown(&case.step),
+                    case.exit_code
+                        .map(|code| code.to_string())
+                        .unwrap_or_default(),
+                    seconds(case.duration_ms)
+                )
+                .unwrap();
+            }
+        }
+
+        // The end of the output usually tells why the step failed
+        for (host, suite) in self.suites() {
+            for case in &suite.cases {
+                if !matches!(case.kind(), CaseKind::Failure | CaseKind::Error) {
+                    continue;
+                }
+
+                // The control characters left after the colors would garble the page
+                let output: String = strip_ansi(&format!("{}{}", case.stdout, case.stderr))
+                    .chars()
+                    .filter(|char| !char.is_control() || matches!(char, '\t' | '\n'))
+                    .collect();
+                let lines: Vec<_> = output.lines().collect();
+                let tail = &lines[lines.len().saturating_sub(MARKDOWN_OUTPUT_LINES)..];
+
+                writeln!(
+                    markdown,
+                    "\n<details><summary>{} / {}: {}</summary>\n\n```\n{}\n```\n</details>",
+                    escape_markdown(&suite_name(host, suite)),
+                    escape_markdown(&case.step),
+                    case.message(),
+                    tail.join("\n").replace("```", "` ` `")
+                )
+                .unwrap();
+            }
+        }
+
+        markdown
+    }
+}
+
+impl HostReport {
+    fn case(&mut self, variant: Option<&BTreeMap<String, String>>, step: &str) -> &mut CaseReport {
+        let suite = match self
+            .suites
+            .iter()
+            .position(|suite| suite.variant.as_ref() == variant)
+        {
+            Some(index) => &mut self.suites[index],
+            None => {
+                self.suites.push(SuiteReport {
+                    variant: variant.cloned(),
+                    cases: Vec::new(),
+                });
+                self.suites.last_mut().unwrap()
+            }
+        };
+
+        let index = match suite.cases.iter().position(|case| case.step == step) {
+            Some(index) => index,
+            None => {
+                suite.cases.push(CaseReport {
+                    step: step.to_string(),
+                    status: None,
+                    exit_code: None,
+                    duration_ms: 0,
+                    stdout: String::new(),
+                    stderr: String::new(),
+                });
+                suite.cases.len() - 1
+            }
+        };
+
+        &mut suite.cases[index]
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CaseKind {
+    Passed,
+    Skipped,
+    // The step exited with a non-zero code
+    Failure,
+    // The step could not be run to the end, e.g. the connection was lost
+    Error,
+}
+
+impl CaseKind {
+    fn mark(self) -> &'static str {
+        match self {
+            CaseKind::Passed => "✅",
+            CaseKind::Skipped => "⏭️",
+            CaseKind::Failure => "❌",
+            CaseKind::Error => "⚠️",
+        }
+    }
+}
+
+impl CaseReport {
+    fn kind(&self) -> CaseKind {
+        match self.status {
+            Some(TaskRunStatus::Success) => CaseKind::Passed,
+            Some(TaskRunStatus::Skipped) => CaseKind::Skipped,
+            Some(TaskRunStatus::Failure(_)) => CaseKind::Failure,
+            Some(TaskRunStatus::Interrupted) | None => CaseKind::Error,
+        }
+    }
+
+    fn message(&self) -> String {
+        match (self.status, self.exit_code) {
+            (Some(TaskRunStatus::Failure(_)), Some(code)) => format!("Exited with code {}", code),
+            (Some(TaskRunStatus::Interrupted), _) => {
+                "Interrupted, as the connection was lost".to_string()
+            }
+            (None, _) => "Did not finish".to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+fn suite_name(host: &HostReport, suite: &SuiteReport) -> String {
+    match &suite.variant {
+        Some(variant) => {
+            let values: Vec<_> = variant
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            format!("{} ({})", host.name, values.join(", "))
+        }
+        None => host.name.clone(),
+    }
+}
+
+fn seconds(millis: u64) -> String {
+    format!("{:.3}", millis as f64 / 1000.0)
+}
+
+// Control characters other than the whitespace, and the noncharacters U+FFFE and U+FFFF, are not
+// allowed even as character references
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for char in text.chars() {
+        match char {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(char),
+            char if char.is_control() => {}
+            '\u{fffe}' | '\u{ffff}' => {}
+            char => escaped.push(char),
+        }
+    }
+
+    escaped
+}
+
+fn escape_markdown(text: &str) -> String {
+    text.replace('|', "\\|")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU8;
+
+    use super::*;
+
+    fn emit(report: &RunReport, variant: Option<&BTreeMap<String, String>>, event: Event) {
+        report.on_event(&Record {
+            version: 1,
+            timestamp_ms: 0,
+            host: Some("box"),
+            variant,
+            event: &event,
+        });
+    }
+
+    fn finish(
+        report: &RunReport,
+        variant: Option<&BTreeMap<String, String>>,
+        step: &str,
+        status: TaskRunStatus,
+        exit_code: Option<u8>,
+    ) {
+        emit(
+            report,
+            variant,
+            Event::StepFinished {
+                step: step.to_string(),
+                status,
+                exit_code,
+                duration_ms: 1500,
+                condition: None,
+            },
+        );
+    }
+
+    fn output(report: &RunReport, step: &str, stream: OutputStream, text: &str) {
+        emit(
+            report,
+            None,
+            Event::Output {
+                step: step.to_string(),
+                stream,
+                text: text.to_string(),
+            },
+        );
+    }
+
+    // A step of each kind, with the output of a failed one that has to be escaped
+    fn run() -> RunReport {
+        let report = RunReport::default();
+        emit(&report, None, Event::HashStarted { files: 12 });
+        emit(
+            &report,
+            None,
+            Event::FileSent {
+                path: "src/main.rs".into(),
+                kind: EntryType::File,
+                bytes: 2048,
+            },
+        );
+        finish(&report, None, "build", TaskRunStatus::Success, Some(0));
+        output(
+            &report,
+            "test | unit",
+            OutputStream::Stdout,
+            "\x1b[31m]]> <fail> & ```\x1b[0m\n",
+        );
+        output(
+            &report,
+            "test | unit",
+            OutputStream::Stderr,
+            "oops\x00\x07\n",
+        );
+        finish(
+            &report,
+            None,
+            "test | unit",
+            TaskRunStatus::Failure(NonZeroU8::new(2).unwrap()),
+            Some(2),
+        );
+        finish(&report, None, "lint", TaskRunStatus::Skipped, None);
+        emit(
+            &report,
+            None,
+            Event::StepStarted {
+                step: "deploy".to_string(),
+                tty: false,
+            },
+        );
+        emit(
+            &report,
+            None,
+            Event::RunFinished {
+                success: false,
+                exit_code: 1,
+                error: Some("The step failed\nwith code 2".to_string()),
+                duration_ms: 4000,
+            },
+        );
+
+        report
+    }
+
+    #[test]
+    fn escapes_what_xml_does_not_allow() {
+        assert_eq!(
+            escape_xml("a<b>&\"c\" 'd' ]]>"),
+            "a&lt;b&gt;&amp;&quot;c&quot; &apos;d&apos; ]]&gt;"
+        );
+        assert_eq!(escape_xml("a\tb\r\nc"), "a\tb\r\nc");
+        assert_eq!(escape_xml("\x00\x08\x1b\x7f\u{fffe}ok\u{ffff}"), "ok");
+        assert_eq!(escape_xml("é ✓"), "é ✓");
+    }
+
+    #[test]
+    fn writes_a_test_case_per_step() {
+        let junit = run().state.lock().unwrap().junit();
+
+        assert!(junit.contains(
+            "<testsuites name=\"difm\" tests=\"4\" failures=\"1\" errors=\"1\" skipped=\"1\" time=\"4.000\">"
+        ));
+        assert!(junit.contains(
+            "<testcase name=\"test | unit\" classname=\"box\" time=\"1.500\">\n      <properties>\n        <property name=\"exit_code\" value=\"2\"/>\n      </properties>\n      <failure message=\"Exited with code 2\" type=\"exit_code\"/>\n      <system-out>]]&gt; &lt;fail&gt; &amp; ```\n</system-out>\n      <system-err>oops\n</system-err>\n"
+        ));
+        assert!(junit.contains(
+            "<testcase name=\"lint\" classname=\"box\" time=\"1.500\">\n      <skipped/>\n"
+        ));
+        assert!(junit.contains("<error message=\"Did not finish\" type=\"interrupted\"/>"));
+    }
+
+    #[test]
+    fn names_the_suites_by_the_variant() {
+        let report = RunReport::default();
+        let variant = BTreeMap::from([("os".to_string(), "linux".to_string())]);
+        finish(
+            &report,
+            Some(&variant),
+            "test",
+            TaskRunStatus::Success,
+            Some(0),
+        );
+        let junit = report.state.lock().unwrap().junit();
+
+        assert!(junit.contains("<testsuite name=\"box (os=linux)\" tests=\"1\""));
+    }
+
+    #[test]
+    fn summarizes_the_run_in_markdown() {
+        let markdown = run().state.lock().unwrap().markdown();
+
+        assert!(markdown.starts_with(
+            "### ❌ difm failed with exit code 1 in 4.000s\n\n> The step failed\n> with code 2\n"
+        ));
+        assert!(markdown.contains("| box | 12 | 0 | 1 | 2.0 KiB | 0 | 0.000s |"));
+        assert!(markdown.contains("| ❌ | box | test \\| unit | 2 | 1.500s |"));
+        assert!(markdown.contains("| ⏭️ | box | lint |  | 1.500s |"));
+        assert!(markdown.contains(
+            "<details><summary>box / test \\| unit: Exited with code 2</summary>\n\n```\n]]> <fail> & ` ` `\noops\n```\n</details>"
+        ));
+        assert!(markdown.contains("<summary>box / deploy: Did not finish</summary>"));
+    }
+
+    #[test]
+    fn writes_the_reports_of_an_empty_run() {
+        let state = RunReport::default();
+        let state = state.state.lock().unwrap();
+
+        assert_eq!(
+            state.junit(),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites name=\"difm\" tests=\"0\" failures=\"0\" errors=\"0\" skipped=\"0\" time=\"0.000\">\n</testsuites>\n"
+        );
+        assert!(state
+            .markdown()
+            .starts_with("### ⚠️ difm did not finish\n\n#### Sync\n"));
+    }
+}
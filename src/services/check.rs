@@ -0,0 +1,38 @@
+use std::path::PathBuf;
+
+use crate::{
+    config::{diagnose, schema::schema, ConfigError},
+    error::{DifmError, EXIT_CONFIG},
+};
+
+pub fn check_config(
+    path: Option<PathBuf>,
+    profiles: &[String],
+    overrides: &[(String, String)],
+    print_schema: bool,
+) -> i32 {
+    if print_schema {
+        println!("{}", serde_json::to_string_pretty(&schema()).unwrap());
+        return 0;
+    }
+
+    let path = path.unwrap_or("./difm.yaml".into());
+    let diagnostics = match diagnose(&path, profiles, overrides) {
+        Ok(diagnostics) => diagnostics,
+        Err(err) => {
+            let err = DifmError::from(err);
+            err.report();
+            return err.exit_code();
+        }
+    };
+
+    if diagnostics.is_empty() {
+        println!("✅ {} is valid", path.display());
+        return 0;
+    }
+
+    let count = diagnostics.len();
+    eprintln!("{}", ConfigError::Invalid(path, diagnostics));
+    eprintln!("[!] Found {} problem(s)", count);
+    EXIT_CONFIG
+}
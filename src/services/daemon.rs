@@ -0,0 +1,114 @@
+use std::{
+    io,
+    os::unix::process::CommandExt,
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use crate::{
+    cli::DaemonCommand,
+    daemon::{
+        client::request,
+        protocol::{Request, Response},
+        server::serve,
+    },
+    error::DifmError,
+    progress::ProgressView,
+};
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub async fn manage_daemon(command: DaemonCommand) -> Result<(), DifmError> {
+    match command {
+        DaemonCommand::Start { idle_timeout } => start(idle_timeout).await,
+        DaemonCommand::Status => {
+            status().await;
+            Ok(())
+        }
+        DaemonCommand::Stop => {
+            stop().await;
+            Ok(())
+        }
+        DaemonCommand::Serve { idle_timeout } => serve(Duration::from_secs(idle_timeout)).await,
+    }
+}
+
+async fn start(idle_timeout: u64) -> Result<(), DifmError> {
+    if request(&Request::Status).await.is_ok() {
+        println!("The daemon is already running");
+        return Ok(());
+    }
+
+    let failed = |source| DifmError::Daemon {
+        action: "start the daemon".to_string(),
+        source,
+    };
+
+    let mut progress = ProgressView::new("Starting the daemon..");
+    progress.start();
+
+    // Detached from our process group, so that Ctrl-C on the terminal does not reach it
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(err) => {
+            progress.failure(None);
+            return Err(failed(err));
+        }
+    };
+    let spawned = Command::new(exe)
+        .args([
+            "daemon",
+            "serve",
+            "--idle-timeout",
+            &idle_timeout.to_string(),
+        ])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn();
+    if let Err(err) = spawned {
+        progress.failure(None);
+        return Err(failed(err));
+    }
+
+    let started_at = std::time::Instant::now();
+    while started_at.elapsed() < STARTUP_TIMEOUT {
+        if let Ok(Response::Status(status)) = request(&Request::Status).await {
+            progress.success(Some(&format!("Started (pid {})", status.pid)));
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    progress.failure(None);
+    Err(failed(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "the daemon did not respond",
+    )))
+}
+
+async fn status() {
+    let Ok(Response::Status(status)) = request(&Request::Status).await else {
+        println!("The daemon is not running");
+        return;
+    };
+
+    println!(
+        "The daemon is running (pid {}, up for {}s, stops after {}s of inactivity)",
+        status.pid, status.uptime_secs, status.idle_timeout_secs
+    );
+    if status.sessions.is_empty() {
+        println!("No sessions are held");
+    }
+    for session in status.sessions {
+        println!("- {} (idle for {}s)", session.host, session.idle_secs);
+    }
+}
+
+async fn stop() {
+    match request(&Request::Stop).await {
+        Ok(_) => println!("Stopped the daemon"),
+        Err(_) => println!("The daemon is not running"),
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    adapter::fs::Entry,
+    condition::ConditionContext,
+    config::{TaskArtifact, TaskRun},
+    event::{self, Event},
+    progress,
+    remote::{
+        artifact::local_dest,
+        task::{resolve_ancestors, TaskRunStatus, TaskRunner, TaskSetError},
+    },
+};
+
+use super::workspace::Workspace;
+
+// difm never removes the files on the host, so there is nothing to be deleted to tell about
+pub fn show_changes(entries: &[Entry]) {
+    if entries.is_empty() {
+        return;
+    }
+
+    progress::report(&format!(
+        "Would send {} files (the ones removed locally are left on the host):",
+        entries.len()
+    ));
+    for entry in entries {
+        event::emit(Event::PlannedUpload {
+            path: entry.relative_path().to_path_buf(),
+            bytes: entry.size(),
+        });
+    }
+}
+
+// The conditions are evaluated as if all of the steps before have passed
+pub fn show_steps<'a>(
+    workspace: &Workspace,
+    runs: &'a [TaskRun],
+    artifacts: &[TaskArtifact],
+    context: &ConditionContext,
+    host_dir: Option<&str>,
+) -> Result<(), TaskSetError<'a>> {
+    let ancestors = resolve_ancestors(runs)?;
+
+    progress::report("Would run:");
+    for (run, ancestors) in runs.iter().zip(ancestors) {
+        let mut step_context = context.clone();
+        step_context.steps.extend(
+            ancestors
+                .iter()
+                .map(|i| (runs[*i].name.clone(), TaskRunStatus::Success)),
+        );
+
+        let env: BTreeMap<_, _> = TaskRunner::pty(run)
+            .map(|pty| ("TERM".to_string(), pty.term))
+            .into_iter()
+            .collect();
+
+        event::emit(Event::PlannedStep {
+            step: run.name.clone(),
+            command: TaskRunner::command(&workspace.code_dir, run),
+            cwd: TaskRunner::cwd(&workspace.code_dir, run),
+            env,
+            condition: run
+                .condition
+                .as_ref()
+                .map(|condition| condition.source().to_string()),
+            would_run: run
+                .condition
+                .as_ref()
+                .is_none_or(|condition| condition.evaluate(&step_context)),
+        });
+    }
+
+    if !artifacts.is_empty() {
+        progress::report("Would fetch:");
+    }
+    for artifact in artifacts {
+        event::emit(Event::PlannedArtifact {
+            remote_path: workspace.code_dir.join(&artifact.remote_path),
+            local_path: local_dest(artifact, host_dir),
+        });
+    }
+
+    Ok(())
+}
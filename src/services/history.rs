@@ -0,0 +1,174 @@
+use std::path::PathBuf;
+
+use crate::{
+    error::DifmError,
+    event::now_ms,
+    history::{history_dir, load_runs, read_log, RunMetadata, StepRecord},
+    progress::{paint, ESEQ_GREEN, ESEQ_RED, ESEQ_RESET, ESEQ_WEAK},
+};
+
+pub fn show_history(config: Option<PathBuf>, limit: usize) -> Result<(), DifmError> {
+    let config_file = config.unwrap_or("./difm.yaml".into());
+    let runs = load_runs(&config_file).map_err(|err| {
+        DifmError::Usage(format!(
+            "Could not read the runs in {}: {}",
+            history_dir(&config_file).display(),
+            err
+        ))
+    })?;
+
+    if runs.is_empty() {
+        println!("No runs are recorded yet");
+        return Ok(());
+    }
+
+    println!(
+        "{}",
+        paint(&format!(
+            "\x1b[1m{:36}  {:>9}  {:>9}  {:20}  HOSTS\x1b[m",
+            "ID", "STARTED", "DURATION", "RESULT"
+        ))
+    );
+    for run in runs.iter().take(limit) {
+        let (color, result) = match &run.finished {
+            Some(outcome) if outcome.success => (ESEQ_GREEN, "✓ passed".to_string()),
+            Some(outcome) => (ESEQ_RED, format!("! failed ({})", outcome.exit_code)),
+            None => (ESEQ_WEAK, "- unfinished".to_string()),
+        };
+        let duration = run
+            .finished
+            .as_ref()
+            .map(|outcome| format!("{:.1}s", outcome.duration_ms as f64 / 1000.0))
+            .unwrap_or_default();
+
+        println!(
+            "{}",
+            paint(&format!(
+                "{:36}  {:>9}  {:>9}  {color}{:20}{ESEQ_RESET}  {}",
+                run.id,
+                format_age(now_ms().saturating_sub(run.started_ms)),
+                duration,
+                result,
+                run.hosts.join(", ")
+            ))
+        );
+    }
+
+    Ok(())
+}
+
+// `run` is `last`, or the ID of the run or a unique prefix of it
+pub fn show_logs(config: Option<PathBuf>, run: &str, step: Option<&str>) -> Result<(), DifmError> {
+    let config_file = config.unwrap_or("./difm.yaml".into());
+    let runs = load_runs(&config_file).map_err(|err| {
+        DifmError::Usage(format!(
+            "Could not read the runs in {}: {}",
+            history_dir(&config_file).display(),
+            err
+        ))
+    })?;
+
+    let run = find_run(&runs, run)?;
+    let steps: Vec<&StepRecord> = run
+        .steps
+        .iter()
+        .filter(|record| step.is_none_or(|step| record.step == step))
+        .collect();
+
+    if let (Some(step), true) = (step, steps.is_empty()) {
+        let mut names: Vec<_> = run
+            .steps
+            .iter()
+            .map(|record| record.step.as_str())
+            .collect();
+        names.sort();
+        names.dedup();
+        return Err(DifmError::Usage(format!(
+            "Run {} has no step '{}' (available: {})",
+            run.id,
+            step,
+            names.join(", ")
+        )));
+    }
+
+    if step.is_none() {
+        println!("{}", paint(&format!("\x1b[1mRun {}\x1b[m", run.id)));
+        println!("  command: {}", run.args.join(" "));
+        if let Some(error) = run
+            .finished
+            .as_ref()
+            .and_then(|outcome| outcome.error.as_ref())
+        {
+            println!("  error: {}", error);
+        }
+    }
+
+    for record in steps {
+        let variant = record
+            .variant
+            .as_ref()
+            .map(|variant| {
+                let values: Vec<_> = variant
+                    .iter()
+                    .map(|(name, value)| format!("{}={}", name, value))
+                    .collect();
+                format!(" ({})", values.join(", "))
+            })
+            .unwrap_or_default();
+        let exit_code = record
+            .exit_code
+            .map(|code| format!(", exit code {}", code))
+            .unwrap_or_default();
+
+        println!(
+            "{}",
+            paint(&format!(
+                "\n\x1b[1m----- {}{} / {}: {}{} -----\x1b[m",
+                record.host, variant, record.step, record.status, exit_code
+            ))
+        );
+        if let Some(log) = &record.log {
+            match read_log(&config_file, run, log) {
+                Ok(output) => print!("{}", output),
+                Err(err) => eprintln!("[!] Could not read {}: {}", log.display(), err),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn find_run<'a>(runs: &'a [RunMetadata], query: &str) -> Result<&'a RunMetadata, DifmError> {
+    if query == "last" {
+        return runs
+            .first()
+            .ok_or_else(|| DifmError::Usage("No runs are recorded yet".to_string()));
+    }
+
+    let matched: Vec<_> = runs
+        .iter()
+        .filter(|run| run.id.starts_with(query))
+        .collect();
+    match matched.as_slice() {
+        [run] => Ok(run),
+        [] => Err(DifmError::Usage(format!(
+            "No run matches {} (see `difm history`)",
+            query
+        ))),
+        _ => Err(DifmError::Usage(format!(
+            "{} runs match {}, give more of the ID",
+            matched.len(),
+            query
+        ))),
+    }
+}
+
+fn format_age(millis: u64) -> String {
+    let seconds = millis / 1000;
+    match seconds {
+        0..=59 => format!("{}s ago", seconds),
+        60..=3599 => format!("{}m ago", seconds / 60),
+        3600..=86399 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
+    }
+}
@@ -0,0 +1,27 @@
+use crate::{
+    adapter::fs::{EntryType, FileTransferList},
+    config::{ConfigContext, Configuration},
+    error::DifmError,
+};
+
+// Only tells the local side, so the files unchanged on the host are listed too
+pub fn list_files(config_ctx: &ConfigContext) -> Result<(), DifmError> {
+    let Configuration::TaskDefinition(task) = &config_ctx.config;
+
+    let list = FileTransferList::new(&task.code, &task.code.dest, &config_ctx.config_file);
+    let mut files: Vec<_> = list
+        .traverse_dir()
+        .map_err(|err| DifmError::Usage(format!("Could not list the files: {}", err)))?
+        .filter(|entry| entry.kind != EntryType::Dir)
+        .collect();
+    files.sort_by(|a, b| a.relative_path().cmp(b.relative_path()));
+
+    for file in files {
+        match &file.link_target {
+            Some(target) => println!("{} -> {}", file.relative_path().display(), target.display()),
+            None => println!("{}", file.relative_path().display()),
+        }
+    }
+
+    Ok(())
+}
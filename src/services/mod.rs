@@ -1,2 +1,10 @@
+pub mod check;
+pub mod daemon;
+pub mod dry_run;
 pub mod execute;
+pub mod history;
+pub mod ls_files;
 pub mod run_task;
+pub mod shell;
+pub mod tunnel;
+pub mod workspace;
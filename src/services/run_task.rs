@@ -1,33 +1,297 @@
+use std::{io, path::Path, sync::Arc};
+
+use futures::future::join_all;
+
 use crate::{
-    adapter::fs::FileTransferList,
-    config::{ssh::SSHConfig, ConfigContext, Configuration},
-    remote::{integrity::check_file_change, task::TaskRunner, transfer::send_directory},
+    adapter::fs::Entry,
+    condition::ConditionContext,
+    config::{
+        check::Diagnostic, matrix::MatrixVariant, ConfigContext, ConfigError, Configuration,
+        TaskHost,
+    },
+    error::DifmError,
+    event::EventScope,
+    progress::{
+        self, MultiProgressView, ProgressGroup, Verbosity, ESEQ_GREEN, ESEQ_RED, ESEQ_RESET,
+        ESEQ_WEAK,
+    },
+    remote::{
+        artifact::fetch_artifacts,
+        forward::PortForwarding,
+        task::{TaskRunner, TaskSetError},
+    },
 };
 
-pub async fn run_task(config_ctx: &ConfigContext) {
+use super::{
+    dry_run::{show_changes, show_steps},
+    workspace::{select_host, Workspace},
+};
+
+// The result of the whole task, or the one of each of the matrix variants
+type HostResults = Vec<(Option<MatrixVariant>, Result<(), DifmError>)>;
+
+// Fails with the first error, after the summary of all the hosts and the variants if there are several
+pub async fn run_task(
+    config_ctx: &ConfigContext,
+    host: Option<&str>,
+    use_daemon: bool,
+    dry_run: bool,
+) -> Result<(), DifmError> {
     let Configuration::TaskDefinition(task) = &config_ctx.config; //  else { unreachable!(); };
-    let session = SSHConfig::new(&task.host.name).open();
 
-    let dirs = FileTransferList::new(
-        &task.code.location,
-        &task.host.base_dir.join(&task.code.dest),
-        &task.code.ignore,
-        &config_ctx.config_file,
-    );
+    let hosts: Vec<&TaskHost> = match host {
+        Some(name) => vec![select_host(task, Some(name))?],
+        None => task.host.iter().collect(),
+    };
 
-    let entries = check_file_change(&session, &dirs).await.unwrap();
+    let results: Vec<(String, HostResults)> = if let [host] = hosts.as_slice() {
+        let scope = EventScope::host(&host.name);
+        let workspace = scope
+            .clone()
+            .scope(Workspace::open(
+                task,
+                host,
+                use_daemon && task.forward.is_empty(),
+            ))
+            .await?;
+        let view = Arc::new(MultiProgressView::new());
+        let result = ProgressGroup::unlabelled(view.clone())
+            .scope(scope.scope(run_on(&workspace, config_ctx, false, dry_run)))
+            .await;
+        view.finish();
 
-    if entries.is_empty() {
-        println!("No files is required to be send");
+        vec![(host.name.clone(), result)]
     } else {
-        for entry in &entries {
-            println!("- {}", entry);
+        // Connecting might ask for the passwords, so the hosts are connected one by one
+        let mut workspaces = Vec::new();
+        let mut unreachable = Vec::new();
+        for host in hosts {
+            progress::report(&format!(
+                "\n{ESEQ_WEAK}----- {} -----{ESEQ_RESET}",
+                host.name
+            ));
+            let opened = EventScope::host(&host.name)
+                .scope(Workspace::open(task, host, use_daemon))
+                .await;
+            match opened {
+                Ok(workspace) => workspaces.push(workspace),
+                Err(err) => {
+                    err.report();
+                    unreachable.push((host.name.clone(), vec![(None, Err(err))]));
+                }
+            }
         }
-        send_directory(&session, &entries).await.unwrap();
+
+        if !task.forward.is_empty() {
+            eprintln!(
+                "[!] Ports are not forwarded while working on several hosts (choose one with --host)"
+            );
+        }
+        progress::report("");
+
+        let view = Arc::new(MultiProgressView::new());
+        let results = join_all(workspaces.iter().map(|workspace| {
+            let run = EventScope::host(&workspace.host.name)
+                .scope(run_on(workspace, config_ctx, true, dry_run));
+            ProgressGroup::new(view.clone(), &workspace.host.name).scope(run)
+        }))
+        .await;
+        view.finish();
+
+        workspaces
+            .into_iter()
+            .map(|workspace| workspace.host.name)
+            .zip(results)
+            .chain(unreachable)
+            .collect()
+    };
+
+    if results.len() > 1 || task.matrix.is_some() {
+        print_summary(&results);
     }
+    if dry_run {
+        progress::report("\nDry run: nothing was sent or run on the host");
+    }
+
+    results
+        .into_iter()
+        .flat_map(|(_, results)| results)
+        .find_map(|(_, result)| result.err())
+        .map_or(Ok(()), Err)
+}
+
+async fn run_on(
+    workspace: &Workspace,
+    config_ctx: &ConfigContext,
+    fan_out: bool,
+    dry_run: bool,
+) -> HostResults {
+    let Configuration::TaskDefinition(task) = &config_ctx.config;
+
+    let entries = match dry_run {
+        true => workspace
+            .changes(task, &config_ctx.config_file)
+            .await
+            .inspect(|entries| show_changes(entries)),
+        false => workspace.sync(task, &config_ctx.config_file).await,
+    };
+    let entries = match entries {
+        Ok(entries) => entries,
+        Err(err) => return vec![(None, Err(err))],
+    };
 
-    TaskRunner::new(&session)
-        .perform_task_set(&task.host.base_dir, &task.run)
+    let _forwarding = match fan_out || dry_run {
+        true => None,
+        // The steps may not need the forwards, which are reported as they fail
+        false => Some(
+            PortForwarding::start(&workspace.session, &task.forward)
+                .await
+                .0,
+        ),
+    };
+
+    let Some(matrix) = &task.matrix else {
+        let result = run_variant(workspace, config_ctx, &entries, None, fan_out, dry_run).await;
+        return vec![(None, result)];
+    };
+
+    // The variants share the code directory, so they cannot run at the same time
+    let mut results = Vec::new();
+    for variant in matrix.expand() {
+        let run = EventScope::current()
+            .with_variant(&variant)
+            .scope(run_variant(
+                workspace,
+                config_ctx,
+                &entries,
+                Some(&variant),
+                fan_out,
+                dry_run,
+            ));
+        let result = match ProgressGroup::current().filter(|_| fan_out) {
+            Some(group) => {
+                group
+                    .relabel(&format!("{} {}", workspace.host.name, variant))
+                    .scope(run)
+                    .await
+            }
+            None => {
+                progress::report(&format!(
+                    "\n{ESEQ_WEAK}----- Variant: {} -----{ESEQ_RESET}",
+                    variant
+                ));
+                run.await
+            }
+        };
+
+        results.push((Some(variant), result));
+    }
+
+    results
+}
+
+async fn run_variant(
+    workspace: &Workspace,
+    config_ctx: &ConfigContext,
+    entries: &[Entry],
+    variant: Option<&MatrixVariant>,
+    fan_out: bool,
+    dry_run: bool,
+) -> Result<(), DifmError> {
+    let Configuration::TaskDefinition(task) = &config_ctx.config;
+    // Without a matrix, only the escapes are written out
+    let (runs, artifacts) =
+        variant
+            .cloned()
+            .unwrap_or_default()
+            .apply(task)
+            .map_err(|diagnostic| {
+                ConfigError::Invalid(config_ctx.config_file.clone(), vec![diagnostic])
+            })?;
+
+    let mut context = ConditionContext::new(task, &workspace.host, entries);
+    if let Some(variant) = variant {
+        context.matrix = variant.values().to_vec();
+    }
+
+    let host_dir = fan_out.then_some(workspace.host.name.as_str());
+    if dry_run {
+        return show_steps(workspace, &runs, &artifacts, &context, host_dir)
+            .map_err(|err| task_set_error(err, &workspace.host, &config_ctx.config_file));
+    }
+
+    TaskRunner::new(&workspace.session)
+        .perform_task_set(&workspace.code_dir, &runs, &mut context)
         .await
-        .unwrap();
+        .map_err(|err| task_set_error(err, &workspace.host, &config_ctx.config_file))?;
+
+    fetch_artifacts(
+        &workspace.session,
+        &workspace.code_dir,
+        &artifacts,
+        host_dir,
+    )
+    .await
+    .map_err(|source| DifmError::Transfer {
+        host: workspace.host.name.clone(),
+        action: "fetch the artifacts".to_string(),
+        source,
+    })
+}
+
+fn task_set_error(err: TaskSetError, host: &TaskHost, config_file: &Path) -> DifmError {
+    match err {
+        TaskSetError::Failed(..) => DifmError::StepFailed(err.to_string()),
+        TaskSetError::Interrupted(run) => DifmError::Exec {
+            host: host.name.clone(),
+            action: format!("finish the task '{}'", run.name),
+            source: io::Error::new(io::ErrorKind::ConnectionAborted, "The connection was lost"),
+        },
+        TaskSetError::UnknownDependency(..) | TaskSetError::CyclicDependency(..) => {
+            DifmError::Config(ConfigError::Invalid(
+                config_file.to_path_buf(),
+                vec![Diagnostic::new(&[], err.to_string())],
+            ))
+        }
+    }
+}
+
+fn print_summary(results: &[(String, HostResults)]) {
+    let rows: Vec<_> = results
+        .iter()
+        .flat_map(|(host, results)| {
+            results.iter().map(move |(variant, result)| {
+                let variant = variant
+                    .as_ref()
+                    .map(|variant| variant.to_string())
+                    .unwrap_or_default();
+                (host.as_str(), variant, result)
+            })
+        })
+        .collect();
+
+    let host_width = rows
+        .iter()
+        .map(|(host, _, _)| host.len())
+        .max()
+        .unwrap_or(0);
+    let variant_width = rows
+        .iter()
+        .map(|(_, variant, _)| variant.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    progress::report_at(Verbosity::Quiet, "\n\x1b[1m----- Summary -----\x1b[m");
+    for (host, variant, result) in rows {
+        let (color, mark, message) = match result {
+            Ok(()) => (ESEQ_GREEN, "✓", "passed".to_string()),
+            Err(err) => (ESEQ_RED, "!", err.to_string()),
+        };
+        progress::report_at(
+            Verbosity::Quiet,
+            &format!(
+                "{color}{mark} {host:host_width$}  {variant:variant_width$}  {message}{ESEQ_RESET}"
+            ),
+        );
+    }
 }
@@ -0,0 +1,35 @@
+use crate::{
+    adapter::ssh::shell::ShellChannel,
+    config::{ConfigContext, Configuration},
+    error::DifmError,
+};
+
+use super::workspace::{select_host, Workspace};
+
+// Returns the exit status of the shell
+pub async fn open_shell(
+    config_ctx: &ConfigContext,
+    host: Option<&str>,
+    sync: bool,
+) -> Result<i32, DifmError> {
+    let Configuration::TaskDefinition(task) = &config_ctx.config;
+    let workspace = Workspace::open(task, select_host(task, host)?, false).await?;
+
+    if sync {
+        workspace.sync(task, &config_ctx.config_file).await?;
+    }
+
+    println!("Opening a shell in {}", workspace.code_dir.display());
+
+    let shell_error = |source| DifmError::Exec {
+        host: workspace.host.name.clone(),
+        action: "run the shell".to_string(),
+        source,
+    };
+    ShellChannel::open(&workspace.session, &workspace.code_dir)
+        .await
+        .map_err(shell_error)?
+        .attach()
+        .await
+        .map_err(shell_error)
+}
@@ -0,0 +1,33 @@
+use crate::{
+    config::{ConfigContext, Configuration},
+    error::DifmError,
+    remote::forward::PortForwarding,
+};
+
+use super::workspace::{select_host, Workspace};
+
+pub async fn open_tunnel(config_ctx: &ConfigContext, host: Option<&str>) -> Result<(), DifmError> {
+    let Configuration::TaskDefinition(task) = &config_ctx.config;
+
+    if task.forward.is_empty() {
+        println!("No ports are configured to be forwarded (see `forward:`)");
+        return Ok(());
+    }
+
+    let workspace = Workspace::open(task, select_host(task, host)?, false).await?;
+    let (forwarding, failures) = PortForwarding::start(&workspace.session, &task.forward).await;
+    if forwarding.is_empty() {
+        return Err(DifmError::Exec {
+            host: workspace.host.name.clone(),
+            action: "forward any of the ports".to_string(),
+            source: failures.into_iter().next().unwrap(),
+        });
+    }
+
+    println!("Forwarding ports. Press Ctrl-C to stop.");
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        eprintln!("[!] Could not wait for Ctrl-C: {}", err);
+    }
+
+    Ok(())
+}
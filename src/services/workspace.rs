@@ -0,0 +1,126 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    adapter::{
+        fs::{Entry, FileTransferList},
+        ssh::SSHSession,
+    },
+    config::{ssh::SSHConfig, TaskDefinition, TaskHost},
+    daemon::client::DaemonClient,
+    error::DifmError,
+    event::{self, Event},
+    progress,
+    remote::{integrity::check_file_change, path::expand_remote_home, transfer::send_directory},
+    util::read_from_stdin,
+};
+
+// The connection to the task's host, along with the directories the code lives in over there
+pub struct Workspace {
+    pub host: TaskHost,
+    pub session: SSHSession,
+    pub code_dir: PathBuf,
+}
+
+impl Workspace {
+    // The daemon only executes commands and transfers files, so the shell and the port forwarding
+    // need their own session.
+    pub async fn open(
+        task: &TaskDefinition,
+        host: &TaskHost,
+        use_daemon: bool,
+    ) -> Result<Self, DifmError> {
+        let client = match use_daemon {
+            true => DaemonClient::connect(host, &read_from_stdin).await,
+            false => None,
+        };
+        let (session, daemon) = match client {
+            Some(client) => (SSHSession::from_daemon(client), true),
+            None => {
+                let session = SSHConfig::for_task_host(host)?
+                    .open(&read_from_stdin)
+                    .await?;
+                (session, false)
+            }
+        };
+        event::emit(Event::Connected { daemon });
+
+        let base_dir = expand_remote_home(&session, &host.base_dir)
+            .await
+            .map_err(|source| DifmError::Exec {
+                host: host.name.clone(),
+                action: "resolve the home directory".to_string(),
+                source,
+            })?;
+        let code_dir = base_dir.join(&task.code.dest);
+
+        Ok(Self {
+            host: host.clone(),
+            session,
+            code_dir,
+        })
+    }
+
+    // The files differing from the ones on the host, without sending anything
+    pub async fn changes(
+        &self,
+        task: &TaskDefinition,
+        config_file: &Path,
+    ) -> Result<Vec<Entry>, DifmError> {
+        let dirs = FileTransferList::new(&task.code, &self.code_dir, config_file);
+
+        check_file_change(&self.session, &dirs)
+            .await
+            .map_err(|source| DifmError::Integrity {
+                host: self.host.name.clone(),
+                source,
+            })
+    }
+
+    pub async fn sync(
+        &self,
+        task: &TaskDefinition,
+        config_file: &Path,
+    ) -> Result<Vec<Entry>, DifmError> {
+        let entries = self.changes(task, config_file).await?;
+
+        if !entries.is_empty() {
+            send_directory(&self.session, &entries)
+                .await
+                .map_err(|source| DifmError::Transfer {
+                    host: self.host.name.clone(),
+                    action: "send the files".to_string(),
+                    source,
+                })?;
+        }
+
+        Ok(entries)
+    }
+}
+
+// For the commands working on a single host. Defaults to the first one.
+pub fn select_host<'a>(
+    task: &'a TaskDefinition,
+    name: Option<&str>,
+) -> Result<&'a TaskHost, DifmError> {
+    let Some(name) = name else {
+        if task.host.len() > 1 {
+            progress::report(&format!(
+                "Using {}, the first of the hosts (choose with --host)",
+                task.host[0].name
+            ));
+        }
+        return Ok(&task.host[0]);
+    };
+
+    task.host
+        .iter()
+        .find(|host| host.name == name)
+        .ok_or_else(|| {
+            let hosts: Vec<_> = task.host.iter().map(|host| host.name.as_str()).collect();
+            DifmError::Usage(format!(
+                "Host {} is not in the task definition (available: {})",
+                name,
+                hosts.join(", ")
+            ))
+        })
+}
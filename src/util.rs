@@ -1,4 +1,9 @@
-use std::io::stdin;
+use std::{
+    io::{self, stdin},
+    sync::OnceLock,
+};
+
+use regex::Regex;
 
 #[macro_export]
 macro_rules! check {
@@ -35,16 +40,22 @@ macro_rules! when {
     };
 }
 
-pub fn read_from_stdin(hidden: bool, prompt: &str) -> String {
+pub fn read_from_stdin(hidden: bool, prompt: &str) -> io::Result<String> {
     if hidden {
-        rpassword::prompt_password(prompt).unwrap()
-    } else {
-        print!("{}", prompt);
-        let mut read = String::new();
-        stdin().read_line(&mut read).unwrap();
+        return rpassword::prompt_password(prompt);
+    }
 
-        read
+    // Not to stdout, which may be the JSON event stream
+    eprint!("{}", prompt);
+    let mut read = String::new();
+    if stdin().read_line(&mut read)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "stdin is closed, so the answer could not be read",
+        ));
     }
+
+    Ok(read)
 }
 
 pub fn indent_str(string: &str, level: usize) -> String {
@@ -54,3 +65,82 @@ pub fn indent_str(string: &str, level: usize) -> String {
         .collect::<Vec<_>>()
         .join("\n")
 }
+
+pub fn shell_quote(string: &str) -> String {
+    format!("'{}'", string.replace('\'', r"'\''"))
+}
+
+pub fn strip_ansi(string: &str) -> String {
+    static ANSI_REGEX: OnceLock<Regex> = OnceLock::new();
+    let regex = ANSI_REGEX.get_or_init(|| Regex::new(r"\x1b\[[0-9;?]*[ -/]*[@-~]").unwrap());
+
+    regex.replace_all(string, "").to_string()
+}
+
+// A new directory under the temporary one with the files given, removed along with them once
+// dropped, so even if the test fails
+#[cfg(test)]
+pub struct TempTree(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempTree {
+    pub fn new(files: &[(&str, &str)]) -> Self {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let root = std::env::temp_dir().join(format!(
+            "difm-test-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        for (path, content) in files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        std::fs::create_dir_all(&root).unwrap();
+
+        Self(std::fs::canonicalize(root).unwrap())
+    }
+
+    // Commits all the files, in a repository made on the first call
+    pub fn commit(&self) {
+        for args in [
+            &["init", "-q"][..],
+            &["add", "."],
+            &[
+                "-c",
+                "user.name=difm",
+                "-c",
+                "user.email=difm@localhost",
+                "commit",
+                "-q",
+                "-m",
+                "commit",
+            ],
+        ] {
+            let status = std::process::Command::new("git")
+                .args(args)
+                .current_dir(&self.0)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempTree {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempTree {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}